@@ -0,0 +1,6 @@
+-- Chats provisioned through create-or-get are keyed by title; other chats may share titles.
+ALTER TABLE chats ADD COLUMN IF NOT EXISTS unique_title BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_chats_unique_title
+    ON chats (title)
+    WHERE unique_title;
@@ -0,0 +1,2 @@
+CREATE INDEX IF NOT EXISTS idx_chats_updated_at
+    ON chats (updated_at DESC, id DESC);
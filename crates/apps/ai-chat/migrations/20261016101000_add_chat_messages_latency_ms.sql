@@ -0,0 +1,2 @@
+-- How long the integration took to produce an assistant message; NULL for prompts.
+ALTER TABLE chat_messages ADD COLUMN IF NOT EXISTS latency_ms BIGINT NULL;
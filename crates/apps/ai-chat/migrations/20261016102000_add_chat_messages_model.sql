@@ -0,0 +1,2 @@
+-- Model an assistant message was produced with, when one was chosen or called.
+ALTER TABLE chat_messages ADD COLUMN IF NOT EXISTS model TEXT NULL;
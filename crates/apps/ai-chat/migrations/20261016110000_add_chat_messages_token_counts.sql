@@ -0,0 +1,5 @@
+-- Tokens an assistant message cost, as reported by its provider or estimated
+-- for synthesized responses.
+ALTER TABLE chat_messages
+    ADD COLUMN IF NOT EXISTS prompt_tokens BIGINT NULL,
+    ADD COLUMN IF NOT EXISTS completion_tokens BIGINT NULL;
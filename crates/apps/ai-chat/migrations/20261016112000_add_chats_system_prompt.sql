@@ -0,0 +1,2 @@
+-- Persona instructions sent to providers ahead of every prompt of the chat.
+ALTER TABLE chats ADD COLUMN IF NOT EXISTS system_prompt TEXT NULL;
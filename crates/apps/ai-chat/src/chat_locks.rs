@@ -0,0 +1,92 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use tokio::{sync::OwnedMutexGuard, time::timeout};
+
+type ChatLock = Arc<tokio::sync::Mutex<()>>;
+
+/// One async lock per chat so interactions on the same chat run one at a time
+/// while different chats proceed concurrently.
+#[derive(Default)]
+pub(crate) struct ChatLocks {
+    locks: Mutex<HashMap<i64, ChatLock>>,
+}
+
+/// Holds a chat's lock until dropped.
+pub(crate) struct ChatLockGuard {
+    locks: Arc<ChatLocks>,
+    chat_id: i64,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl ChatLocks {
+    /// Waits up to `wait` for the lock of `chat_id`, or `None` when it stays busy.
+    pub(crate) async fn acquire(
+        self: &Arc<Self>,
+        chat_id: i64,
+        wait: Duration,
+    ) -> Option<ChatLockGuard> {
+        let lock = Arc::clone(lock(&self.locks).entry(chat_id).or_default());
+        let mut guard = ChatLockGuard {
+            locks: Arc::clone(self),
+            chat_id,
+            guard: None,
+        };
+        // On timeout the guard still drops and forgets an otherwise unused lock.
+        guard.guard = Some(timeout(wait, lock.lock_owned()).await.ok()?);
+        Some(guard)
+    }
+}
+
+impl Drop for ChatLockGuard {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+        let mut locks = lock(&self.locks.locks);
+        // Waiters clone the lock under the map mutex, so a single reference
+        // means nobody holds or waits for it.
+        if locks
+            .get(&self.chat_id)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(&self.chat_id);
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // The guarded data stays consistent even if a holder panicked.
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WAIT: Duration = Duration::from_millis(20);
+
+    #[tokio::test]
+    async fn same_chat_waits_and_times_out() {
+        let locks = Arc::new(ChatLocks::default());
+        let held = locks.acquire(1, WAIT).await.expect("first lock is free");
+
+        assert!(locks.acquire(1, WAIT).await.is_none());
+        assert!(locks.acquire(2, WAIT).await.is_some());
+
+        drop(held);
+        assert!(locks.acquire(1, WAIT).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn released_locks_are_forgotten() {
+        let locks = Arc::new(ChatLocks::default());
+        let held = locks.acquire(1, WAIT).await.expect("first lock is free");
+        assert!(locks.acquire(1, WAIT).await.is_none());
+        assert_eq!(lock(&locks.locks).len(), 1);
+
+        drop(held);
+        assert!(lock(&locks.locks).is_empty());
+    }
+}
@@ -0,0 +1,82 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use crate::{LengthRanker, OpenAiConfig, ResponseRanker, pb, state::integration_to_proto};
+
+/// Most chats a single list response returns when not configured.
+const DEFAULT_MAX_LIST_ROWS: usize = 1_000;
+const DEFAULT_BUSY_CHAT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_MAX_RETRIES: u32 = 2;
+const DEFAULT_INTERACTION_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_HISTORY_TURNS: usize = 10;
+const DEFAULT_HISTORY_MAX_BYTES: usize = 32 * 1024;
+
+#[derive(Clone)]
+pub struct AiChatConfig {
+    pub ranker: Arc<dyn ResponseRanker>,
+    /// Lets list endpoints answer `?explain=true` with the query plan. Development only.
+    pub allow_explain: bool,
+    /// Integrations listed as unavailable and rejected by interactions.
+    pub disabled_integrations: Vec<pb::LlmIntegration>,
+    /// Logs prompts and responses as length and hash instead of their text.
+    pub redact_logged_content: bool,
+    /// Most chats a single list response returns; longer lists are marked `truncated`.
+    pub max_list_rows: usize,
+    /// How long an interaction waits for another one on the same chat before
+    /// answering `409 Conflict`.
+    pub busy_chat_timeout: Duration,
+    /// Requests per minute allowed per integration; unlisted integrations are
+    /// unlimited. Interactions skip an integration whose quota is used up.
+    pub requests_per_minute: HashMap<pb::LlmIntegration, u32>,
+    /// Reads and writes JSON bodies for clients that send no `Content-Type` or
+    /// `Accept` naming a format, instead of protobuf.
+    pub json_by_default: bool,
+    /// Sends `OpenAI` interactions to the chat completions API; without it they
+    /// get a synthesized preview response.
+    pub openai: Option<OpenAiConfig>,
+    /// Times a provider call failing with a rate limit, server error or lost
+    /// connection is repeated, with exponential backoff, before the
+    /// integration is reported as failed.
+    pub max_retries: u32,
+    /// How long an interaction may keep retrying provider calls; a retry
+    /// that would start later is not made. Usually the server's request timeout.
+    pub interaction_timeout: Duration,
+    /// Replaces the built-in wording of synthesized preview responses, e.g. to
+    /// simulate a response shape in development. `{integration}` and `{prompt}`
+    /// are substituted with the integration's name (`openai`) and the prompt.
+    pub fake_template: Option<String>,
+    /// Earlier turns, each a prompt with its responses, sent to integrations
+    /// as context; older ones are left out so prompts stay bounded.
+    pub history_turns: usize,
+    /// Most bytes of message content those turns may add up to; the oldest
+    /// are left out until the rest fits, since turns can be arbitrarily long.
+    pub history_max_bytes: usize,
+}
+
+impl Default for AiChatConfig {
+    fn default() -> Self {
+        Self {
+            ranker: Arc::new(LengthRanker),
+            allow_explain: false,
+            disabled_integrations: Vec::new(),
+            redact_logged_content: true,
+            max_list_rows: DEFAULT_MAX_LIST_ROWS,
+            busy_chat_timeout: DEFAULT_BUSY_CHAT_TIMEOUT,
+            requests_per_minute: HashMap::new(),
+            json_by_default: false,
+            openai: None,
+            max_retries: DEFAULT_MAX_RETRIES,
+            interaction_timeout: DEFAULT_INTERACTION_TIMEOUT,
+            fake_template: None,
+            history_turns: DEFAULT_HISTORY_TURNS,
+            history_max_bytes: DEFAULT_HISTORY_MAX_BYTES,
+        }
+    }
+}
+
+/// Parses a lowercase integration name such as `openai`, as stored in the database.
+pub fn parse_integration_name(name: &str) -> Option<pb::LlmIntegration> {
+    match integration_to_proto(Some(name)) {
+        pb::LlmIntegration::Unspecified => None,
+        integration => Some(integration),
+    }
+}
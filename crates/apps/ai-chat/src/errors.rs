@@ -1,8 +1,17 @@
+use std::collections::HashMap;
+
 use axum::{
-    http::StatusCode,
+    http::{HeaderValue, StatusCode, header::RETRY_AFTER},
     response::{IntoResponse, Response},
 };
+use protobuf_http::Rejection;
 use thiserror::Error;
+use tracing::error;
+
+use crate::{Protobuf, pb};
+
+/// Seconds a client should wait before retrying when the connection pool is exhausted.
+const POOL_TIMEOUT_RETRY_AFTER_SECS: HeaderValue = HeaderValue::from_static("1");
 
 #[derive(Debug, Error)]
 pub enum AiChatError {
@@ -10,27 +19,340 @@ pub enum AiChatError {
     InvalidBody,
     #[error("invalid protocol buffers payload: {0}")]
     InvalidProtobuf(prost::DecodeError),
+    #[error("invalid JSON payload: {0}")]
+    InvalidJson(serde_json::Error),
     #[error("chat {0} was not found")]
     NotFound(i64),
+    #[error("chat {0} is busy with another interaction")]
+    ChatBusy(i64),
+    #[error("message {message_id} was not found in chat {chat_id}")]
+    MessageNotFound { chat_id: i64, message_id: i64 },
     #[error("{0}")]
     Validation(&'static str),
+    /// A `Validation` failure that is down to one request field.
+    #[error("{reason}")]
+    InvalidField {
+        field: &'static str,
+        reason: &'static str,
+    },
+    #[error("integration at index {index} has unknown value {value}")]
+    UnknownIntegration { index: usize, value: i32 },
+    #[error("integration at index {index} cannot be unspecified")]
+    UnspecifiedIntegration { index: usize },
+    #[error("integration at index {index} is not available on this server")]
+    UnavailableIntegration { index: usize },
+    /// A stored row could not be decoded, e.g. text that is not valid UTF-8.
+    #[error("stored chat data could not be read")]
+    DataCorruption(Option<i64>),
     #[error("database error: {0}")]
-    Database(#[from] sqlx::Error),
+    Database(sqlx::Error),
+}
+
+impl From<Rejection> for AiChatError {
+    fn from(rejection: Rejection) -> Self {
+        match rejection {
+            // Chat bodies are neither capped nor checked for their media type,
+            // so only unreadable ones end up here.
+            Rejection::InvalidBody
+            | Rejection::BodyTooLarge { .. }
+            | Rejection::UnsupportedMediaType => Self::InvalidBody,
+            Rejection::InvalidProtobuf(error) => Self::InvalidProtobuf(error),
+            Rejection::InvalidJson(error) => Self::InvalidJson(error),
+        }
+    }
+}
+
+impl From<sqlx::Error> for AiChatError {
+    fn from(error: sqlx::Error) -> Self {
+        if is_undecodable(&error) {
+            error!(%error, "failed to decode stored chat data");
+            return Self::DataCorruption(None);
+        }
+        Self::Database(error)
+    }
+}
+
+/// Postgres `character_not_in_repertoire`, raised when stored text is invalid in
+/// the client encoding.
+const CHARACTER_NOT_IN_REPERTOIRE: &str = "22021";
+
+fn is_undecodable(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::ColumnDecode { .. } => true,
+        sqlx::Error::Database(error) => {
+            error.code().as_deref() == Some(CHARACTER_NOT_IN_REPERTOIRE)
+        }
+        _ => false,
+    }
+}
+
+impl AiChatError {
+    /// Converts a database error raised while reading the row of `chat_id`,
+    /// naming that chat if it turns out to be undecodable.
+    pub(crate) fn reading_chat(chat_id: i64) -> impl FnOnce(sqlx::Error) -> Self {
+        move |error| {
+            if is_undecodable(&error) {
+                error!(chat_id, %error, "failed to decode stored chat data");
+                return Self::DataCorruption(Some(chat_id));
+            }
+            Self::Database(error)
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::InvalidBody
+            | Self::InvalidProtobuf(_)
+            | Self::InvalidJson(_)
+            | Self::Validation(_)
+            | Self::InvalidField { .. }
+            | Self::UnknownIntegration { .. }
+            | Self::UnspecifiedIntegration { .. }
+            | Self::UnavailableIntegration { .. } => StatusCode::BAD_REQUEST,
+            Self::NotFound(_) | Self::MessageNotFound { .. } => StatusCode::NOT_FOUND,
+            Self::ChatBusy(_) => StatusCode::CONFLICT,
+            Self::Database(sqlx::Error::PoolTimedOut) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::DataCorruption(_) | Self::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn to_api_error(&self) -> pb::ApiError {
+        let (code, message) = match self {
+            Self::InvalidBody => ("invalid_body", self.to_string()),
+            Self::InvalidProtobuf(_) => ("invalid_protobuf", self.to_string()),
+            Self::InvalidJson(_) => ("invalid_json", self.to_string()),
+            Self::NotFound(_) | Self::MessageNotFound { .. } => ("not_found", self.to_string()),
+            Self::ChatBusy(_) => ("chat_busy", self.to_string()),
+            Self::Validation(_) | Self::InvalidField { .. } => ("validation", self.to_string()),
+            Self::UnknownIntegration { .. } => ("unknown_integration", self.to_string()),
+            Self::UnspecifiedIntegration { .. } => ("unspecified_integration", self.to_string()),
+            Self::UnavailableIntegration { .. } => ("unavailable_integration", self.to_string()),
+            Self::DataCorruption(_) => ("data_corruption", self.to_string()),
+            Self::Database(sqlx::Error::PoolTimedOut) => {
+                ("unavailable", "database is busy, retry later".to_owned())
+            }
+            Self::Database(_) => ("internal", "internal server error".to_owned()),
+        };
+        let details = match *self {
+            Self::NotFound(chat_id)
+            | Self::ChatBusy(chat_id)
+            | Self::DataCorruption(Some(chat_id)) => detail_map([("chat_id", chat_id.to_string())]),
+            Self::MessageNotFound {
+                chat_id,
+                message_id,
+            } => detail_map([
+                ("chat_id", chat_id.to_string()),
+                ("message_id", message_id.to_string()),
+            ]),
+            Self::UnknownIntegration { index, value } => {
+                detail_map([("index", index.to_string()), ("value", value.to_string())])
+            }
+            Self::UnspecifiedIntegration { index } | Self::UnavailableIntegration { index } => {
+                detail_map([("index", index.to_string())])
+            }
+            Self::InvalidField { field, .. } => detail_map([("field", field.to_owned())]),
+            _ => HashMap::new(),
+        };
+
+        pb::ApiError {
+            code: code.to_owned(),
+            message,
+            details,
+        }
+    }
+}
+
+fn detail_map<const N: usize>(details: [(&str, String); N]) -> HashMap<String, String> {
+    details
+        .into_iter()
+        .map(|(key, value)| (key.to_owned(), value))
+        .collect()
 }
 
 impl IntoResponse for AiChatError {
     fn into_response(self) -> Response {
-        let (status, message) = match self {
-            Self::InvalidBody | Self::InvalidProtobuf(_) | Self::Validation(_) => {
-                (StatusCode::BAD_REQUEST, self.to_string())
-            }
-            Self::NotFound(_) => (StatusCode::NOT_FOUND, self.to_string()),
-            Self::Database(_) => (
+        let mut response = (self.status(), Protobuf(self.to_api_error())).into_response();
+        if matches!(self, Self::Database(sqlx::Error::PoolTimedOut)) {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, POOL_TIMEOUT_RETRY_AFTER_SECS);
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::to_bytes;
+    use prost::Message;
+
+    use super::*;
+
+    fn decode_error() -> prost::DecodeError {
+        pb::ApiError::decode(&[0xff][..]).expect_err("a lone 0xff byte is not a valid message")
+    }
+
+    fn json_error() -> serde_json::Error {
+        serde_json::from_str::<pb::ApiError>("{").expect_err("`{` is not a valid JSON document")
+    }
+
+    async fn api_error(error: AiChatError) -> (StatusCode, pb::ApiError) {
+        let response = error.into_response();
+        let status = response.status();
+        assert_eq!(
+            response
+                .headers()
+                .get("content-type")
+                .map(HeaderValue::as_bytes),
+            Some(&b"application/x-protobuf"[..])
+        );
+        let body = to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("error body should be readable");
+        (
+            status,
+            pb::ApiError::decode(body).expect("error body should be an ApiError"),
+        )
+    }
+
+    #[tokio::test]
+    async fn every_variant_answers_with_an_api_error() {
+        let cases = [
+            (
+                AiChatError::InvalidBody,
+                StatusCode::BAD_REQUEST,
+                "invalid_body",
+            ),
+            (
+                AiChatError::InvalidProtobuf(decode_error()),
+                StatusCode::BAD_REQUEST,
+                "invalid_protobuf",
+            ),
+            (
+                AiChatError::InvalidJson(json_error()),
+                StatusCode::BAD_REQUEST,
+                "invalid_json",
+            ),
+            (AiChatError::NotFound(3), StatusCode::NOT_FOUND, "not_found"),
+            (
+                AiChatError::MessageNotFound {
+                    chat_id: 3,
+                    message_id: 9,
+                },
+                StatusCode::NOT_FOUND,
+                "not_found",
+            ),
+            (AiChatError::ChatBusy(3), StatusCode::CONFLICT, "chat_busy"),
+            (
+                AiChatError::Validation("integrations cannot be empty"),
+                StatusCode::BAD_REQUEST,
+                "validation",
+            ),
+            (
+                AiChatError::InvalidField {
+                    field: "prompt",
+                    reason: "prompt cannot be empty",
+                },
+                StatusCode::BAD_REQUEST,
+                "validation",
+            ),
+            (
+                AiChatError::UnknownIntegration {
+                    index: 1,
+                    value: 42,
+                },
+                StatusCode::BAD_REQUEST,
+                "unknown_integration",
+            ),
+            (
+                AiChatError::UnspecifiedIntegration { index: 0 },
+                StatusCode::BAD_REQUEST,
+                "unspecified_integration",
+            ),
+            (
+                AiChatError::UnavailableIntegration { index: 2 },
+                StatusCode::BAD_REQUEST,
+                "unavailable_integration",
+            ),
+            (
+                AiChatError::DataCorruption(Some(3)),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "data_corruption",
+            ),
+            (
+                AiChatError::Database(sqlx::Error::PoolTimedOut),
+                StatusCode::SERVICE_UNAVAILABLE,
+                "unavailable",
+            ),
+            (
+                AiChatError::Database(sqlx::Error::RowNotFound),
                 StatusCode::INTERNAL_SERVER_ERROR,
-                "internal server error".to_owned(),
+                "internal",
             ),
+        ];
+
+        for (error, expected_status, expected_code) in cases {
+            let (status, body) = api_error(error).await;
+            assert_eq!(status, expected_status);
+            assert_eq!(body.code, expected_code);
+            assert!(!body.message.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn details_name_the_offending_values() {
+        let (_, body) = api_error(AiChatError::MessageNotFound {
+            chat_id: 3,
+            message_id: 9,
+        })
+        .await;
+        assert_eq!(body.details.get("chat_id").map(String::as_str), Some("3"));
+        assert_eq!(
+            body.details.get("message_id").map(String::as_str),
+            Some("9")
+        );
+
+        let (_, body) = api_error(AiChatError::UnknownIntegration {
+            index: 1,
+            value: 42,
+        })
+        .await;
+        assert_eq!(body.details.get("index").map(String::as_str), Some("1"));
+        assert_eq!(body.details.get("value").map(String::as_str), Some("42"));
+
+        let (_, body) = api_error(AiChatError::InvalidField {
+            field: "prompt",
+            reason: "prompt cannot be empty",
+        })
+        .await;
+        assert_eq!(body.message, "prompt cannot be empty");
+        assert_eq!(
+            body.details.get("field").map(String::as_str),
+            Some("prompt")
+        );
+    }
+
+    #[tokio::test]
+    async fn database_errors_do_not_leak_details() {
+        let (_, body) = api_error(AiChatError::Database(sqlx::Error::RowNotFound)).await;
+        assert_eq!(body.message, "internal server error");
+        assert!(body.details.is_empty());
+    }
+
+    #[test]
+    fn undecodable_columns_become_data_corruption() {
+        let decode_failure = || sqlx::Error::ColumnDecode {
+            index: "\"content\"".to_owned(),
+            source: "invalid utf-8 sequence".into(),
         };
 
-        (status, message).into_response()
+        assert!(matches!(
+            AiChatError::from(decode_failure()),
+            AiChatError::DataCorruption(None)
+        ));
+        assert!(matches!(
+            AiChatError::reading_chat(3)(decode_failure()),
+            AiChatError::DataCorruption(Some(3))
+        ));
     }
 }
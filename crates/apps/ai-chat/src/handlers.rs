@@ -1,133 +1,598 @@
-use std::collections::HashSet;
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    ops::RangeInclusive,
+    sync::Arc,
+    time::Instant,
+};
 
 use axum::{
-    Router,
-    extract::{Path, State},
-    routing::post,
+    Extension, Router,
+    body::Body,
+    extract::{Path, Query, State},
+    http::{StatusCode, header::CONTENT_TYPE},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
 };
+use bytes::Bytes;
+use futures_util::stream;
+use prost::Message as ProstMessage;
+use protobuf_http::{JsonByDefault, negotiate_json};
+use serde::Deserialize;
 use sqlx::{PgPool, Postgres, Transaction};
+use tokio::{runtime::Handle, sync::mpsc};
+use tracing::{debug, warn};
 
 use crate::{
-    AiChatError, Protobuf, pb,
+    AiChatConfig, AiChatError, Protobuf, ResponseRanker,
+    chat_locks::ChatLockGuard,
+    openai::OpenAiClient,
+    pb,
+    ranking::{RankBy, rank_responses, sort_prompt_groups},
+    rate_limits::IntegrationRateLimits,
+    redaction::{LoggedContent, RedactContent},
     state::{
-        AiChatState, ChatMessageRow, ChatRow, build_state, integration_to_db, now_unix_millis,
+        AiChatState, ChatMessageRow, ChatPreviewRow, ChatRow, Conversation, INTEGRATIONS,
+        TokenUsage, build_state, integration_display_name, integration_to_db, integration_to_proto,
+        now_unix_millis,
     },
 };
 
+/// Content type of chunked interactions: length-delimited `InteractChatChunk`s.
+const PROTOBUF_DELIMITED_CONTENT_TYPE: &str = "application/x-protobuf; delimited=true";
+
 pub fn create_handlers(pool: PgPool) -> Router {
-    let state = build_state(pool);
+    create_handlers_with_config(pool, AiChatConfig::default())
+}
+
+pub fn create_handlers_with_config(pool: PgPool, config: AiChatConfig) -> Router {
+    let json_by_default = JsonByDefault(config.json_by_default);
+    let state = build_state(pool, config);
+    let redact = state.redact;
 
     Router::new()
         .route("/", post(create_chat).get(list_chats))
+        .route("/by-title/{title}", put(create_or_get_chat))
+        .route("/integrations", get(list_integrations))
+        .route(
+            "/{chat_id}",
+            get(get_chat).patch(update_chat).delete(delete_chat),
+        )
         .route("/{chat_id}/interact", post(interact_chat))
+        .route("/{chat_id}/usage", get(get_chat_usage))
+        .route(
+            "/{chat_id}/messages",
+            get(list_chat_messages).delete(clear_chat_messages),
+        )
+        .route(
+            "/{chat_id}/messages/{message_id}/move",
+            post(move_chat_message),
+        )
+        .layer(Extension(redact))
+        .layer(middleware::from_fn_with_state(
+            json_by_default,
+            negotiate_json,
+        ))
         .with_state(state)
 }
 
 async fn create_chat(
     State(state): State<AiChatState>,
     Protobuf(payload): Protobuf<pb::CreateChatRequest>,
-) -> Result<Protobuf<pb::CreateChatResponse>, AiChatError> {
+) -> Result<(StatusCode, Protobuf<pb::CreateChatResponse>), AiChatError> {
     let title = payload.title.trim();
     if title.is_empty() {
-        return Err(AiChatError::Validation("title cannot be empty"));
+        return Err(AiChatError::InvalidField {
+            field: "title",
+            reason: "title cannot be empty",
+        });
     }
+    let system_prompt = payload
+        .system_prompt
+        .as_deref()
+        .map(parse_system_prompt)
+        .transpose()?;
 
     let now = now_unix_millis();
     let row = sqlx::query_as!(
         ChatRow,
         r#"
-        INSERT INTO chats (title, created_at, updated_at)
-        VALUES ($1, $2, $2)
-        RETURNING id, title, created_at, updated_at
+        INSERT INTO chats (title, created_at, updated_at, system_prompt)
+        VALUES ($1, $2, $2, $3)
+        RETURNING id, title, created_at, updated_at, system_prompt
         "#,
         title,
-        now
+        now,
+        system_prompt
     )
     .fetch_one(&state.pool)
     .await?;
 
-    Ok(Protobuf(pb::CreateChatResponse {
+    Ok(created_response(pb::Chat::from(row), true))
+}
+
+async fn update_chat(
+    Path(chat_id): Path<i64>,
+    State(state): State<AiChatState>,
+    Protobuf(payload): Protobuf<pb::UpdateChatRequest>,
+) -> Result<Protobuf<pb::UpdateChatResponse>, AiChatError> {
+    let system_prompt = match (
+        payload.system_prompt.as_deref(),
+        payload.clear_system_prompt,
+    ) {
+        (None, false) => {
+            return Err(AiChatError::InvalidField {
+                field: "system_prompt",
+                reason: "system_prompt or clear_system_prompt must be provided",
+            });
+        }
+        (Some(_), true) => {
+            return Err(AiChatError::InvalidField {
+                field: "system_prompt",
+                reason: "system_prompt cannot be set and cleared at once",
+            });
+        }
+        (Some(system_prompt), false) => Some(parse_system_prompt(system_prompt)?),
+        (None, true) => None,
+    };
+
+    let row = sqlx::query_as!(
+        ChatRow,
+        r#"
+        UPDATE chats
+        SET system_prompt = $2, updated_at = $3
+        WHERE id = $1
+        RETURNING id, title, created_at, updated_at, system_prompt
+        "#,
+        chat_id,
+        system_prompt,
+        now_unix_millis()
+    )
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(AiChatError::reading_chat(chat_id))?
+    .ok_or(AiChatError::NotFound(chat_id))?;
+
+    Ok(Protobuf(pb::UpdateChatResponse {
         chat: Some(pb::Chat::from(row)),
     }))
 }
 
-async fn list_chats(
+fn parse_system_prompt(system_prompt: &str) -> Result<&str, AiChatError> {
+    let system_prompt = system_prompt.trim();
+    if system_prompt.is_empty() {
+        return Err(AiChatError::InvalidField {
+            field: "system_prompt",
+            reason: "system_prompt cannot be empty; use clear_system_prompt to remove it",
+        });
+    }
+    Ok(system_prompt)
+}
+
+/// Returns the chat keyed by `title`, creating it first if it does not exist.
+async fn create_or_get_chat(
+    Path(title): Path<String>,
     State(state): State<AiChatState>,
-) -> Result<Protobuf<pb::ListChatsResponse>, AiChatError> {
-    let rows = sqlx::query_as!(
-        ChatRow,
+) -> Result<(StatusCode, Protobuf<pb::CreateChatResponse>), AiChatError> {
+    let title = title.trim();
+    if title.is_empty() {
+        return Err(AiChatError::InvalidField {
+            field: "title",
+            reason: "title cannot be empty",
+        });
+    }
+
+    let now = now_unix_millis();
+    // The no-op update makes RETURNING yield the existing row on conflict;
+    // `xmax = 0` only holds for freshly inserted tuples.
+    let row = sqlx::query!(
         r#"
-        SELECT id, title, created_at, updated_at
-        FROM chats
-        ORDER BY id
+        INSERT INTO chats (title, created_at, updated_at, unique_title)
+        VALUES ($1, $2, $2, TRUE)
+        ON CONFLICT (title) WHERE unique_title
+        DO UPDATE SET title = EXCLUDED.title
+        RETURNING id, title, created_at, updated_at, system_prompt, (xmax = 0) AS "created!"
         "#,
+        title,
+        now
     )
-    .fetch_all(&state.pool)
+    .fetch_one(&state.pool)
     .await?;
 
+    let chat = ChatRow {
+        id: row.id,
+        title: row.title,
+        created_at: row.created_at,
+        updated_at: row.updated_at,
+        system_prompt: row.system_prompt,
+    };
+    Ok(created_response(pb::Chat::from(chat), row.created))
+}
+
+/// Answers `201 Created` only when the chat was inserted, `200 OK` when an
+/// existing one matched, mirroring the `created` response field.
+fn created_response(
+    chat: pb::Chat,
+    created: bool,
+) -> (StatusCode, Protobuf<pb::CreateChatResponse>) {
+    let status = if created {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    (
+        status,
+        Protobuf(pb::CreateChatResponse {
+            chat: Some(chat),
+            created,
+        }),
+    )
+}
+
+async fn list_integrations(State(state): State<AiChatState>) -> Protobuf<pb::IntegrationList> {
+    let integrations = INTEGRATIONS
+        .into_iter()
+        .map(|integration| pb::IntegrationInfo {
+            id: integration as i32,
+            display_name: integration_display_name(integration).to_owned(),
+            available: !state.disabled_integrations.contains(&integration),
+        })
+        .collect();
+
+    Protobuf(pb::IntegrationList { integrations })
+}
+
+/// Maximum number of characters of the last message included in chat previews.
+const LAST_MESSAGE_PREVIEW_CHARS: i32 = 200;
+
+// List queries get their `ORDER BY` appended from `ChatSort`.
+const LIST_CHATS_SQL: &str = r"
+    SELECT id, title, created_at, updated_at, system_prompt
+    FROM chats
+";
+
+const LIST_CHATS_WITH_LAST_MESSAGE_SQL: &str = r"
+    SELECT
+        chats.id,
+        chats.title,
+        chats.created_at,
+        chats.updated_at,
+        chats.system_prompt,
+        last_message.id AS message_id,
+        last_message.role AS message_role,
+        last_message.integration AS message_integration,
+        last_message.content AS message_content,
+        last_message.created_at AS message_created_at
+    FROM chats
+    LEFT JOIN LATERAL (
+        SELECT id, role, integration, LEFT(content, $1) AS content, created_at
+        FROM chat_messages
+        WHERE chat_messages.chat_id = chats.id
+        ORDER BY created_at DESC, id DESC
+        LIMIT 1
+    ) AS last_message ON TRUE
+";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ChatSort {
+    #[default]
+    Id,
+    /// Most recently active first; interactions bump `updated_at`.
+    UpdatedDesc,
+}
+
+impl ChatSort {
+    fn order_by(&self) -> &'static str {
+        match self {
+            Self::Id => "ORDER BY chats.id",
+            Self::UpdatedDesc => "ORDER BY chats.updated_at DESC, chats.id DESC",
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ListChatsQuery {
+    #[serde(default)]
+    include_last_message: bool,
+    #[serde(default)]
+    sort: ChatSort,
+    #[serde(default)]
+    explain: bool,
+}
+
+async fn list_chats(
+    Query(query): Query<ListChatsQuery>,
+    State(state): State<AiChatState>,
+) -> Result<Response, AiChatError> {
+    let sql = if query.include_last_message {
+        LIST_CHATS_WITH_LAST_MESSAGE_SQL
+    } else {
+        LIST_CHATS_SQL
+    };
+    // Ask for one row past the cap so a full list can be told apart from a truncated one.
+    let sql = format!(
+        "{sql} {} LIMIT {}",
+        query.sort.order_by(),
+        state.max_list_rows.saturating_add(1)
+    );
+
+    if query.explain {
+        if !state.allow_explain {
+            return Err(AiChatError::Validation(
+                "query plans are disabled on this server",
+            ));
+        }
+        let plan: Vec<String> = if query.include_last_message {
+            sqlx::query_scalar(&explain_sql(&sql))
+                .bind(LAST_MESSAGE_PREVIEW_CHARS)
+                .fetch_all(&state.pool)
+                .await?
+        } else {
+            sqlx::query_scalar(&explain_sql(&sql))
+                .fetch_all(&state.pool)
+                .await?
+        };
+        return Ok((
+            [(CONTENT_TYPE, "text/plain; charset=utf-8")],
+            plan.join("\n"),
+        )
+            .into_response());
+    }
+
+    let mut chats: Vec<pb::Chat> = if query.include_last_message {
+        sqlx::query_as::<_, ChatPreviewRow>(&sql)
+            .bind(LAST_MESSAGE_PREVIEW_CHARS)
+            .fetch_all(&state.pool)
+            .await?
+            .into_iter()
+            .map(pb::Chat::from)
+            .collect()
+    } else {
+        sqlx::query_as::<_, ChatRow>(&sql)
+            .fetch_all(&state.pool)
+            .await?
+            .into_iter()
+            .map(pb::Chat::from)
+            .collect()
+    };
+
+    let truncated = chats.len() > state.max_list_rows;
+    chats.truncate(state.max_list_rows);
+    let next_cursor = match chats.last() {
+        Some(chat) if truncated => chat.id,
+        _ => 0,
+    };
+
     Ok(Protobuf(pb::ListChatsResponse {
-        chats: rows.into_iter().map(pb::Chat::from).collect(),
-    }))
+        chats,
+        truncated,
+        next_cursor,
+    })
+    .into_response())
 }
 
-async fn interact_chat(
+fn explain_sql(sql: &str) -> String {
+    format!("EXPLAIN (ANALYZE, FORMAT TEXT) {sql}")
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum TransferMode {
+    #[default]
+    Buffered,
+    Chunked,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct InteractQuery {
+    #[serde(default)]
+    transfer: TransferMode,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct GetChatQuery {
+    /// Most messages to return, capped by the server's row cap.
+    limit: Option<usize>,
+    /// Only returns messages older than this one.
+    before_id: Option<i64>,
+}
+
+async fn get_chat(
     Path(chat_id): Path<i64>,
+    Query(query): Query<GetChatQuery>,
     State(state): State<AiChatState>,
-    Protobuf(payload): Protobuf<pb::InteractChatRequest>,
-) -> Result<Protobuf<pb::InteractChatResponse>, AiChatError> {
-    let prompt = payload.prompt.trim();
-    if prompt.is_empty() {
-        return Err(AiChatError::Validation("prompt cannot be empty"));
+) -> Result<Protobuf<pb::GetChatResponse>, AiChatError> {
+    let limit = query
+        .limit
+        .unwrap_or(state.max_list_rows)
+        .min(state.max_list_rows);
+    if limit == 0 {
+        return Err(AiChatError::InvalidField {
+            field: "limit",
+            reason: "limit must be positive",
+        });
     }
 
-    let integrations = parse_integrations(payload.integrations)?;
-
     let mut tx = state.pool.begin().await?;
-    let mut chat = fetch_chat(chat_id, &mut tx).await?;
-    let now = now_unix_millis();
-
-    let prompt_message = sqlx::query_as!(
+    let chat = fetch_chat(chat_id, &mut tx).await?;
+    let before = match query.before_id {
+        Some(message_id) => {
+            let created_at = sqlx::query_scalar!(
+                r#"
+                SELECT created_at
+                FROM chat_messages
+                WHERE id = $1 AND chat_id = $2
+                "#,
+                message_id,
+                chat_id
+            )
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or(AiChatError::MessageNotFound {
+                chat_id,
+                message_id,
+            })?;
+            Some((created_at, message_id))
+        }
+        None => None,
+    };
+    // Newest first, one row past the limit to tell whether older messages remain.
+    let mut rows = sqlx::query_as!(
         ChatMessageRow,
         r#"
-        INSERT INTO chat_messages (chat_id, role, integration, content, created_at)
-        VALUES ($1, 'user', NULL, $2, $3)
-        RETURNING id, chat_id, role, integration, content, created_at
+        SELECT id, chat_id, role, integration, content, created_at, latency_ms, model,
+            prompt_tokens, completion_tokens
+        FROM chat_messages
+        WHERE chat_id = $1
+          AND ($2::BIGINT IS NULL OR (created_at, id) < ($2, $3))
+        ORDER BY created_at DESC, id DESC
+        LIMIT $4
         "#,
         chat_id,
-        prompt,
-        now
+        before.map(|(created_at, _)| created_at),
+        before.map(|(_, message_id)| message_id),
+        i64::try_from(limit.saturating_add(1)).unwrap_or(i64::MAX)
     )
-    .fetch_one(&mut *tx)
-    .await?;
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(AiChatError::reading_chat(chat_id))?;
+    tx.commit().await?;
 
-    let mut responses = Vec::with_capacity(integrations.len());
-    for integration in integrations {
-        let content = synthesize_response(integration, prompt);
-        let row = sqlx::query_as!(
-            ChatMessageRow,
-            r#"
-            INSERT INTO chat_messages (chat_id, role, integration, content, created_at)
-            VALUES ($1, 'assistant', $2, $3, $4)
-            RETURNING id, chat_id, role, integration, content, created_at
-            "#,
-            chat_id,
-            integration_to_db(integration),
-            content,
-            now
-        )
-        .fetch_one(&mut *tx)
-        .await?;
-        responses.push(pb::ChatMessage::from(row));
+    let has_more = rows.len() > limit;
+    rows.truncate(limit);
+    rows.reverse();
+
+    Ok(Protobuf(pb::GetChatResponse {
+        chat: Some(pb::Chat::from(chat)),
+        messages: rows.into_iter().map(pb::ChatMessage::from).collect(),
+        has_more,
+    }))
+}
+
+/// Deletes a chat; its messages go with it through `ON DELETE CASCADE`.
+async fn delete_chat(
+    Path(chat_id): Path<i64>,
+    State(state): State<AiChatState>,
+) -> Result<Protobuf<pb::DeleteChatResponse>, AiChatError> {
+    let id = sqlx::query_scalar!(
+        r#"
+        DELETE FROM chats
+        WHERE id = $1
+        RETURNING id
+        "#,
+        chat_id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .ok_or(AiChatError::NotFound(chat_id))?;
+
+    Ok(Protobuf(pb::DeleteChatResponse { id }))
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ListChatMessagesQuery {
+    /// Re-sorts each prompt's responses by a stored metric: `latency` or `length`.
+    rank_by: Option<RankBy>,
+}
+
+async fn list_chat_messages(
+    Path(chat_id): Path<i64>,
+    Query(query): Query<ListChatMessagesQuery>,
+    State(state): State<AiChatState>,
+) -> Result<Protobuf<pb::ListChatMessagesResponse>, AiChatError> {
+    let mut tx = state.pool.begin().await?;
+    fetch_chat(chat_id, &mut tx).await?;
+    let rows = sqlx::query_as!(
+        ChatMessageRow,
+        r#"
+        SELECT id, chat_id, role, integration, content, created_at, latency_ms, model,
+            prompt_tokens, completion_tokens
+        FROM chat_messages
+        WHERE chat_id = $1
+        ORDER BY created_at, id
+        "#,
+        chat_id
+    )
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(AiChatError::reading_chat(chat_id))?;
+    tx.commit().await?;
+
+    let mut messages: Vec<_> = rows.into_iter().map(pb::ChatMessage::from).collect();
+    if let Some(rank_by) = query.rank_by {
+        sort_prompt_groups(&mut messages, rank_by);
     }
 
-    chat.updated_at = now;
-    sqlx::query!(
+    Ok(Protobuf(pb::ListChatMessagesResponse { messages }))
+}
+
+/// Totals the tokens of the chat's responses per integration.
+async fn get_chat_usage(
+    Path(chat_id): Path<i64>,
+    State(state): State<AiChatState>,
+) -> Result<Protobuf<pb::ChatUsageResponse>, AiChatError> {
+    let mut tx = state.pool.begin().await?;
+    fetch_chat(chat_id, &mut tx).await?;
+    let rows = sqlx::query!(
+        r#"
+        SELECT integration AS "integration!",
+            COUNT(*) AS "responses!",
+            COALESCE(SUM(prompt_tokens), 0)::BIGINT AS "prompt_tokens!",
+            COALESCE(SUM(completion_tokens), 0)::BIGINT AS "completion_tokens!"
+        FROM chat_messages
+        WHERE chat_id = $1 AND role = 'assistant' AND integration IS NOT NULL
+        GROUP BY integration
+        "#,
+        chat_id
+    )
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(AiChatError::reading_chat(chat_id))?;
+    tx.commit().await?;
+
+    let mut integrations: Vec<_> = rows
+        .into_iter()
+        .map(|row| pb::IntegrationUsage {
+            integration: integration_to_proto(Some(&row.integration)) as i32,
+            responses: row.responses,
+            prompt_tokens: row.prompt_tokens,
+            completion_tokens: row.completion_tokens,
+        })
+        .collect();
+    integrations.sort_unstable_by_key(|usage| usage.integration);
+
+    Ok(Protobuf(pb::ChatUsageResponse { integrations }))
+}
+
+/// Removes every message of a chat while keeping the chat itself.
+async fn clear_chat_messages(
+    Path(chat_id): Path<i64>,
+    State(state): State<AiChatState>,
+) -> Result<Protobuf<pb::ClearChatMessagesResponse>, AiChatError> {
+    let mut tx = state.pool.begin().await?;
+
+    let touched = sqlx::query!(
         r#"
         UPDATE chats
         SET updated_at = $1
         WHERE id = $2
         "#,
-        now,
+        now_unix_millis(),
+        chat_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    if touched.rows_affected() == 0 {
+        return Err(AiChatError::NotFound(chat_id));
+    }
+
+    let deleted = sqlx::query!(
+        r#"
+        DELETE FROM chat_messages
+        WHERE chat_id = $1
+        "#,
         chat_id
     )
     .execute(&mut *tx)
@@ -135,38 +600,629 @@ async fn interact_chat(
 
     tx.commit().await?;
 
-    Ok(Protobuf(pb::InteractChatResponse {
-        chat: Some(pb::Chat::from(chat)),
-        prompt_message: Some(pb::ChatMessage::from(prompt_message)),
-        responses,
+    Ok(Protobuf(pb::ClearChatMessagesResponse {
+        chat_id,
+        deleted_count: i64::try_from(deleted.rows_affected()).unwrap_or(i64::MAX),
     }))
 }
 
+/// Moves a message to another chat, e.g. a prompt sent to the wrong one.
+async fn move_chat_message(
+    Path((chat_id, message_id)): Path<(i64, i64)>,
+    State(state): State<AiChatState>,
+    Protobuf(payload): Protobuf<pb::MoveChatMessageRequest>,
+) -> Result<Protobuf<pb::MoveChatMessageResponse>, AiChatError> {
+    let target_chat_id = payload.target_chat_id;
+    if target_chat_id == chat_id {
+        return Err(AiChatError::InvalidField {
+            field: "target_chat_id",
+            reason: "target chat must differ from the source chat",
+        });
+    }
+
+    let mut tx = state.pool.begin().await?;
+    let touched = sqlx::query_scalar!(
+        r#"
+        UPDATE chats
+        SET updated_at = $1
+        WHERE id = ANY($2)
+        RETURNING id
+        "#,
+        now_unix_millis(),
+        &[chat_id, target_chat_id]
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    if let Some(missing) = [chat_id, target_chat_id]
+        .into_iter()
+        .find(|id| !touched.contains(id))
+    {
+        return Err(AiChatError::NotFound(missing));
+    }
+
+    let message = sqlx::query_as!(
+        ChatMessageRow,
+        r#"
+        UPDATE chat_messages
+        SET chat_id = $1
+        WHERE id = $2 AND chat_id = $3
+        RETURNING id, chat_id, role, integration, content, created_at, latency_ms, model,
+            prompt_tokens, completion_tokens
+        "#,
+        target_chat_id,
+        message_id,
+        chat_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(AiChatError::MessageNotFound {
+        chat_id,
+        message_id,
+    })?;
+
+    tx.commit().await?;
+
+    Ok(Protobuf(pb::MoveChatMessageResponse {
+        message: Some(pb::ChatMessage::from(message)),
+    }))
+}
+
+async fn interact_chat(
+    Path(chat_id): Path<i64>,
+    Query(query): Query<InteractQuery>,
+    State(state): State<AiChatState>,
+    Protobuf(payload): Protobuf<pb::InteractChatRequest>,
+) -> Result<Response, AiChatError> {
+    let mut interaction = PendingInteraction::begin(&state, chat_id, payload).await?;
+
+    match query.transfer {
+        TransferMode::Buffered => {
+            let mut failures = Vec::new();
+            for (index, integration) in interaction.integrations.clone().into_iter().enumerate() {
+                if let IntegrationOutcome::Skipped(failure) =
+                    interaction.respond(index, integration).await?
+                {
+                    failures.push(failure);
+                }
+            }
+            let response = interaction.finish(failures, state.ranker.as_ref()).await?;
+            Ok(Protobuf(response).into_response())
+        }
+        TransferMode::Chunked => Ok(stream_interaction(interaction, state.ranker)),
+    }
+}
+
+/// An interaction whose prompt is recorded and whose context is read, waiting
+/// on its integrations. No transaction is held while providers are asked;
+/// their responses are stored together by [`PendingInteraction::finish`]. The
+/// chat stays locked until then, so interactions on one chat do not interleave.
+///
+/// Dropping it before then, as happens to interactions whose client
+/// disconnects, cancels the outstanding provider call and deletes the
+/// recorded prompt.
+struct PendingInteraction {
+    pool: PgPool,
+    chat: ChatRow,
+    /// The chat's latest turns before this one, oldest first.
+    history: Vec<ChatMessageRow>,
+    prompt_message: ChatMessageRow,
+    /// Replies so far with their integration's index, stored once every
+    /// integration had its turn.
+    responses: Vec<(u32, ChatMessageRow)>,
+    integrations: Vec<pb::LlmIntegration>,
+    overrides: HashMap<pb::LlmIntegration, IntegrationParams>,
+    rank: bool,
+    ephemeral: bool,
+    now: i64,
+    /// When provider calls stop being retried.
+    deadline: tokio::time::Instant,
+    redact: RedactContent,
+    rate_limits: Arc<IntegrationRateLimits>,
+    openai: Option<OpenAiClient>,
+    fake_template: Option<Arc<str>>,
+    abandoned: AbandonedLog,
+}
+
+/// Deletes the prompt of an interaction dropped before its responses were
+/// stored, so that abandoned interactions leave nothing behind.
+struct AbandonedLog {
+    pool: PgPool,
+    chat_id: i64,
+    /// The recorded prompt; ephemeral interactions record none.
+    prompt_id: Option<i64>,
+    /// Serializes recorded interactions on one chat; released only once an
+    /// abandoned prompt is deleted.
+    chat_lock: Option<ChatLockGuard>,
+    armed: bool,
+}
+
+impl Drop for AbandonedLog {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        debug!(chat_id = self.chat_id, "chat interaction abandoned");
+        let (Some(prompt_id), Ok(runtime)) = (self.prompt_id, Handle::try_current()) else {
+            return;
+        };
+        let pool = self.pool.clone();
+        let chat_id = self.chat_id;
+        let chat_lock = self.chat_lock.take();
+        runtime.spawn(async move {
+            let deleted = sqlx::query!(
+                r#"
+                DELETE FROM chat_messages
+                WHERE id = $1
+                "#,
+                prompt_id
+            )
+            .execute(&pool)
+            .await;
+            if let Err(error) = deleted {
+                warn!("failed to delete abandoned prompt of chat {chat_id}: {error}");
+            }
+            drop(chat_lock);
+        });
+    }
+}
+
+/// How one integration of an interaction is asked, from its overrides.
+#[derive(Debug, Clone, Default)]
+struct IntegrationParams {
+    response_format: pb::ResponseFormat,
+    model: Option<String>,
+    temperature: Option<f64>,
+}
+
+/// A reply with the model that produced it and what it cost.
+struct Generated {
+    content: String,
+    model: Option<String>,
+    usage: TokenUsage,
+}
+
+/// What a single integration contributed to an interaction.
+enum IntegrationOutcome {
+    Responded(pb::ChatMessage),
+    /// The integration was not asked or did not answer; the interaction
+    /// continues without it.
+    Skipped(pb::IntegrationFailure),
+}
+
+impl PendingInteraction {
+    async fn begin(
+        state: &AiChatState,
+        chat_id: i64,
+        payload: pb::InteractChatRequest,
+    ) -> Result<Self, AiChatError> {
+        let prompt = payload.prompt.trim();
+        if prompt.is_empty() {
+            return Err(AiChatError::InvalidField {
+                field: "prompt",
+                reason: "prompt cannot be empty",
+            });
+        }
+
+        let integrations = parse_integrations(payload.integrations)?;
+        let overrides = parse_overrides(payload.overrides, &integrations)?;
+        if let Some(index) = integrations
+            .iter()
+            .position(|integration| state.disabled_integrations.contains(integration))
+        {
+            return Err(AiChatError::UnavailableIntegration { index });
+        }
+
+        let chat_lock = if payload.ephemeral {
+            None
+        } else {
+            let lock = state
+                .chat_locks
+                .acquire(chat_id, state.busy_chat_timeout)
+                .await
+                .ok_or(AiChatError::ChatBusy(chat_id))?;
+            Some(lock)
+        };
+
+        let mut tx = state.pool.begin().await?;
+        let chat = fetch_chat(chat_id, &mut tx).await?;
+        let history = fetch_history(
+            chat_id,
+            state.history_turns,
+            state.history_max_bytes,
+            &mut tx,
+        )
+        .await?;
+        let now = now_unix_millis();
+
+        let prompt_message = if payload.ephemeral {
+            unsaved_message(chat_id, "user", None, prompt.to_owned(), now, None)
+        } else {
+            sqlx::query_as!(
+                ChatMessageRow,
+                r#"
+                INSERT INTO chat_messages (chat_id, role, integration, content, created_at)
+                VALUES ($1, 'user', NULL, $2, $3)
+                RETURNING id, chat_id, role, integration, content, created_at, latency_ms, model,
+                    prompt_tokens, completion_tokens
+                "#,
+                chat_id,
+                prompt,
+                now
+            )
+            .fetch_one(&mut *tx)
+            .await?
+        };
+        tx.commit().await?;
+
+        debug!(
+            chat_id,
+            integrations = integrations.len(),
+            ephemeral = payload.ephemeral,
+            prompt = %LoggedContent::new(prompt.as_bytes(), state.redact),
+            "chat interaction started"
+        );
+
+        Ok(Self {
+            pool: state.pool.clone(),
+            chat,
+            history,
+            abandoned: AbandonedLog {
+                pool: state.pool.clone(),
+                chat_id,
+                prompt_id: (!payload.ephemeral).then_some(prompt_message.id),
+                chat_lock,
+                armed: true,
+            },
+            prompt_message,
+            responses: Vec::with_capacity(integrations.len()),
+            integrations,
+            overrides,
+            rank: payload.rank,
+            ephemeral: payload.ephemeral,
+            now,
+            deadline: tokio::time::Instant::now() + state.interaction_timeout,
+            redact: state.redact,
+            rate_limits: Arc::clone(&state.rate_limits),
+            openai: state.openai.clone(),
+            fake_template: state.fake_template.clone(),
+        })
+    }
+
+    /// Asks the integration at `index` of the requested integrations, tagging
+    /// the outcome with that index.
+    async fn respond(
+        &mut self,
+        index: usize,
+        integration: pb::LlmIntegration,
+    ) -> Result<IntegrationOutcome, AiChatError> {
+        let integration_index = u32::try_from(index).unwrap_or(u32::MAX);
+        if !self.rate_limits.try_acquire(integration) {
+            debug!(
+                chat_id = self.chat.id,
+                integration = integration.as_str_name(),
+                "integration skipped by its rate limit"
+            );
+            return Ok(IntegrationOutcome::Skipped(pb::IntegrationFailure {
+                integration: integration as i32,
+                integration_index,
+                code: "rate_limited".to_owned(),
+                message: format!(
+                    "{} is rate limited, try again later",
+                    integration_display_name(integration)
+                ),
+            }));
+        }
+
+        let params = self
+            .overrides
+            .get(&integration)
+            .cloned()
+            .unwrap_or_default();
+        let started = Instant::now();
+        let Generated {
+            content,
+            model,
+            usage,
+        } = match self.generate(integration, &params).await {
+            Ok(generated) => generated,
+            Err(message) => {
+                warn!(
+                    chat_id = self.chat.id,
+                    integration = integration.as_str_name(),
+                    "integration failed: {message}"
+                );
+                return Ok(IntegrationOutcome::Skipped(pb::IntegrationFailure {
+                    integration: integration as i32,
+                    integration_index,
+                    code: "integration_failed".to_owned(),
+                    message: format!("{} {message}", integration_display_name(integration)),
+                }));
+            }
+        };
+        let latency_ms = i64::try_from(started.elapsed().as_millis()).unwrap_or(i64::MAX);
+        debug!(
+            chat_id = self.chat.id,
+            integration = integration.as_str_name(),
+            response = %LoggedContent::new(content.as_bytes(), self.redact),
+            "integration responded"
+        );
+        let row = unsaved_message(
+            self.chat.id,
+            "assistant",
+            integration_to_db(integration),
+            content,
+            self.now,
+            Some(latency_ms),
+        );
+        let row = ChatMessageRow {
+            model,
+            prompt_tokens: Some(usage.prompt_tokens),
+            completion_tokens: Some(usage.completion_tokens),
+            ..row
+        };
+        self.responses.push((integration_index, row.clone()));
+        Ok(IntegrationOutcome::Responded(pb::ChatMessage {
+            integration_index: Some(integration_index),
+            ..pb::ChatMessage::from(row)
+        }))
+    }
+
+    /// Asks the integration's provider when one is configured, and synthesizes
+    /// a preview response otherwise. Errors describe why the provider gave no
+    /// reply.
+    async fn generate(
+        &self,
+        integration: pb::LlmIntegration,
+        params: &IntegrationParams,
+    ) -> Result<Generated, String> {
+        let prompt = &self.prompt_message.content;
+        let conversation = Conversation {
+            system_prompt: self.chat.system_prompt.as_deref(),
+            history: self
+                .history
+                .iter()
+                .filter(|message| {
+                    message.role == "user"
+                        || message.integration.as_deref() == integration_to_db(integration)
+                })
+                .collect(),
+            prompt,
+        };
+        if let (pb::LlmIntegration::Openai, Some(client)) = (integration, &self.openai) {
+            let model = params.model.as_deref().unwrap_or(client.default_model());
+            let completion = client
+                .complete(
+                    &conversation,
+                    model,
+                    params.response_format,
+                    params.temperature,
+                    self.deadline,
+                )
+                .await?;
+            let usage = completion
+                .usage
+                .unwrap_or_else(|| TokenUsage::estimate(&conversation, &completion.content));
+            return Ok(Generated {
+                content: completion.content,
+                model: Some(model.to_owned()),
+                usage,
+            });
+        }
+
+        let content = synthesize_response(
+            integration,
+            &conversation,
+            params,
+            self.fake_template.as_deref(),
+        );
+        Ok(Generated {
+            usage: TokenUsage::estimate(&conversation, &content),
+            content,
+            model: params.model.clone(),
+        })
+    }
+
+    /// Stores the responses, unless the interaction is ephemeral, in one short
+    /// transaction.
+    async fn finish(
+        mut self,
+        mut failures: Vec<pb::IntegrationFailure>,
+        ranker: &dyn ResponseRanker,
+    ) -> Result<pb::InteractChatResponse, AiChatError> {
+        let mut rows = std::mem::take(&mut self.responses);
+        if !self.ephemeral {
+            let mut tx = self.pool.begin().await?;
+            for (_, row) in &mut rows {
+                *row = sqlx::query_as!(
+                    ChatMessageRow,
+                    r#"
+                    INSERT INTO chat_messages (chat_id, role, integration, content, created_at,
+                        latency_ms, model, prompt_tokens, completion_tokens)
+                    VALUES ($1, 'assistant', $2, $3, $4, $5, $6, $7, $8)
+                    RETURNING id, chat_id, role, integration, content, created_at, latency_ms,
+                        model, prompt_tokens, completion_tokens
+                    "#,
+                    row.chat_id,
+                    row.integration,
+                    row.content,
+                    row.created_at,
+                    row.latency_ms,
+                    row.model,
+                    row.prompt_tokens,
+                    row.completion_tokens
+                )
+                .fetch_one(&mut *tx)
+                .await?;
+            }
+            self.chat.updated_at = self.now;
+            sqlx::query!(
+                r#"
+                UPDATE chats
+                SET updated_at = $1
+                WHERE id = $2
+                "#,
+                self.now,
+                self.chat.id
+            )
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+        }
+        self.abandoned.armed = false;
+
+        let mut responses: Vec<_> = rows
+            .into_iter()
+            .map(|(integration_index, row)| pb::ChatMessage {
+                integration_index: Some(integration_index),
+                ..pb::ChatMessage::from(row)
+            })
+            .collect();
+        order_by_request(&mut responses, &mut failures);
+        if self.rank {
+            rank_responses(ranker, &mut responses);
+        }
+
+        Ok(pb::InteractChatResponse {
+            chat: Some(pb::Chat::from(self.chat)),
+            prompt_message: Some(pb::ChatMessage::from(self.prompt_message)),
+            responses,
+            failures,
+        })
+    }
+}
+
+/// Streams each assistant response or skipped integration as a length-delimited
+/// `InteractChatChunk`, ending with the summary once the responses are
+/// stored. Streamed responses carry id 0 until then. A stream that ends without
+/// a summary means the interaction was abandoned and its prompt deleted.
+fn stream_interaction(
+    interaction: PendingInteraction,
+    ranker: Arc<dyn ResponseRanker>,
+) -> Response {
+    let (chunks_tx, chunks_rx) = mpsc::channel(interaction.integrations.len() + 1);
+    let chat_id = interaction.chat.id;
+
+    tokio::spawn(async move {
+        if let Err(error) = send_interaction_chunks(interaction, ranker.as_ref(), &chunks_tx).await
+        {
+            warn!("chunked interaction for chat {chat_id} failed: {error}");
+        }
+    });
+
+    let chunks = stream::unfold(chunks_rx, |mut chunks_rx| async move {
+        let chunk = chunks_rx.recv().await?;
+        Some((Ok::<_, Infallible>(chunk), chunks_rx))
+    });
+
+    (
+        [(CONTENT_TYPE, PROTOBUF_DELIMITED_CONTENT_TYPE)],
+        Body::from_stream(chunks),
+    )
+        .into_response()
+}
+
+async fn send_interaction_chunks(
+    mut interaction: PendingInteraction,
+    ranker: &dyn ResponseRanker,
+    chunks_tx: &mpsc::Sender<Bytes>,
+) -> Result<(), AiChatError> {
+    let mut failures = Vec::new();
+    for (index, integration) in interaction.integrations.clone().into_iter().enumerate() {
+        let outcome = tokio::select! {
+            outcome = interaction.respond(index, integration) => outcome?,
+            // Stop paying for provider calls nobody will read; dropping the
+            // interaction deletes its prompt.
+            () = chunks_tx.closed() => return Ok(()),
+        };
+        let chunk = match outcome {
+            IntegrationOutcome::Responded(response) => {
+                pb::interact_chat_chunk::Chunk::Response(response)
+            }
+            IntegrationOutcome::Skipped(failure) => {
+                let chunk = pb::interact_chat_chunk::Chunk::Failure(failure.clone());
+                failures.push(failure);
+                chunk
+            }
+        };
+        if chunks_tx.send(encode_chunk(chunk)).await.is_err() {
+            // The client went away; dropping the interaction deletes its prompt.
+            return Ok(());
+        }
+    }
+
+    let summary = interaction.finish(failures, ranker).await?;
+    let _ignored = chunks_tx
+        .send(encode_chunk(pb::interact_chat_chunk::Chunk::Summary(
+            summary,
+        )))
+        .await;
+
+    Ok(())
+}
+
+/// Puts responses and failures back in the order their integrations were
+/// requested, whatever order they completed in.
+fn order_by_request(responses: &mut [pb::ChatMessage], failures: &mut [pb::IntegrationFailure]) {
+    responses.sort_by_key(|response| response.integration_index);
+    failures.sort_by_key(|failure| failure.integration_index);
+}
+
+/// A message that is returned to the client but never stored, hence id 0.
+fn unsaved_message(
+    chat_id: i64,
+    role: &str,
+    integration: Option<&str>,
+    content: String,
+    created_at: i64,
+    latency_ms: Option<i64>,
+) -> ChatMessageRow {
+    ChatMessageRow {
+        id: 0,
+        chat_id,
+        role: role.to_owned(),
+        integration: integration.map(str::to_owned),
+        content,
+        created_at,
+        latency_ms,
+        model: None,
+        prompt_tokens: None,
+        completion_tokens: None,
+    }
+}
+
+fn encode_chunk(chunk: pb::interact_chat_chunk::Chunk) -> Bytes {
+    let chunk = pb::InteractChatChunk { chunk: Some(chunk) };
+    Bytes::from(chunk.encode_length_delimited_to_vec())
+}
+
 fn parse_integrations(values: Vec<i32>) -> Result<Vec<pb::LlmIntegration>, AiChatError> {
     if values.is_empty() {
-        return Err(AiChatError::Validation(
-            "at least one integration must be provided",
-        ));
+        return Err(AiChatError::InvalidField {
+            field: "integrations",
+            reason: "at least one integration must be provided",
+        });
     }
     if values.len() > 4 {
-        return Err(AiChatError::Validation(
-            "a single prompt supports at most 4 integrations",
-        ));
+        return Err(AiChatError::InvalidField {
+            field: "integrations",
+            reason: "a single prompt supports at most 4 integrations",
+        });
     }
 
     let mut integrations = Vec::with_capacity(values.len());
     let mut dedupe = HashSet::with_capacity(values.len());
-    for value in values {
+    for (index, value) in values.into_iter().enumerate() {
         let integration = pb::LlmIntegration::try_from(value)
-            .map_err(|_| AiChatError::Validation("invalid integration value"))?;
+            .map_err(|_| AiChatError::UnknownIntegration { index, value })?;
         if integration == pb::LlmIntegration::Unspecified {
-            return Err(AiChatError::Validation("integration cannot be unspecified"));
+            return Err(AiChatError::UnspecifiedIntegration { index });
         }
 
         if !dedupe.insert(integration as i32) {
-            return Err(AiChatError::Validation(
-                "integrations must not contain duplicates",
-            ));
+            return Err(AiChatError::InvalidField {
+                field: "integrations",
+                reason: "integrations must not contain duplicates",
+            });
         }
 
         integrations.push(integration);
@@ -175,6 +1231,66 @@ fn parse_integrations(values: Vec<i32>) -> Result<Vec<pb::LlmIntegration>, AiCha
     Ok(integrations)
 }
 
+/// The messages of the chat's latest `turns` prompts, oldest first, less the
+/// oldest of those turns that would take the content past `max_bytes`. A turn
+/// is a prompt with the responses recorded after it.
+async fn fetch_history(
+    chat_id: i64,
+    turns: i64,
+    max_bytes: usize,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<Vec<ChatMessageRow>, AiChatError> {
+    if turns == 0 {
+        return Ok(Vec::new());
+    }
+    let history = sqlx::query_as!(
+        ChatMessageRow,
+        r#"
+        WITH recent_prompts AS (
+            SELECT created_at, id
+            FROM chat_messages
+            WHERE chat_id = $1 AND role = 'user'
+            ORDER BY created_at DESC, id DESC
+            LIMIT $2
+        )
+        SELECT id, chat_id, role, integration, content, created_at, latency_ms, model,
+            prompt_tokens, completion_tokens
+        FROM chat_messages
+        WHERE chat_id = $1
+            AND (created_at, id) >= (
+                SELECT created_at, id FROM recent_prompts ORDER BY created_at, id LIMIT 1
+            )
+        ORDER BY created_at, id
+        "#,
+        chat_id,
+        turns
+    )
+    .fetch_all(&mut **tx)
+    .await
+    .map_err(AiChatError::reading_chat(chat_id))?;
+    Ok(trim_history(history, max_bytes))
+}
+
+/// Drops whole turns from the front of `history` until the content of the
+/// rest fits in `max_bytes`.
+fn trim_history(mut history: Vec<ChatMessageRow>, max_bytes: usize) -> Vec<ChatMessageRow> {
+    let mut bytes: usize = history.iter().map(|message| message.content.len()).sum();
+    let mut start = 0;
+    while bytes > max_bytes && start < history.len() {
+        bytes -= history[start].content.len();
+        start += 1;
+        while history
+            .get(start)
+            .is_some_and(|message| message.role != "user")
+        {
+            bytes -= history[start].content.len();
+            start += 1;
+        }
+    }
+    history.drain(..start);
+    history
+}
+
 async fn fetch_chat(
     chat_id: i64,
     tx: &mut Transaction<'_, Postgres>,
@@ -182,20 +1298,117 @@ async fn fetch_chat(
     let chat = sqlx::query_as!(
         ChatRow,
         r#"
-        SELECT id, title, created_at, updated_at
+        SELECT id, title, created_at, updated_at, system_prompt
         FROM chats
         WHERE id = $1
         "#,
         chat_id
     )
     .fetch_optional(&mut **tx)
-    .await?;
+    .await
+    .map_err(AiChatError::reading_chat(chat_id))?;
 
     chat.ok_or(AiChatError::NotFound(chat_id))
 }
 
-fn synthesize_response(integration: pb::LlmIntegration, prompt: &str) -> String {
-    match integration {
+/// Sampling temperatures every supported provider accepts.
+const TEMPERATURE_RANGE: RangeInclusive<f64> = 0.0..=2.0;
+
+/// Maps each integration to its requested parameters, rejecting overrides for
+/// integrations that are not part of the interaction.
+fn parse_overrides(
+    overrides: Vec<pb::IntegrationOverrides>,
+    integrations: &[pb::LlmIntegration],
+) -> Result<HashMap<pb::LlmIntegration, IntegrationParams>, AiChatError> {
+    let mut params = HashMap::with_capacity(overrides.len());
+    for entry in overrides {
+        let integration = pb::LlmIntegration::try_from(entry.integration)
+            .ok()
+            .filter(|integration| integrations.contains(integration))
+            .ok_or(AiChatError::InvalidField {
+                field: "overrides",
+                reason: "overrides must name one of the requested integrations",
+            })?;
+        let response_format =
+            pb::ResponseFormat::try_from(entry.response_format).map_err(|_| {
+                AiChatError::InvalidField {
+                    field: "overrides.response_format",
+                    reason: "response format is unknown",
+                }
+            })?;
+        let model = entry.model.map(|model| model.trim().to_owned());
+        if model.as_deref() == Some("") {
+            return Err(AiChatError::InvalidField {
+                field: "overrides.model",
+                reason: "model cannot be empty",
+            });
+        }
+        if entry
+            .temperature
+            .is_some_and(|temperature| !TEMPERATURE_RANGE.contains(&temperature))
+        {
+            return Err(AiChatError::InvalidField {
+                field: "overrides.temperature",
+                reason: "temperature must be between 0 and 2",
+            });
+        }
+
+        let entry = IntegrationParams {
+            response_format,
+            model,
+            temperature: entry.temperature,
+        };
+        if params.insert(integration, entry).is_some() {
+            return Err(AiChatError::InvalidField {
+                field: "overrides",
+                reason: "overrides must not repeat an integration",
+            });
+        }
+    }
+
+    Ok(params)
+}
+
+/// A preview response worded after `template` when configured, otherwise
+/// after the integration, requested model and the context it was given.
+fn synthesize_response(
+    integration: pb::LlmIntegration,
+    conversation: &Conversation<'_>,
+    params: &IntegrationParams,
+    template: Option<&str>,
+) -> String {
+    let prompt = conversation.prompt;
+    let text = match template {
+        // The prompt goes in last so that placeholders it contains stay literal.
+        Some(template) => template
+            .replace(
+                "{integration}",
+                integration_to_db(integration).unwrap_or_default(),
+            )
+            .replace("{prompt}", prompt),
+        None => builtin_response(integration, conversation, params),
+    };
+
+    match params.response_format {
+        pb::ResponseFormat::Json => serde_json::json!({
+            "format": "json",
+            "integration": integration_to_db(integration),
+            "model": params.model,
+            "temperature": params.temperature,
+            "response": text,
+        })
+        .to_string(),
+        pb::ResponseFormat::Unspecified | pb::ResponseFormat::Text => text,
+    }
+}
+
+fn builtin_response(
+    integration: pb::LlmIntegration,
+    conversation: &Conversation<'_>,
+    params: &IntegrationParams,
+) -> String {
+    let prompt = conversation.prompt;
+    let text = match integration {
         pb::LlmIntegration::Openai => {
             format!("OpenAI preview response: processed prompt `{prompt}`")
         }
@@ -209,5 +1422,278 @@ fn synthesize_response(integration: pb::LlmIntegration, prompt: &str) -> String
             format!("Ollama preview response: processed prompt `{prompt}`")
         }
         pb::LlmIntegration::Unspecified => "Integration not specified".to_owned(),
+    };
+
+    let text = match &params.model {
+        Some(model) => format!("{text} with model `{model}`"),
+        None => text,
+    };
+
+    let mut context = Vec::with_capacity(2);
+    if conversation.system_prompt.is_some() {
+        context.push("system prompt applied".to_owned());
+    }
+    match conversation.turns() {
+        0 => {}
+        1 => context.push("1 prior turn included".to_owned()),
+        turns => context.push(format!("{turns} prior turns included")),
+    }
+    if context.is_empty() {
+        text
+    } else {
+        format!("{text} ({})", context.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_message(values: Vec<i32>) -> String {
+        parse_integrations(values)
+            .expect_err("integrations should be rejected")
+            .to_string()
+    }
+
+    #[test]
+    fn parse_integrations_names_unknown_value_and_index() {
+        let values = vec![pb::LlmIntegration::Openai as i32, 42];
+        assert_eq!(
+            error_message(values),
+            "integration at index 1 has unknown value 42"
+        );
+    }
+
+    #[test]
+    fn parse_integrations_rejects_unspecified_by_index() {
+        let values = vec![
+            pb::LlmIntegration::Gemini as i32,
+            pb::LlmIntegration::Ollama as i32,
+            pb::LlmIntegration::Unspecified as i32,
+        ];
+        assert_eq!(
+            error_message(values),
+            "integration at index 2 cannot be unspecified"
+        );
+    }
+
+    #[test]
+    fn parse_integrations_rejects_negative_values() {
+        assert_eq!(
+            error_message(vec![-1]),
+            "integration at index 0 has unknown value -1"
+        );
+    }
+
+    #[test]
+    fn parse_integrations_keeps_requested_order() {
+        let values = vec![
+            pb::LlmIntegration::Anthropic as i32,
+            pb::LlmIntegration::Openai as i32,
+        ];
+        let integrations = parse_integrations(values).expect("integrations should parse");
+        assert_eq!(
+            integrations,
+            vec![pb::LlmIntegration::Anthropic, pb::LlmIntegration::Openai]
+        );
+    }
+
+    fn overrides(entries: &[(pb::LlmIntegration, i32)]) -> Vec<pb::IntegrationOverrides> {
+        entries
+            .iter()
+            .map(|&(integration, response_format)| pb::IntegrationOverrides {
+                integration: integration.into(),
+                response_format,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parse_overrides_maps_formats_per_integration() {
+        let integrations = [pb::LlmIntegration::Openai, pb::LlmIntegration::Gemini];
+        let formats = parse_overrides(
+            overrides(&[(pb::LlmIntegration::Gemini, pb::ResponseFormat::Json.into())]),
+            &integrations,
+        )
+        .expect("overrides should parse");
+
+        assert_eq!(formats.len(), 1);
+        assert_eq!(
+            formats
+                .get(&pb::LlmIntegration::Gemini)
+                .map(|params| params.response_format),
+            Some(pb::ResponseFormat::Json)
+        );
+    }
+
+    #[test]
+    fn parse_overrides_rejects_out_of_range_temperatures() {
+        for temperature in [-0.1, 2.5, f64::NAN] {
+            let error = parse_overrides(
+                vec![pb::IntegrationOverrides {
+                    integration: pb::LlmIntegration::Openai.into(),
+                    temperature: Some(temperature),
+                    ..Default::default()
+                }],
+                &[pb::LlmIntegration::Openai],
+            )
+            .expect_err("temperature should be rejected");
+            assert_eq!(error.to_string(), "temperature must be between 0 and 2");
+        }
+    }
+
+    #[test]
+    fn parse_overrides_rejects_integrations_outside_the_request() {
+        let error = parse_overrides(
+            overrides(&[(pb::LlmIntegration::Ollama, pb::ResponseFormat::Text.into())]),
+            &[pb::LlmIntegration::Openai],
+        )
+        .expect_err("override should be rejected");
+        assert_eq!(
+            error.to_string(),
+            "overrides must name one of the requested integrations"
+        );
+    }
+
+    #[test]
+    fn order_by_request_undoes_out_of_order_completion() {
+        let response = |integration_index| pb::ChatMessage {
+            integration_index: Some(integration_index),
+            ..Default::default()
+        };
+        let failure = |integration_index| pb::IntegrationFailure {
+            integration_index,
+            ..Default::default()
+        };
+        // Integrations 1 and 4 failed; the rest finished in reverse order.
+        let mut responses = vec![response(3), response(2), response(0)];
+        let mut failures = vec![failure(4), failure(1)];
+
+        order_by_request(&mut responses, &mut failures);
+
+        let response_indexes: Vec<_> = responses
+            .iter()
+            .map(|response| response.integration_index)
+            .collect();
+        assert_eq!(response_indexes, [Some(0), Some(2), Some(3)]);
+        let failure_indexes: Vec<_> = failures
+            .iter()
+            .map(|failure| failure.integration_index)
+            .collect();
+        assert_eq!(failure_indexes, [1, 4]);
+    }
+
+    #[test]
+    fn synthesize_response_echoes_the_requested_format() {
+        let prose = synthesize_response(
+            pb::LlmIntegration::Openai,
+            &conversation("hi", Vec::new()),
+            &IntegrationParams::default(),
+            None,
+        );
+        assert_eq!(prose, "OpenAI preview response: processed prompt `hi`");
+
+        let json = synthesize_response(
+            pb::LlmIntegration::Openai,
+            &conversation("hi", Vec::new()),
+            &IntegrationParams {
+                response_format: pb::ResponseFormat::Json,
+                ..Default::default()
+            },
+            None,
+        );
+        let value: serde_json::Value =
+            serde_json::from_str(&json).expect("JSON format should produce JSON");
+        assert_eq!(value["format"], "json");
+        assert_eq!(value["integration"], "openai");
+        assert_eq!(value["response"], prose);
+    }
+
+    #[test]
+    fn synthesize_response_fills_in_the_template() {
+        let text = synthesize_response(
+            pb::LlmIntegration::Gemini,
+            &Conversation {
+                system_prompt: Some("ignored as well"),
+                ..conversation("hi {integration}", Vec::new())
+            },
+            &IntegrationParams {
+                model: Some("ignored".to_owned()),
+                ..Default::default()
+            },
+            Some("{integration}: {prompt}!"),
+        );
+        assert_eq!(text, "gemini: hi {integration}!");
+    }
+
+    #[test]
+    fn synthesize_response_summarizes_the_context() {
+        let message = |role: &str, content: &str| ChatMessageRow {
+            id: 0,
+            chat_id: 1,
+            role: role.to_owned(),
+            integration: None,
+            content: content.to_owned(),
+            created_at: 0,
+            latency_ms: None,
+            model: None,
+            prompt_tokens: None,
+            completion_tokens: None,
+        };
+        let history = [
+            message("user", "first"),
+            message("assistant", "one"),
+            message("user", "second"),
+        ];
+
+        let text = synthesize_response(
+            pb::LlmIntegration::Ollama,
+            &Conversation {
+                system_prompt: Some("Be brief."),
+                ..conversation("third", history.iter().collect())
+            },
+            &IntegrationParams::default(),
+            None,
+        );
+        assert_eq!(
+            text,
+            "Ollama preview response: processed prompt `third` \
+             (system prompt applied, 2 prior turns included)"
+        );
+    }
+
+    #[test]
+    fn trim_history_drops_whole_turns_oldest_first() {
+        let history = || {
+            [
+                ("user", "aaaa"),
+                ("assistant", "bbbb"),
+                ("assistant", "cc"),
+                ("user", "dd"),
+                ("assistant", "ee"),
+            ]
+            .map(|(role, content)| unsaved_message(1, role, None, content.to_owned(), 0, None))
+            .to_vec()
+        };
+        let contents = |history: Vec<ChatMessageRow>| {
+            history
+                .into_iter()
+                .map(|message| message.content)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(contents(trim_history(history(), 14)).len(), 5);
+        assert_eq!(contents(trim_history(history(), 13)), ["dd", "ee"]);
+        assert_eq!(contents(trim_history(history(), 4)), ["dd", "ee"]);
+        assert!(trim_history(history(), 3).is_empty());
+    }
+
+    fn conversation<'a>(prompt: &'a str, history: Vec<&'a ChatMessageRow>) -> Conversation<'a> {
+        Conversation {
+            system_prompt: None,
+            history,
+            prompt,
+        }
     }
 }
@@ -0,0 +1,248 @@
+use std::time::Duration;
+
+use reqwest::{
+    Client, StatusCode,
+    header::{CONTENT_TYPE, HeaderMap, RETRY_AFTER},
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::{
+    pb,
+    retry::{CallError, RetryPolicy, with_retries},
+    state::{Conversation, TokenUsage},
+};
+
+const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+const DEFAULT_MODEL: &str = "gpt-4o-mini";
+/// How long one completion may take before the interaction gives up on it.
+const REQUEST_TIMEOUT: Duration = Duration::from_mins(1);
+/// JSON mode requires the conversation itself to ask for JSON.
+const JSON_INSTRUCTION: &str = "Respond with a single JSON object.";
+
+/// Credentials and endpoint for the `OpenAI` chat completions API.
+#[derive(Debug, Clone)]
+pub struct OpenAiConfig {
+    pub api_key: String,
+    /// API root without a trailing slash, e.g. `https://api.openai.com/v1`.
+    pub base_url: String,
+    pub model: String,
+}
+
+impl OpenAiConfig {
+    /// Targets the public API with the default model.
+    pub fn new(api_key: impl Into<String>) -> Self {
+        Self {
+            api_key: api_key.into(),
+            base_url: DEFAULT_BASE_URL.to_owned(),
+            model: DEFAULT_MODEL.to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct OpenAiClient {
+    http: Client,
+    config: OpenAiConfig,
+    retry: RetryPolicy,
+}
+
+#[derive(Serialize)]
+struct CompletionRequest<'a> {
+    model: &'a str,
+    messages: Vec<CompletionMessage<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<CompletionFormat>,
+}
+
+#[derive(Serialize)]
+struct CompletionMessage<'a> {
+    role: &'a str,
+    content: &'a str,
+}
+
+#[derive(Serialize)]
+struct CompletionFormat {
+    r#type: &'static str,
+}
+
+#[derive(Deserialize)]
+struct CompletionResponse {
+    choices: Vec<CompletionChoice>,
+    usage: Option<CompletionUsage>,
+}
+
+#[derive(Deserialize)]
+struct CompletionUsage {
+    prompt_tokens: i64,
+    completion_tokens: i64,
+}
+
+/// An assistant reply, with the tokens it cost when the API reported them.
+pub(crate) struct Completion {
+    pub(crate) content: String,
+    pub(crate) usage: Option<TokenUsage>,
+}
+
+#[derive(Deserialize)]
+struct CompletionChoice {
+    message: CompletionReply,
+}
+
+#[derive(Deserialize)]
+struct CompletionReply {
+    content: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: ErrorBody,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    message: String,
+}
+
+impl OpenAiClient {
+    pub(crate) fn new(config: OpenAiConfig, retry: RetryPolicy) -> Self {
+        let http = Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            // Fails only when the TLS backend cannot be initialized, which
+            // `Client::new` treats as fatal too.
+            .expect("failed to build the OpenAI HTTP client");
+        Self {
+            http,
+            config,
+            retry,
+        }
+    }
+
+    /// Model used when an interaction does not choose one.
+    pub(crate) fn default_model(&self) -> &str {
+        &self.config.model
+    }
+
+    /// Sends the conversation, its prompt as the last user message, and returns
+    /// the assistant reply, or a description of why none was received. Rate
+    /// limits, server errors and failed connections are retried first, as long
+    /// as the retry can start before `deadline`.
+    pub(crate) async fn complete(
+        &self,
+        conversation: &Conversation<'_>,
+        model: &str,
+        response_format: pb::ResponseFormat,
+        temperature: Option<f64>,
+        deadline: Instant,
+    ) -> Result<Completion, String> {
+        with_retries(self.retry, deadline, || {
+            self.complete_once(conversation, model, response_format, temperature)
+        })
+        .await
+        .map_err(|error| error.message)
+    }
+
+    async fn complete_once(
+        &self,
+        conversation: &Conversation<'_>,
+        model: &str,
+        response_format: pb::ResponseFormat,
+        temperature: Option<f64>,
+    ) -> Result<Completion, CallError> {
+        let json = response_format == pb::ResponseFormat::Json;
+        let mut messages = Vec::with_capacity(conversation.history.len() + 3);
+        if let Some(system_prompt) = conversation.system_prompt {
+            messages.push(CompletionMessage {
+                role: "system",
+                content: system_prompt,
+            });
+        }
+        if json {
+            messages.push(CompletionMessage {
+                role: "system",
+                content: JSON_INSTRUCTION,
+            });
+        }
+        // Stored roles, `user` and `assistant`, are also the API's.
+        messages.extend(
+            conversation
+                .history
+                .iter()
+                .map(|message| CompletionMessage {
+                    role: &message.role,
+                    content: &message.content,
+                }),
+        );
+        messages.push(CompletionMessage {
+            role: "user",
+            content: conversation.prompt,
+        });
+        let request = CompletionRequest {
+            model,
+            messages,
+            temperature,
+            response_format: json.then_some(CompletionFormat {
+                r#type: "json_object",
+            }),
+        };
+        let body = serde_json::to_vec(&request)
+            .map_err(|error| CallError::permanent(error.to_string()))?;
+
+        let response = self
+            .http
+            .post(format!("{}/chat/completions", self.config.base_url))
+            .bearer_auth(&self.config.api_key)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+            .map_err(|error| CallError::transient(format!("request failed: {error}")))?;
+        let status = response.status();
+        let retry_after = retry_after(response.headers());
+        let body = response.bytes().await.map_err(|error| {
+            CallError::transient(format!("reading the response failed: {error}"))
+        })?;
+
+        if !status.is_success() {
+            let reason = serde_json::from_slice::<ErrorResponse>(&body)
+                .map_or_else(|_| status.to_string(), |error| error.error.message);
+            return Err(CallError {
+                message: format!("answered {}: {reason}", status.as_u16()),
+                transient: status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error(),
+                retry_after,
+            });
+        }
+
+        let response = serde_json::from_slice::<CompletionResponse>(&body)
+            .map_err(|error| CallError::permanent(format!("unexpected response: {error}")))?;
+        let content = response
+            .choices
+            .into_iter()
+            .next()
+            .and_then(|choice| choice.message.content)
+            .ok_or_else(|| CallError::permanent("response contained no reply"))?;
+
+        Ok(Completion {
+            content,
+            usage: response.usage.map(|usage| TokenUsage {
+                prompt_tokens: usage.prompt_tokens,
+                completion_tokens: usage.completion_tokens,
+            }),
+        })
+    }
+}
+
+/// Reads a `Retry-After` given in seconds; HTTP dates fall back to the backoff.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let seconds = headers
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(Duration::from_secs(seconds))
+}
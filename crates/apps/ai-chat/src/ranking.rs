@@ -0,0 +1,93 @@
+use std::cmp::Reverse;
+
+use serde::Deserialize;
+
+use crate::pb;
+
+/// Scores assistant responses so an interaction can flag the best one.
+pub trait ResponseRanker: Send + Sync {
+    fn score(&self, message: &pb::ChatMessage) -> f64;
+}
+
+/// Prefers longer responses, measured in characters.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LengthRanker;
+
+impl ResponseRanker for LengthRanker {
+    fn score(&self, message: &pb::ChatMessage) -> f64 {
+        f64::from(u32::try_from(message.content.chars().count()).unwrap_or(u32::MAX))
+    }
+}
+
+pub(crate) fn rank_responses(ranker: &dyn ResponseRanker, responses: &mut [pb::ChatMessage]) {
+    let mut best: Option<(usize, f64)> = None;
+    for (index, response) in responses.iter_mut().enumerate() {
+        let score = ranker.score(response);
+        response.rank_score = Some(score);
+        if best.is_none_or(|(_, best_score)| score > best_score) {
+            best = Some((index, score));
+        }
+    }
+
+    if let Some((index, _)) = best {
+        responses[index].best = true;
+    }
+}
+
+/// A stored metric to re-sort earlier responses by, without re-running the prompt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum RankBy {
+    /// Fastest first; responses without a recorded latency come last.
+    Latency,
+    /// Longest first, in characters.
+    Length,
+}
+
+/// Sorts the responses following each prompt by `rank_by`; ties keep their
+/// stored order and prompts stay in place.
+pub(crate) fn sort_prompt_groups(messages: &mut [pb::ChatMessage], rank_by: RankBy) {
+    let is_response = |message: &pb::ChatMessage| message.role() == pb::ChatMessageRole::Assistant;
+    for responses in messages.chunk_by_mut(|left, right| is_response(left) && is_response(right)) {
+        match rank_by {
+            RankBy::Latency => responses
+                .sort_by_key(|response| (response.latency_ms.is_none(), response.latency_ms)),
+            RankBy::Length => {
+                responses.sort_by_key(|response| Reverse(response.content.chars().count()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: i64, role: pb::ChatMessageRole, latency_ms: Option<i64>) -> pb::ChatMessage {
+        pb::ChatMessage {
+            id,
+            role: role.into(),
+            latency_ms,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn latency_sorts_each_prompts_responses_fastest_first() {
+        use pb::ChatMessageRole::{Assistant, User};
+        let mut messages = vec![
+            message(1, User, None),
+            message(2, Assistant, None),
+            message(3, Assistant, Some(900)),
+            message(4, Assistant, Some(40)),
+            message(5, User, None),
+            message(6, Assistant, Some(70)),
+            message(7, Assistant, Some(5)),
+        ];
+
+        sort_prompt_groups(&mut messages, RankBy::Latency);
+
+        let ids: Vec<_> = messages.iter().map(|message| message.id).collect();
+        assert_eq!(ids, [1, 4, 3, 2, 5, 7, 6]);
+    }
+}
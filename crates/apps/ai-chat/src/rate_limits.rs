@@ -0,0 +1,107 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::Instant,
+};
+
+use crate::pb;
+
+/// One token bucket per rate-limited integration, refilled continuously so
+/// that each provider's requests-per-minute quota is respected independently.
+#[derive(Debug, Default)]
+pub(crate) struct IntegrationRateLimits {
+    buckets: HashMap<pb::LlmIntegration, Mutex<TokenBucket>>,
+}
+
+impl IntegrationRateLimits {
+    pub(crate) fn new(requests_per_minute: &HashMap<pb::LlmIntegration, u32>) -> Self {
+        let now = Instant::now();
+        let buckets = requests_per_minute
+            .iter()
+            .map(|(&integration, &rpm)| (integration, Mutex::new(TokenBucket::new(rpm, now))))
+            .collect();
+        Self { buckets }
+    }
+
+    /// Takes one request from the integration's quota, or `false` when it is
+    /// used up. Integrations without a configured limit are never limited.
+    pub(crate) fn try_acquire(&self, integration: pb::LlmIntegration) -> bool {
+        self.try_acquire_at(integration, Instant::now())
+    }
+
+    fn try_acquire_at(&self, integration: pb::LlmIntegration, now: Instant) -> bool {
+        self.buckets.get(&integration).is_none_or(|bucket| {
+            bucket
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .try_take(now)
+        })
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(requests_per_minute: u32, now: Instant) -> Self {
+        let capacity = f64::from(requests_per_minute);
+        Self {
+            capacity,
+            tokens: capacity,
+            refill_per_sec: capacity / 60.0,
+            refilled_at: now,
+        }
+    }
+
+    fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.tokens = elapsed
+            .as_secs_f64()
+            .mul_add(self.refill_per_sec, self.tokens)
+            .min(self.capacity);
+        self.refilled_at = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn quota_is_per_integration_and_refills() {
+        let limits = IntegrationRateLimits::new(&HashMap::from([(pb::LlmIntegration::Openai, 2)]));
+        let start = Instant::now();
+
+        assert!(limits.try_acquire_at(pb::LlmIntegration::Openai, start));
+        assert!(limits.try_acquire_at(pb::LlmIntegration::Openai, start));
+        assert!(!limits.try_acquire_at(pb::LlmIntegration::Openai, start));
+        assert!(limits.try_acquire_at(pb::LlmIntegration::Gemini, start));
+
+        // Two requests per minute refill one token every 30 seconds.
+        let refilled = start + Duration::from_secs(30);
+        assert!(limits.try_acquire_at(pb::LlmIntegration::Openai, refilled));
+        assert!(!limits.try_acquire_at(pb::LlmIntegration::Openai, refilled));
+    }
+
+    #[test]
+    fn zero_rpm_blocks_the_integration() {
+        let limits = IntegrationRateLimits::new(&HashMap::from([(pb::LlmIntegration::Ollama, 0)]));
+        let later = Instant::now() + Duration::from_hours(1);
+
+        assert!(!limits.try_acquire_at(pb::LlmIntegration::Ollama, later));
+    }
+}
@@ -0,0 +1,57 @@
+use std::fmt;
+
+use sha2::{Digest, Sha256};
+
+/// Marks requests whose prompt and response content must not reach the logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RedactContent(pub(crate) bool);
+
+/// Content as it should appear in a log field: verbatim, or as its length and
+/// a short hash so identical prompts can still be correlated.
+pub(crate) struct LoggedContent<'a> {
+    content: &'a [u8],
+    redact: bool,
+}
+
+impl<'a> LoggedContent<'a> {
+    pub(crate) fn new(content: &'a [u8], redact: RedactContent) -> Self {
+        Self {
+            content,
+            redact: redact.0,
+        }
+    }
+}
+
+impl fmt::Display for LoggedContent<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.redact {
+            return f.write_str(&String::from_utf8_lossy(self.content));
+        }
+
+        let digest = Sha256::digest(self.content);
+        write!(
+            f,
+            "<redacted len={} sha256={}>",
+            self.content.len(),
+            hex::encode(&digest[..8])
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacted_content_shows_only_length_and_hash() {
+        let logged = LoggedContent::new(b"my password is hunter2", RedactContent(true)).to_string();
+        assert!(logged.starts_with("<redacted len=22 sha256="));
+        assert!(!logged.contains("hunter2"));
+    }
+
+    #[test]
+    fn unredacted_content_is_logged_verbatim() {
+        let logged = LoggedContent::new(b"hello", RedactContent(false)).to_string();
+        assert_eq!(logged, "hello");
+    }
+}
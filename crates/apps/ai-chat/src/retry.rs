@@ -0,0 +1,215 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+use tracing::warn;
+
+/// Delay before the first retry; each further retry doubles it.
+const BASE_DELAY: Duration = Duration::from_millis(250);
+const MAX_DELAY: Duration = Duration::from_secs(8);
+/// Longest `Retry-After` honored; a provider asking for more is given up on.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+/// How often, and how patiently, a failed provider call is repeated.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RetryPolicy {
+    pub(crate) max_retries: u32,
+    pub(crate) base_delay: Duration,
+    pub(crate) max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub(crate) fn new(max_retries: u32) -> Self {
+        Self {
+            max_retries,
+            base_delay: BASE_DELAY,
+            max_delay: MAX_DELAY,
+        }
+    }
+
+    /// Exponential backoff with jitter: somewhere between half and all of
+    /// `base_delay * 2^retry`, capped at `max_delay`.
+    fn backoff(&self, retry: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2_u32.saturating_pow(retry))
+            .min(self.max_delay);
+        delay / 2 + delay.mul_f64(rand::random::<f64>() / 2.0)
+    }
+}
+
+/// Why a provider call failed, and whether repeating it may help.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CallError {
+    pub(crate) message: String,
+    /// Rate limits, server errors and dropped connections; not bad requests.
+    pub(crate) transient: bool,
+    /// The provider's `Retry-After`, used instead of the backoff delay.
+    pub(crate) retry_after: Option<Duration>,
+}
+
+impl CallError {
+    pub(crate) fn permanent(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            transient: false,
+            retry_after: None,
+        }
+    }
+
+    pub(crate) fn transient(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            transient: true,
+            retry_after: None,
+        }
+    }
+}
+
+/// Runs `call` until it succeeds, fails permanently, or has been retried
+/// `policy.max_retries` times, and returns its last outcome. No retry is made
+/// whose delay would end past `deadline`.
+pub(crate) async fn with_retries<T, F, Fut>(
+    policy: RetryPolicy,
+    deadline: Instant,
+    mut call: F,
+) -> Result<T, CallError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, CallError>>,
+{
+    let mut retry = 0;
+    loop {
+        let error = match call().await {
+            Ok(value) => return Ok(value),
+            Err(error) => error,
+        };
+        if !error.transient || retry >= policy.max_retries {
+            return Err(error);
+        }
+        let delay = match error.retry_after {
+            Some(retry_after) if retry_after > MAX_RETRY_AFTER => return Err(error),
+            Some(retry_after) => retry_after,
+            None => policy.backoff(retry),
+        };
+        if Instant::now() + delay >= deadline {
+            return Err(error);
+        }
+        retry += 1;
+        warn!(
+            retry,
+            delay_ms = u64::try_from(delay.as_millis()).unwrap_or(u64::MAX),
+            "provider call failed, retrying: {}",
+            error.message
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::{Ready, ready},
+        sync::atomic::{AtomicU32, Ordering},
+    };
+
+    use super::*;
+
+    const INSTANT: RetryPolicy = RetryPolicy {
+        max_retries: 2,
+        base_delay: Duration::ZERO,
+        max_delay: Duration::ZERO,
+    };
+
+    fn far_deadline() -> Instant {
+        Instant::now() + Duration::from_mins(1)
+    }
+
+    /// A provider that fails transiently `failures` times before answering.
+    fn flaky(calls: &AtomicU32, failures: u32) -> Ready<Result<&'static str, CallError>> {
+        ready(if calls.fetch_add(1, Ordering::SeqCst) < failures {
+            Err(CallError::transient("answered 503: overloaded"))
+        } else {
+            Ok("reply")
+        })
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried() {
+        let calls = AtomicU32::new(0);
+
+        let result = with_retries(INSTANT, far_deadline(), || flaky(&calls, 2)).await;
+
+        assert_eq!(result, Ok("reply"));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn the_last_error_surfaces_once_retries_run_out() {
+        let calls = AtomicU32::new(0);
+
+        let result = with_retries(INSTANT, far_deadline(), || flaky(&calls, 5)).await;
+
+        assert_eq!(
+            result,
+            Err(CallError::transient("answered 503: overloaded"))
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn permanent_failures_and_long_retry_afters_are_not_retried() {
+        let calls = AtomicU32::new(0);
+        let result: Result<(), _> = with_retries(INSTANT, far_deadline(), || {
+            calls.fetch_add(1, Ordering::SeqCst);
+            async { Err(CallError::permanent("answered 400: bad request")) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let patient = CallError {
+            retry_after: Some(MAX_RETRY_AFTER + Duration::from_secs(1)),
+            ..CallError::transient("answered 429: slow down")
+        };
+        let result: Result<(), _> = with_retries(INSTANT, far_deadline(), || {
+            calls.fetch_add(1, Ordering::SeqCst);
+            let patient = patient.clone();
+            async move { Err(patient) }
+        })
+        .await;
+        assert_eq!(result, Err(patient));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn retries_stop_at_the_deadline() {
+        let calls = AtomicU32::new(0);
+        let patient = RetryPolicy::new(2);
+
+        let result = with_retries(patient, Instant::now() + BASE_DELAY / 4, || {
+            flaky(&calls, 1)
+        })
+        .await;
+
+        assert_eq!(
+            result,
+            Err(CallError::transient("answered 503: overloaded"))
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn backoff_doubles_within_jitter_and_is_capped() {
+        let policy = RetryPolicy::new(5);
+
+        for retry in 0..3 {
+            let full = BASE_DELAY * 2_u32.pow(retry);
+            let delay = policy.backoff(retry);
+            assert!(
+                delay >= full / 2 && delay <= full,
+                "{delay:?} for retry {retry}"
+            );
+        }
+        assert!(policy.backoff(20) <= MAX_DELAY);
+    }
+}
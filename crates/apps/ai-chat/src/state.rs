@@ -1,12 +1,68 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use axum::http::Extensions;
+use protobuf_http::ProtobufState;
+use serde::Serialize;
 use sqlx::PgPool;
+use tracing::{Level, trace};
 
-use crate::pb;
+use crate::{
+    AiChatConfig, AiChatError, ResponseRanker,
+    chat_locks::ChatLocks,
+    openai::OpenAiClient,
+    pb,
+    rate_limits::IntegrationRateLimits,
+    redaction::{LoggedContent, RedactContent},
+    retry::RetryPolicy,
+};
+
+/// Enable with `RUST_LOG=ai_chat::payload=trace` to log decoded requests as JSON.
+const PAYLOAD_LOG_TARGET: &str = "ai_chat::payload";
 
 #[derive(Clone)]
 pub(crate) struct AiChatState {
     pub(crate) pool: PgPool,
+    pub(crate) ranker: Arc<dyn ResponseRanker>,
+    pub(crate) allow_explain: bool,
+    pub(crate) disabled_integrations: Arc<[pb::LlmIntegration]>,
+    pub(crate) redact: RedactContent,
+    pub(crate) max_list_rows: usize,
+    pub(crate) chat_locks: Arc<ChatLocks>,
+    pub(crate) busy_chat_timeout: Duration,
+    pub(crate) rate_limits: Arc<IntegrationRateLimits>,
+    pub(crate) openai: Option<OpenAiClient>,
+    pub(crate) fake_template: Option<Arc<str>>,
+    pub(crate) history_turns: i64,
+    pub(crate) history_max_bytes: usize,
+    pub(crate) interaction_timeout: Duration,
+}
+
+impl ProtobufState for AiChatState {
+    type Rejection = AiChatError;
+
+    fn decoded<T: Serialize>(extensions: &Extensions, message: &T, body: &[u8]) {
+        if !tracing::enabled!(target: PAYLOAD_LOG_TARGET, Level::TRACE) {
+            return;
+        }
+        // Redact unless the router explicitly opted out.
+        let redact = extensions
+            .get::<RedactContent>()
+            .copied()
+            .unwrap_or(RedactContent(true));
+        if redact.0 {
+            let payload = LoggedContent::new(body, redact);
+            trace!(target: PAYLOAD_LOG_TARGET, %payload, "decoded request");
+            return;
+        }
+
+        match serde_json::to_string(message) {
+            Ok(json) => trace!(target: PAYLOAD_LOG_TARGET, payload = %json, "decoded request"),
+            Err(error) => trace!(target: PAYLOAD_LOG_TARGET, %error, "failed to serialize request"),
+        }
+    }
 }
 
 #[derive(Debug, Clone, sqlx::FromRow)]
@@ -15,6 +71,21 @@ pub(crate) struct ChatRow {
     pub(crate) title: String,
     pub(crate) created_at: i64,
     pub(crate) updated_at: i64,
+    pub(crate) system_prompt: Option<String>,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub(crate) struct ChatPreviewRow {
+    pub(crate) id: i64,
+    pub(crate) title: String,
+    pub(crate) created_at: i64,
+    pub(crate) updated_at: i64,
+    pub(crate) system_prompt: Option<String>,
+    pub(crate) message_id: Option<i64>,
+    pub(crate) message_role: Option<String>,
+    pub(crate) message_integration: Option<String>,
+    pub(crate) message_content: Option<String>,
+    pub(crate) message_created_at: Option<i64>,
 }
 
 #[derive(Debug, Clone, sqlx::FromRow)]
@@ -25,6 +96,56 @@ pub(crate) struct ChatMessageRow {
     pub(crate) integration: Option<String>,
     pub(crate) content: String,
     pub(crate) created_at: i64,
+    pub(crate) latency_ms: Option<i64>,
+    pub(crate) model: Option<String>,
+    pub(crate) prompt_tokens: Option<i64>,
+    pub(crate) completion_tokens: Option<i64>,
+}
+
+/// Tokens an integration response cost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct TokenUsage {
+    pub(crate) prompt_tokens: i64,
+    pub(crate) completion_tokens: i64,
+}
+
+impl TokenUsage {
+    /// Roughly four characters per token, as a stand-in where no provider
+    /// counted them. The prompt is all of `conversation` the provider is sent.
+    pub(crate) fn estimate(conversation: &Conversation<'_>, completion: &str) -> Self {
+        let chars = |text: &str| text.chars().count();
+        let tokens = |chars: usize| i64::try_from(chars.div_ceil(4)).unwrap_or(i64::MAX);
+        let prompt_chars = conversation.system_prompt.map_or(0, chars)
+            + conversation
+                .history
+                .iter()
+                .map(|message| chars(&message.content))
+                .sum::<usize>()
+            + chars(conversation.prompt);
+        Self {
+            prompt_tokens: tokens(prompt_chars),
+            completion_tokens: tokens(chars(completion)),
+        }
+    }
+}
+
+/// What an integration is asked to continue: the chat's system prompt, its
+/// earlier turns and the new prompt.
+pub(crate) struct Conversation<'a> {
+    pub(crate) system_prompt: Option<&'a str>,
+    /// Earlier prompts and the integration's own replies to them, oldest first.
+    pub(crate) history: Vec<&'a ChatMessageRow>,
+    pub(crate) prompt: &'a str,
+}
+
+impl Conversation<'_> {
+    /// Earlier prompts included as context.
+    pub(crate) fn turns(&self) -> usize {
+        self.history
+            .iter()
+            .filter(|message| message.role == "user")
+            .count()
+    }
 }
 
 impl From<ChatRow> for pb::Chat {
@@ -34,6 +155,44 @@ impl From<ChatRow> for pb::Chat {
             title: value.title,
             created_at_unix_ms: value.created_at,
             updated_at_unix_ms: value.updated_at,
+            last_message: None,
+            system_prompt: value.system_prompt,
+        }
+    }
+}
+
+impl From<ChatPreviewRow> for pb::Chat {
+    fn from(value: ChatPreviewRow) -> Self {
+        let last_message = match (
+            value.message_id,
+            value.message_role,
+            value.message_content,
+            value.message_created_at,
+        ) {
+            (Some(id), Some(role), Some(content), Some(created_at)) => {
+                Some(pb::ChatMessage::from(ChatMessageRow {
+                    id,
+                    chat_id: value.id,
+                    role,
+                    integration: value.message_integration,
+                    content,
+                    created_at,
+                    latency_ms: None,
+                    model: None,
+                    prompt_tokens: None,
+                    completion_tokens: None,
+                }))
+            }
+            _ => None,
+        };
+
+        Self {
+            id: value.id,
+            title: value.title,
+            created_at_unix_ms: value.created_at,
+            updated_at_unix_ms: value.updated_at,
+            last_message,
+            system_prompt: value.system_prompt,
         }
     }
 }
@@ -47,12 +206,36 @@ impl From<ChatMessageRow> for pb::ChatMessage {
             integration: integration_to_proto(value.integration.as_deref()) as i32,
             content: value.content,
             created_at_unix_ms: value.created_at,
+            rank_score: None,
+            best: false,
+            latency_ms: value.latency_ms,
+            integration_index: None,
+            model: value.model,
+            prompt_tokens: value.prompt_tokens,
+            completion_tokens: value.completion_tokens,
         }
     }
 }
 
-pub(crate) fn build_state(pool: PgPool) -> AiChatState {
-    AiChatState { pool }
+pub(crate) fn build_state(pool: PgPool, config: AiChatConfig) -> AiChatState {
+    AiChatState {
+        pool,
+        ranker: config.ranker,
+        allow_explain: config.allow_explain,
+        disabled_integrations: config.disabled_integrations.into(),
+        redact: RedactContent(config.redact_logged_content),
+        max_list_rows: config.max_list_rows,
+        chat_locks: Arc::default(),
+        busy_chat_timeout: config.busy_chat_timeout,
+        rate_limits: Arc::new(IntegrationRateLimits::new(&config.requests_per_minute)),
+        openai: config
+            .openai
+            .map(|openai| OpenAiClient::new(openai, RetryPolicy::new(config.max_retries))),
+        fake_template: config.fake_template.map(Arc::from),
+        history_turns: i64::try_from(config.history_turns).unwrap_or(i64::MAX),
+        history_max_bytes: config.history_max_bytes,
+        interaction_timeout: config.interaction_timeout,
+    }
 }
 
 pub(crate) fn now_unix_millis() -> i64 {
@@ -62,6 +245,24 @@ pub(crate) fn now_unix_millis() -> i64 {
     }
 }
 
+/// Every concrete integration, in the order they are presented to clients.
+pub(crate) const INTEGRATIONS: [pb::LlmIntegration; 4] = [
+    pb::LlmIntegration::Openai,
+    pb::LlmIntegration::Anthropic,
+    pb::LlmIntegration::Gemini,
+    pb::LlmIntegration::Ollama,
+];
+
+pub(crate) fn integration_display_name(integration: pb::LlmIntegration) -> &'static str {
+    match integration {
+        pb::LlmIntegration::Unspecified => "Unspecified",
+        pb::LlmIntegration::Openai => "OpenAI",
+        pb::LlmIntegration::Anthropic => "Anthropic",
+        pb::LlmIntegration::Gemini => "Gemini",
+        pb::LlmIntegration::Ollama => "Ollama",
+    }
+}
+
 pub(crate) fn integration_to_db(integration: pb::LlmIntegration) -> Option<&'static str> {
     match integration {
         pb::LlmIntegration::Unspecified => None,
@@ -72,7 +273,7 @@ pub(crate) fn integration_to_db(integration: pb::LlmIntegration) -> Option<&'sta
     }
 }
 
-fn integration_to_proto(integration: Option<&str>) -> pb::LlmIntegration {
+pub(crate) fn integration_to_proto(integration: Option<&str>) -> pb::LlmIntegration {
     match integration {
         Some("openai") => pb::LlmIntegration::Openai,
         Some("anthropic") => pb::LlmIntegration::Anthropic,
@@ -3,6 +3,9 @@ fn main() {
         protoc_bin_vendored::protoc_bin_path().expect("failed to find bundled protoc");
     prost_build::Config::new()
         .protoc_executable(protoc_path)
+        .type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]")
+        // Lets JSON requests omit fields, as protobuf ones may.
+        .message_attribute(".", "#[serde(default)]")
         .compile_protos(&["proto/notes.proto"], &["proto"])
         .expect("failed to compile notes protobuf schema");
 }
@@ -0,0 +1,5 @@
+ALTER TABLE notes ADD COLUMN IF NOT EXISTS due_at BIGINT NULL;
+
+CREATE INDEX IF NOT EXISTS idx_notes_due_at
+    ON notes (due_at, id)
+    WHERE due_at IS NOT NULL;
@@ -0,0 +1,6 @@
+ALTER TABLE notes
+    ADD COLUMN IF NOT EXISTS parent_id BIGINT NULL REFERENCES notes(id) ON DELETE SET NULL;
+
+CREATE INDEX IF NOT EXISTS idx_notes_parent_id
+    ON notes (parent_id, id)
+    WHERE parent_id IS NOT NULL;
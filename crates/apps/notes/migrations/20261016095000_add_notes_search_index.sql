@@ -0,0 +1,3 @@
+CREATE INDEX IF NOT EXISTS idx_notes_search
+    ON notes
+    USING GIN (to_tsvector('english', title || ' ' || body));
@@ -0,0 +1,5 @@
+ALTER TABLE notes
+    ADD COLUMN IF NOT EXISTS deleted_at BIGINT NULL;
+CREATE INDEX IF NOT EXISTS idx_notes_trash
+    ON notes (deleted_at DESC)
+    WHERE deleted_at IS NOT NULL;
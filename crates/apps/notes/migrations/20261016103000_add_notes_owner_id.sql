@@ -0,0 +1,4 @@
+-- Subject of the user who created the note; NULL for notes created while the
+-- server ran unauthenticated.
+ALTER TABLE notes
+    ADD COLUMN IF NOT EXISTS owner_id TEXT NULL;
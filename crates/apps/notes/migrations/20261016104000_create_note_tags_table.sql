@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS note_tags (
+    note_id BIGINT NOT NULL REFERENCES notes(id) ON DELETE CASCADE,
+    tag TEXT NOT NULL,
+    PRIMARY KEY (note_id, tag)
+);
+
+CREATE INDEX IF NOT EXISTS idx_note_tags_tag
+    ON note_tags (tag, note_id);
@@ -0,0 +1,11 @@
+-- Content of every superseded note version, recorded in the transaction that
+-- replaced it.
+CREATE TABLE IF NOT EXISTS note_revisions (
+    note_id BIGINT NOT NULL REFERENCES notes(id) ON DELETE CASCADE,
+    version BIGINT NOT NULL,
+    title TEXT NOT NULL,
+    body TEXT NOT NULL,
+    updated_at BIGINT NOT NULL,
+    updated_by TEXT NULL,
+    PRIMARY KEY (note_id, version)
+);
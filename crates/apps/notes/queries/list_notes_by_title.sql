@@ -0,0 +1,11 @@
+SELECT id, title, CASE WHEN $7 THEN body ELSE '' END AS "body!", created_at, updated_at, version,
+    due_at, parent_id, updated_by,
+    ARRAY(SELECT tag FROM note_tags WHERE note_id = notes.id ORDER BY tag) AS "tags!"
+FROM notes
+WHERE deleted_at IS NULL AND owner_id IS NOT DISTINCT FROM $4
+    AND ($2::BIGINT IS NULL OR id >= $2) AND ($3::BIGINT IS NULL OR id <= $3)
+    AND ($5::TEXT IS NULL
+        OR EXISTS(SELECT 1 FROM note_tags WHERE note_id = notes.id AND tag = $5))
+    AND ($6::BIGINT IS NULL OR (title, id) > (SELECT title, id FROM notes WHERE id = $6))
+ORDER BY title, id
+LIMIT $1
@@ -0,0 +1,39 @@
+use axum::{extract::FromRequestParts, http::request::Parts};
+
+use crate::{NoteOwner, NotesError};
+
+const ACTOR_HEADER: &str = "x-actor";
+const MAX_ACTOR_LEN: usize = 256;
+
+/// Who is making the request: the authenticated [`NoteOwner`] or, when the
+/// server runs unauthenticated, the `X-Actor` header; `None` when absent.
+pub(crate) struct Actor(pub(crate) Option<String>);
+
+impl<S> FromRequestParts<S> for Actor
+where
+    S: Send + Sync,
+{
+    type Rejection = NotesError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        // Authenticated users cannot write someone else's name into the audit trail.
+        if let Some(NoteOwner(owner)) = parts.extensions.get::<NoteOwner>() {
+            return Ok(Self(Some(owner.clone())));
+        }
+        let Some(value) = parts.headers.get(ACTOR_HEADER) else {
+            return Ok(Self(None));
+        };
+
+        let actor = value
+            .to_str()
+            .map_err(|_| NotesError::Validation("X-Actor must be visible ASCII"))?
+            .trim();
+        if actor.len() > MAX_ACTOR_LEN {
+            return Err(NotesError::Validation(
+                "X-Actor cannot exceed 256 characters",
+            ));
+        }
+
+        Ok(Self((!actor.is_empty()).then(|| actor.to_owned())))
+    }
+}
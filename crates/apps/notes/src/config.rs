@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+/// Events kept in memory for `?since=` resumption when not configured.
+const DEFAULT_EVENT_BUFFER_SIZE: usize = 256;
+const DEFAULT_MAX_SUBSCRIBERS_PER_NOTE: usize = 1_000;
+const DEFAULT_MAX_LIST_ROWS: usize = 1_000;
+const DEFAULT_BULK_EVENT_THRESHOLD: usize = 100;
+const DEFAULT_BROADCAST_CAPACITY: usize = 512;
+const DEFAULT_BROADCAST_DEGRADED_PERCENT: usize = 80;
+/// Largest request body buffered for decoding when not configured.
+pub(crate) const DEFAULT_MAX_BODY_BYTES: usize = 1024 * 1024;
+const DEFAULT_MAX_BATCH_BODY_BYTES: usize = 16 * DEFAULT_MAX_BODY_BYTES;
+const DEFAULT_MAX_TITLE_CHARS: usize = 255;
+const DEFAULT_MAX_NOTE_BODY_BYTES: usize = 1024 * 1024;
+const DEFAULT_RECONNECT_TOKEN_TTL: Duration = Duration::from_mins(10);
+const DEFAULT_RECONNECT_TOKEN_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_mins(1);
+
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Clone, Copy)]
+pub struct NotesConfig {
+    /// Lets list endpoints answer `?explain=true` with the query plan. Development only.
+    pub allow_explain: bool,
+    /// How many recent events realtime subscribers can resume from; 0 disables resumption.
+    pub event_buffer_size: usize,
+    /// Enables the irreversible `DELETE /{note_id}/purge` endpoint.
+    pub allow_purge: bool,
+    /// How long an issued `reconnect_token` can be used to `?resume=`.
+    pub reconnect_token_ttl: Duration,
+    /// How often realtime subscribers receive a fresh `reconnect_token`.
+    pub reconnect_token_interval: Duration,
+    /// Fields whose changes bump `version` and emit an `Updated` event.
+    pub significant_fields: SignificantFields,
+    /// Realtime subscriptions filtered to one `note_id` allowed per note.
+    pub max_subscribers_per_note: usize,
+    /// Most notes a single list response returns; longer lists are marked `truncated`.
+    pub max_list_rows: usize,
+    /// Batch writes changing more notes than this publish a single `BulkChange`
+    /// event instead of one event per note.
+    pub bulk_event_threshold: usize,
+    /// How often realtime subscribers are pinged to keep idle connections open.
+    pub heartbeat_interval: Duration,
+    /// How long a ping may go without any reply before the connection is
+    /// dropped; longer than `heartbeat_interval`, so that a client missing one
+    /// ping is pinged again before it is given up on.
+    pub heartbeat_timeout: Duration,
+    /// Reads and writes JSON bodies for clients that send no `Content-Type` or
+    /// `Accept` naming a format, instead of protobuf.
+    pub json_by_default: bool,
+    /// Largest request body accepted, in bytes; larger ones answer `413`.
+    pub max_body_bytes: usize,
+    /// Largest `POST /batch` body accepted, in bytes, in place of
+    /// `max_body_bytes`.
+    pub max_batch_body_bytes: usize,
+    /// Longest note title, in characters (Unicode scalar values).
+    pub max_title_chars: usize,
+    /// Longest note body, in UTF-8 bytes.
+    pub max_note_body_bytes: usize,
+    /// Events a realtime subscriber may fall behind the broadcast before it
+    /// lags and is sent a `Resync`.
+    pub broadcast_capacity: usize,
+    /// Percentage of the realtime broadcast channel that may fill up before
+    /// `GET /health` reports the notes app as degraded.
+    pub broadcast_degraded_percent: usize,
+    /// Relays realtime events through Postgres `LISTEN`/`NOTIFY`, so that
+    /// subscribers of every instance sharing the database receive them.
+    pub relay_events: bool,
+}
+
+impl Default for NotesConfig {
+    fn default() -> Self {
+        Self {
+            allow_explain: false,
+            event_buffer_size: DEFAULT_EVENT_BUFFER_SIZE,
+            allow_purge: false,
+            reconnect_token_ttl: DEFAULT_RECONNECT_TOKEN_TTL,
+            reconnect_token_interval: DEFAULT_RECONNECT_TOKEN_INTERVAL,
+            significant_fields: SignificantFields::default(),
+            max_subscribers_per_note: DEFAULT_MAX_SUBSCRIBERS_PER_NOTE,
+            max_list_rows: DEFAULT_MAX_LIST_ROWS,
+            bulk_event_threshold: DEFAULT_BULK_EVENT_THRESHOLD,
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            heartbeat_timeout: DEFAULT_HEARTBEAT_TIMEOUT,
+            json_by_default: false,
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+            max_batch_body_bytes: DEFAULT_MAX_BATCH_BODY_BYTES,
+            max_title_chars: DEFAULT_MAX_TITLE_CHARS,
+            max_note_body_bytes: DEFAULT_MAX_NOTE_BODY_BYTES,
+            broadcast_capacity: DEFAULT_BROADCAST_CAPACITY,
+            broadcast_degraded_percent: DEFAULT_BROADCAST_DEGRADED_PERCENT,
+            relay_events: false,
+        }
+    }
+}
+
+/// Which note fields count as a new version when they change.
+///
+/// Changes to other fields are still saved and refresh `updated_at`, but keep
+/// the current `version` (and so the `ETag`) and are not broadcast. By default
+/// `title`, `body` and `tags` are significant.
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignificantFields {
+    pub title: bool,
+    pub body: bool,
+    pub due_at: bool,
+    pub parent: bool,
+    pub tags: bool,
+}
+
+impl SignificantFields {
+    pub const NONE: Self = Self {
+        title: false,
+        body: false,
+        due_at: false,
+        parent: false,
+        tags: false,
+    };
+}
+
+impl Default for SignificantFields {
+    fn default() -> Self {
+        Self {
+            title: true,
+            body: true,
+            tags: true,
+            ..Self::NONE
+        }
+    }
+}
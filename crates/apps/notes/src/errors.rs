@@ -1,8 +1,17 @@
+use std::collections::HashMap;
+
 use axum::{
-    http::StatusCode,
+    http::{HeaderValue, StatusCode, header::RETRY_AFTER},
     response::{IntoResponse, Response},
 };
+use protobuf_http::Rejection;
 use thiserror::Error;
+use tracing::error;
+
+use crate::{Protobuf, pb};
+
+/// Seconds a client should wait before retrying when the connection pool is exhausted.
+const POOL_TIMEOUT_RETRY_AFTER_SECS: HeaderValue = HeaderValue::from_static("1");
 
 #[derive(Debug, Error)]
 pub enum NotesError {
@@ -10,27 +19,374 @@ pub enum NotesError {
     InvalidBody,
     #[error("invalid protocol buffers payload: {0}")]
     InvalidProtobuf(prost::DecodeError),
+    #[error("invalid JSON payload: {0}")]
+    InvalidJson(serde_json::Error),
+    #[error("request body must be application/x-protobuf or application/json")]
+    UnsupportedMediaType,
+    #[error("request body exceeds {limit} bytes")]
+    BodyTooLarge { limit: usize },
     #[error("note {0} was not found")]
     NotFound(i64),
+    #[error("note {note_id} has no revision at version {version}")]
+    RevisionNotFound { note_id: i64, version: i64 },
     #[error("{0}")]
     Validation(&'static str),
+    /// A `Validation` failure that is down to one request field.
+    #[error("{reason}")]
+    InvalidField {
+        field: &'static str,
+        reason: &'static str,
+    },
+    /// A validation failure of one item of a batch request.
+    #[error("item {index}: {reason}")]
+    InvalidBatchItem {
+        index: usize,
+        field: Option<&'static str>,
+        reason: &'static str,
+    },
+    #[error("{0}")]
+    Forbidden(&'static str),
+    #[error("note {note_id} is at version {current_version}")]
+    VersionConflict { note_id: i64, current_version: i64 },
+    /// A stored row could not be decoded, e.g. text that is not valid UTF-8.
+    #[error("stored note data could not be read")]
+    DataCorruption(Option<i64>),
     #[error("database error: {0}")]
-    Database(#[from] sqlx::Error),
+    Database(sqlx::Error),
+}
+
+impl From<Rejection> for NotesError {
+    fn from(rejection: Rejection) -> Self {
+        match rejection {
+            Rejection::InvalidBody => Self::InvalidBody,
+            Rejection::InvalidProtobuf(error) => Self::InvalidProtobuf(error),
+            Rejection::InvalidJson(error) => Self::InvalidJson(error),
+            Rejection::BodyTooLarge { limit } => Self::BodyTooLarge { limit },
+            Rejection::UnsupportedMediaType => Self::UnsupportedMediaType,
+        }
+    }
+}
+
+impl From<sqlx::Error> for NotesError {
+    fn from(error: sqlx::Error) -> Self {
+        if is_undecodable(&error) {
+            error!(%error, "failed to decode a stored note");
+            return Self::DataCorruption(None);
+        }
+        Self::Database(error)
+    }
+}
+
+/// Postgres `character_not_in_repertoire`, raised when stored text is invalid in
+/// the client encoding.
+const CHARACTER_NOT_IN_REPERTOIRE: &str = "22021";
+
+fn is_undecodable(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::ColumnDecode { .. } => true,
+        sqlx::Error::Database(error) => {
+            error.code().as_deref() == Some(CHARACTER_NOT_IN_REPERTOIRE)
+        }
+        _ => false,
+    }
+}
+
+impl NotesError {
+    /// Converts a database error raised while reading the row of `note_id`,
+    /// naming that row if it turns out to be undecodable.
+    pub(crate) fn reading_note(note_id: i64) -> impl FnOnce(sqlx::Error) -> Self {
+        move |error| {
+            if is_undecodable(&error) {
+                error!(note_id, %error, "failed to decode a stored note");
+                return Self::DataCorruption(Some(note_id));
+            }
+            Self::Database(error)
+        }
+    }
+
+    /// Names the batch item at `index` in validation errors, so clients can
+    /// tell which one to fix.
+    pub(crate) fn in_batch_item(self, index: usize) -> Self {
+        match self {
+            Self::Validation(reason) => Self::InvalidBatchItem {
+                index,
+                field: None,
+                reason,
+            },
+            Self::InvalidField { field, reason } => Self::InvalidBatchItem {
+                index,
+                field: Some(field),
+                reason,
+            },
+            other => other,
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::InvalidBody
+            | Self::InvalidProtobuf(_)
+            | Self::InvalidJson(_)
+            | Self::Validation(_)
+            | Self::InvalidField { .. }
+            | Self::InvalidBatchItem { .. } => StatusCode::BAD_REQUEST,
+            Self::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::BodyTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::NotFound(_) | Self::RevisionNotFound { .. } => StatusCode::NOT_FOUND,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::VersionConflict { .. } => StatusCode::CONFLICT,
+            Self::Database(sqlx::Error::PoolTimedOut) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::DataCorruption(_) | Self::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn to_api_error(&self) -> pb::ApiError {
+        let (code, message) = match self {
+            Self::InvalidBody => ("invalid_body", self.to_string()),
+            Self::InvalidProtobuf(_) => ("invalid_protobuf", self.to_string()),
+            Self::InvalidJson(_) => ("invalid_json", self.to_string()),
+            Self::UnsupportedMediaType => ("unsupported_media_type", self.to_string()),
+            Self::BodyTooLarge { .. } => ("body_too_large", self.to_string()),
+            Self::NotFound(_) => ("not_found", self.to_string()),
+            Self::RevisionNotFound { .. } => ("revision_not_found", self.to_string()),
+            Self::Validation(_) | Self::InvalidField { .. } | Self::InvalidBatchItem { .. } => {
+                ("validation", self.to_string())
+            }
+            Self::Forbidden(_) => ("forbidden", self.to_string()),
+            Self::VersionConflict { .. } => ("version_conflict", self.to_string()),
+            Self::DataCorruption(_) => ("data_corruption", self.to_string()),
+            Self::Database(sqlx::Error::PoolTimedOut) => {
+                ("unavailable", "database is busy, retry later".to_owned())
+            }
+            Self::Database(_) => ("internal", "internal server error".to_owned()),
+        };
+        let details = match self {
+            Self::NotFound(id) | Self::DataCorruption(Some(id)) => {
+                HashMap::from([("id".to_owned(), id.to_string())])
+            }
+            Self::RevisionNotFound { note_id, version } => HashMap::from([
+                ("id".to_owned(), note_id.to_string()),
+                ("version".to_owned(), version.to_string()),
+            ]),
+            Self::VersionConflict {
+                note_id,
+                current_version,
+            } => HashMap::from([
+                ("id".to_owned(), note_id.to_string()),
+                ("current_version".to_owned(), current_version.to_string()),
+            ]),
+            Self::InvalidField { field, .. } => {
+                HashMap::from([("field".to_owned(), (*field).to_owned())])
+            }
+            Self::InvalidBatchItem { index, field, .. } => {
+                let mut details = HashMap::from([("index".to_owned(), index.to_string())]);
+                if let Some(field) = field {
+                    details.insert("field".to_owned(), (*field).to_owned());
+                }
+                details
+            }
+            Self::BodyTooLarge { limit } => {
+                HashMap::from([("limit".to_owned(), limit.to_string())])
+            }
+            _ => HashMap::new(),
+        };
+
+        pb::ApiError {
+            code: code.to_owned(),
+            message,
+            details,
+        }
+    }
 }
 
 impl IntoResponse for NotesError {
     fn into_response(self) -> Response {
-        let (status, message) = match self {
-            Self::InvalidBody | Self::InvalidProtobuf(_) | Self::Validation(_) => {
-                (StatusCode::BAD_REQUEST, self.to_string())
-            }
-            Self::NotFound(_) => (StatusCode::NOT_FOUND, self.to_string()),
-            Self::Database(_) => (
+        let mut response = (self.status(), Protobuf(self.to_api_error())).into_response();
+        if matches!(self, Self::Database(sqlx::Error::PoolTimedOut)) {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, POOL_TIMEOUT_RETRY_AFTER_SECS);
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::to_bytes;
+    use prost::Message;
+
+    use super::*;
+
+    fn decode_error() -> prost::DecodeError {
+        pb::ApiError::decode(&[0xff][..]).expect_err("a lone 0xff byte is not a valid message")
+    }
+
+    fn json_error() -> serde_json::Error {
+        serde_json::from_str::<pb::ApiError>("{").expect_err("`{` is not a valid JSON document")
+    }
+
+    async fn api_error(error: NotesError) -> (StatusCode, pb::ApiError) {
+        let response = error.into_response();
+        let status = response.status();
+        assert_eq!(
+            response
+                .headers()
+                .get("content-type")
+                .map(HeaderValue::as_bytes),
+            Some(&b"application/x-protobuf"[..])
+        );
+        let body = to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("error body should be readable");
+        (
+            status,
+            pb::ApiError::decode(body).expect("error body should be an ApiError"),
+        )
+    }
+
+    #[tokio::test]
+    async fn every_variant_answers_with_an_api_error() {
+        let cases = [
+            (
+                NotesError::InvalidBody,
+                StatusCode::BAD_REQUEST,
+                "invalid_body",
+            ),
+            (
+                NotesError::InvalidProtobuf(decode_error()),
+                StatusCode::BAD_REQUEST,
+                "invalid_protobuf",
+            ),
+            (
+                NotesError::InvalidJson(json_error()),
+                StatusCode::BAD_REQUEST,
+                "invalid_json",
+            ),
+            (
+                NotesError::UnsupportedMediaType,
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported_media_type",
+            ),
+            (
+                NotesError::BodyTooLarge { limit: 1024 },
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "body_too_large",
+            ),
+            (NotesError::NotFound(7), StatusCode::NOT_FOUND, "not_found"),
+            (
+                NotesError::RevisionNotFound {
+                    note_id: 7,
+                    version: 2,
+                },
+                StatusCode::NOT_FOUND,
+                "revision_not_found",
+            ),
+            (
+                NotesError::Validation("title cannot be empty"),
+                StatusCode::BAD_REQUEST,
+                "validation",
+            ),
+            (
+                NotesError::InvalidField {
+                    field: "title",
+                    reason: "title cannot be empty",
+                },
+                StatusCode::BAD_REQUEST,
+                "validation",
+            ),
+            (
+                NotesError::Validation("title cannot be empty").in_batch_item(3),
+                StatusCode::BAD_REQUEST,
+                "validation",
+            ),
+            (
+                NotesError::Forbidden("purging notes is disabled on this server"),
+                StatusCode::FORBIDDEN,
+                "forbidden",
+            ),
+            (
+                NotesError::VersionConflict {
+                    note_id: 7,
+                    current_version: 3,
+                },
+                StatusCode::CONFLICT,
+                "version_conflict",
+            ),
+            (
+                NotesError::DataCorruption(Some(7)),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "data_corruption",
+            ),
+            (
+                NotesError::Database(sqlx::Error::PoolTimedOut),
+                StatusCode::SERVICE_UNAVAILABLE,
+                "unavailable",
+            ),
+            (
+                NotesError::Database(sqlx::Error::RowNotFound),
                 StatusCode::INTERNAL_SERVER_ERROR,
-                "internal server error".to_owned(),
+                "internal",
             ),
+        ];
+
+        for (error, expected_status, expected_code) in cases {
+            let (status, body) = api_error(error).await;
+            assert_eq!(status, expected_status);
+            assert_eq!(body.code, expected_code);
+            assert!(!body.message.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn not_found_names_the_missing_id() {
+        let (_, body) = api_error(NotesError::NotFound(7)).await;
+        assert_eq!(body.message, "note 7 was not found");
+        assert_eq!(body.details.get("id").map(String::as_str), Some("7"));
+    }
+
+    #[tokio::test]
+    async fn field_validation_names_the_field() {
+        let invalid_title = || NotesError::InvalidField {
+            field: "title",
+            reason: "title cannot be empty",
+        };
+
+        let (_, body) = api_error(invalid_title()).await;
+        assert_eq!(body.message, "title cannot be empty");
+        assert_eq!(body.details.get("field").map(String::as_str), Some("title"));
+
+        let (_, body) = api_error(invalid_title().in_batch_item(2)).await;
+        assert_eq!(body.message, "item 2: title cannot be empty");
+        assert_eq!(body.details.get("index").map(String::as_str), Some("2"));
+        assert_eq!(body.details.get("field").map(String::as_str), Some("title"));
+    }
+
+    #[tokio::test]
+    async fn database_errors_do_not_leak_details() {
+        let (_, body) = api_error(NotesError::Database(sqlx::Error::RowNotFound)).await;
+        assert_eq!(body.message, "internal server error");
+        assert!(body.details.is_empty());
+    }
+
+    #[test]
+    fn undecodable_columns_become_data_corruption() {
+        let decode_failure = || sqlx::Error::ColumnDecode {
+            index: "\"title\"".to_owned(),
+            source: "invalid utf-8 sequence".into(),
         };
 
-        (status, message).into_response()
+        assert!(matches!(
+            NotesError::from(decode_failure()),
+            NotesError::DataCorruption(None)
+        ));
+        assert!(matches!(
+            NotesError::reading_note(7)(decode_failure()),
+            NotesError::DataCorruption(Some(7))
+        ));
+        assert!(matches!(
+            NotesError::reading_note(7)(sqlx::Error::PoolTimedOut),
+            NotesError::Database(sqlx::Error::PoolTimedOut)
+        ));
     }
 }
@@ -0,0 +1,600 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    sync::{
+        Arc, Mutex, MutexGuard, PoisonError,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use tokio::sync::{broadcast, watch};
+
+use crate::{NotesError, pb, relay::Relay};
+
+/// Wraps `event` into a `NoteEvent`, naming its kind.
+pub(crate) fn note_event(event: pb::note_event::Event, seq: u64) -> pb::NoteEvent {
+    let kind = match &event {
+        pb::note_event::Event::Created(_) => pb::NoteEventKind::Created,
+        pb::note_event::Event::Updated(_) => pb::NoteEventKind::Updated,
+        pb::note_event::Event::Deleted(_) => pb::NoteEventKind::Deleted,
+        pb::note_event::Event::Resync(_) => pb::NoteEventKind::Resync,
+        pb::note_event::Event::Reconnect(_) => pb::NoteEventKind::Reconnect,
+        pb::note_event::Event::Snapshot(_) => pb::NoteEventKind::Snapshot,
+        pb::note_event::Event::BulkChange(_) => pb::NoteEventKind::BulkChange,
+        pb::note_event::Event::Restored(_) => pb::NoteEventKind::Restored,
+    };
+
+    pb::NoteEvent {
+        event: Some(event),
+        seq,
+        kind: kind.into(),
+    }
+}
+
+fn parse_kind(value: &str) -> Option<pb::NoteEventKind> {
+    match value {
+        "created" => Some(pb::NoteEventKind::Created),
+        "updated" => Some(pb::NoteEventKind::Updated),
+        "deleted" => Some(pb::NoteEventKind::Deleted),
+        "restored" => Some(pb::NoteEventKind::Restored),
+        _ => None,
+    }
+}
+
+fn note_id_of(event: &pb::NoteEvent) -> Option<i64> {
+    match event.event.as_ref()? {
+        pb::note_event::Event::Created(note) | pb::note_event::Event::Restored(note) => {
+            Some(note.id)
+        }
+        pb::note_event::Event::Updated(delta) => Some(delta.id),
+        pb::note_event::Event::Deleted(deleted) => Some(deleted.id),
+        pb::note_event::Event::Resync(_)
+        | pb::note_event::Event::Reconnect(_)
+        | pb::note_event::Event::Snapshot(_)
+        | pb::note_event::Event::BulkChange(_) => None,
+    }
+}
+
+/// Which events a realtime subscriber asked to receive.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct EventFilter {
+    kinds: Option<HashSet<pb::NoteEventKind>>,
+    note_ids: Option<BTreeSet<i64>>,
+}
+
+impl EventFilter {
+    /// Parses a comma-separated `kinds` query value; `None` accepts every kind.
+    /// Non-empty `note_ids` restrict the stream to those notes.
+    pub(crate) fn parse(
+        kinds: Option<&str>,
+        note_ids: impl IntoIterator<Item = i64>,
+    ) -> Result<Self, NotesError> {
+        let kinds = kinds
+            .map(|kinds| {
+                kinds
+                    .split(',')
+                    .map(|kind| parse_kind(kind.trim()))
+                    .collect::<Option<HashSet<_>>>()
+                    .ok_or(NotesError::InvalidField { field: "kinds", reason: "kinds must be a comma-separated list of created, updated, deleted or restored" })
+            })
+            .transpose()?;
+
+        let note_ids: BTreeSet<_> = note_ids.into_iter().collect();
+
+        Ok(Self {
+            kinds,
+            note_ids: (!note_ids.is_empty()).then_some(note_ids),
+        })
+    }
+
+    /// The subscribed notes in ascending order; empty when every note is.
+    pub(crate) fn note_ids(&self) -> Vec<i64> {
+        self.note_ids.iter().flatten().copied().collect()
+    }
+
+    /// The subscribed kinds in ascending order; empty when every kind is.
+    fn kinds(&self) -> Vec<i32> {
+        let mut kinds: Vec<i32> = self
+            .kinds
+            .iter()
+            .flatten()
+            .map(|&kind| i32::from(kind))
+            .collect();
+        kinds.sort_unstable();
+        kinds
+    }
+
+    pub(crate) fn matches(&self, event: &pb::NoteEvent) -> bool {
+        // Control events such as `resync` are never filtered out.
+        let kind_matches = match event.kind() {
+            kind @ (pb::NoteEventKind::Created
+            | pb::NoteEventKind::Updated
+            | pb::NoteEventKind::Deleted
+            | pb::NoteEventKind::Restored) => self
+                .kinds
+                .as_ref()
+                .is_none_or(|kinds| kinds.contains(&kind)),
+            _ => true,
+        };
+        let note_matches = match (&self.note_ids, note_id_of(event)) {
+            (Some(wanted), Some(note_id)) => wanted.contains(&note_id),
+            (None, _) | (Some(_), None) => true,
+        };
+        kind_matches && note_matches
+    }
+}
+
+/// Counts realtime subscribers per note so a single hot note can be capped.
+#[derive(Default)]
+pub(crate) struct NoteSubscribers {
+    counts: Mutex<HashMap<i64, usize>>,
+}
+
+/// Holds one of a note's subscriber slots until dropped.
+pub(crate) struct NoteSubscriberSlot {
+    subscribers: Arc<NoteSubscribers>,
+    note_id: i64,
+}
+
+impl NoteSubscribers {
+    /// Claims a slot for `note_id`, or `None` when it already has `max` subscribers.
+    pub(crate) fn try_claim(
+        self: &Arc<Self>,
+        note_id: i64,
+        max: usize,
+    ) -> Option<NoteSubscriberSlot> {
+        let mut counts = lock(&self.counts);
+        let count = counts.entry(note_id).or_default();
+        if *count >= max {
+            if *count == 0 {
+                counts.remove(&note_id);
+            }
+            return None;
+        }
+        *count += 1;
+
+        Some(NoteSubscriberSlot {
+            subscribers: Arc::clone(self),
+            note_id,
+        })
+    }
+}
+
+impl Drop for NoteSubscriberSlot {
+    fn drop(&mut self) {
+        let mut counts = lock(&self.subscribers.counts);
+        if let Some(count) = counts.get_mut(&self.note_id) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.note_id);
+            }
+        }
+    }
+}
+
+/// Live realtime connections, kept so operators can list them through the
+/// admin `GET /ws-connections` endpoint.
+#[derive(Clone, Default)]
+pub struct WsConnections(Arc<ConnectionRegistry>);
+
+#[derive(Default)]
+struct ConnectionRegistry {
+    next_id: AtomicU64,
+    connections: Mutex<HashMap<u64, Arc<ConnectionStats>>>,
+    /// Set once every connection should close, e.g. while the server shuts down.
+    closing: watch::Sender<bool>,
+}
+
+struct ConnectionStats {
+    connected_at: i64,
+    note_ids: Vec<i64>,
+    kinds: Vec<i32>,
+    events_sent: AtomicU64,
+    lag_count: AtomicU64,
+    skipped_events: AtomicU64,
+}
+
+/// Keeps a connection listed, and its counters updated, until dropped.
+pub(crate) struct RegisteredConnection {
+    connections: WsConnections,
+    id: u64,
+    stats: Arc<ConnectionStats>,
+}
+
+impl WsConnections {
+    pub(crate) fn register(&self, filter: &EventFilter, now_unix_ms: i64) -> RegisteredConnection {
+        let id = self.0.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let stats = Arc::new(ConnectionStats {
+            connected_at: now_unix_ms,
+            note_ids: filter.note_ids(),
+            kinds: filter.kinds(),
+            events_sent: AtomicU64::new(0),
+            lag_count: AtomicU64::new(0),
+            skipped_events: AtomicU64::new(0),
+        });
+        lock(&self.0.connections).insert(id, Arc::clone(&stats));
+
+        RegisteredConnection {
+            connections: self.clone(),
+            id,
+            stats,
+        }
+    }
+
+    /// Every live connection, oldest first.
+    pub(crate) fn list(&self) -> Vec<pb::WsConnection> {
+        let mut connections: Vec<_> = lock(&self.0.connections)
+            .iter()
+            .map(|(&id, stats)| pb::WsConnection {
+                id,
+                connected_at_unix_ms: stats.connected_at,
+                note_id: match stats.note_ids[..] {
+                    [note_id] => Some(note_id),
+                    _ => None,
+                },
+                note_ids: stats.note_ids.clone(),
+                kinds: stats.kinds.clone(),
+                events_sent: stats.events_sent.load(Ordering::Relaxed),
+                lag_count: stats.lag_count.load(Ordering::Relaxed),
+                skipped_events: stats.skipped_events.load(Ordering::Relaxed),
+            })
+            .collect();
+        connections.sort_unstable_by_key(|connection| connection.id);
+        connections
+    }
+
+    /// Asks every live connection, and any opened afterwards, to close with
+    /// `1001 Going Away` so a graceful shutdown is not held up by subscribers.
+    pub fn close_all(&self) {
+        self.0.closing.send_replace(true);
+    }
+}
+
+impl RegisteredConnection {
+    /// Resolves once [`WsConnections::close_all`] was called, immediately if it
+    /// already was.
+    pub(crate) async fn closing(&self) {
+        let mut closing = self.connections.0.closing.subscribe();
+        // The sender lives as long as this connection's registry, so this only
+        // returns once closing is set.
+        let _ignored = closing.wait_for(|closing| *closing).await;
+    }
+
+    pub(crate) fn record_sent(&self) {
+        self.stats.events_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_lag(&self, skipped_events: u64) {
+        self.stats.lag_count.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .skipped_events
+            .fetch_add(skipped_events, Ordering::Relaxed);
+    }
+}
+
+impl Drop for RegisteredConnection {
+    fn drop(&mut self) {
+        lock(&self.connections.0.connections).remove(&self.id);
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // The guarded data stays consistent even if a holder panicked.
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Fans note events out to live subscribers and keeps the most recent ones so
+/// a subscriber that briefly dropped can resume with `?since=<seq>`.
+pub(crate) struct EventHub {
+    tx: broadcast::Sender<PublishedEvent>,
+    capacity: usize,
+    recent: Mutex<RecentEvents>,
+    relay: Option<Relay>,
+}
+
+struct RecentEvents {
+    next_seq: u64,
+    events: VecDeque<PublishedEvent>,
+    capacity: usize,
+}
+
+/// An event along with the owner of the notes it is about; only subscribers
+/// acting for that owner receive it, unless it is meant for every owner.
+#[derive(Debug, Clone)]
+pub(crate) struct PublishedEvent {
+    pub(crate) owner_id: Option<String>,
+    pub(crate) every_owner: bool,
+    pub(crate) event: pb::NoteEvent,
+}
+
+impl PublishedEvent {
+    /// Whether subscribers acting for `owner_id` receive the event.
+    pub(crate) fn is_for(&self, owner_id: Option<&str>) -> bool {
+        self.every_owner || self.owner_id.as_deref() == owner_id
+    }
+}
+
+/// A live receiver plus whatever has to be sent before it.
+pub(crate) struct Subscription {
+    pub(crate) rx: broadcast::Receiver<PublishedEvent>,
+    pub(crate) backlog: Vec<pb::NoteEvent>,
+    /// Newest `seq` published before the receiver was attached.
+    pub(crate) latest_seq: u64,
+    /// Owner whose events the receiver should forward; others are skipped.
+    pub(crate) owner_id: Option<String>,
+}
+
+impl EventHub {
+    /// Keeps `buffer_size` events for resumption, and lets live subscribers
+    /// fall up to `capacity` events behind before they lag.
+    pub(crate) fn new(buffer_size: usize, capacity: usize) -> Self {
+        Self::with_relay(buffer_size, capacity, None)
+    }
+
+    pub(crate) fn with_relay(buffer_size: usize, capacity: usize, relay: Option<Relay>) -> Self {
+        let capacity = capacity.max(1);
+        let (tx, _) = broadcast::channel(capacity);
+        Self {
+            tx,
+            capacity,
+            recent: Mutex::new(RecentEvents {
+                next_seq: 1,
+                events: VecDeque::with_capacity(buffer_size),
+                capacity: buffer_size,
+            }),
+            relay,
+        }
+    }
+
+    /// Publishes `event` about notes owned by `owner_id`, relaying it to the
+    /// other instances when configured to.
+    pub(crate) fn publish(&self, owner_id: Option<&str>, event: pb::note_event::Event) {
+        let relayed = self
+            .relay
+            .as_ref()
+            .is_none_or(|relay| relay.send(owner_id, &event));
+        self.publish_relayed(owner_id, event);
+        if !relayed {
+            self.publish_resync();
+        }
+    }
+
+    /// Publishes `event` to this instance's subscribers only, as for events
+    /// relayed from another instance.
+    pub(crate) fn publish_relayed(&self, owner_id: Option<&str>, event: pb::note_event::Event) {
+        let mut recent = self.lock_recent();
+        let event = PublishedEvent {
+            owner_id: owner_id.map(str::to_owned),
+            every_owner: false,
+            event: note_event(event, recent.next_seq),
+        };
+        self.push(&mut recent, event);
+    }
+
+    /// Tells this instance's subscribers, whoever they act for, to refetch
+    /// their notes because relayed events were lost.
+    pub(crate) fn publish_resync(&self) {
+        let mut recent = self.lock_recent();
+        let latest_seq = recent.next_seq;
+        let event = PublishedEvent {
+            owner_id: None,
+            every_owner: true,
+            event: note_event(
+                pb::note_event::Event::Resync(pb::Resync { latest_seq }),
+                latest_seq,
+            ),
+        };
+        self.push(&mut recent, event);
+    }
+
+    fn push(&self, recent: &mut RecentEvents, event: PublishedEvent) {
+        recent.next_seq += 1;
+        if recent.capacity > 0 {
+            if recent.events.len() == recent.capacity {
+                recent.events.pop_front();
+            }
+            recent.events.push_back(event.clone());
+        }
+
+        // Sending under the lock keeps the buffer and the live stream in the same order.
+        if self.tx.send(event).is_err() {
+            // No active realtime subscribers is expected and not a server error.
+        }
+    }
+
+    /// Subscribes to live events about `owner_id`'s notes, first replaying
+    /// their buffered events after `since`. When `since` is no longer buffered
+    /// the backlog is a single `Resync`.
+    pub(crate) fn subscribe(&self, since: Option<u64>, owner_id: Option<String>) -> Subscription {
+        let recent = self.lock_recent();
+        let rx = self.tx.subscribe();
+        let latest_seq = recent.next_seq - 1;
+        let backlog = match since {
+            None => Vec::new(),
+            Some(since) => recent
+                .replay_after(since, owner_id.as_deref())
+                .unwrap_or_else(|| {
+                    vec![note_event(
+                        pb::note_event::Event::Resync(pb::Resync { latest_seq }),
+                        0,
+                    )]
+                }),
+        };
+
+        Subscription {
+            rx,
+            backlog,
+            latest_seq,
+            owner_id,
+        }
+    }
+
+    /// How full the live stream is: events the slowest subscriber has yet to
+    /// receive. It lags once this reaches the channel's capacity.
+    pub(crate) fn health(&self, degraded_percent: usize) -> pb::NotesHealth {
+        let queued = self.tx.len();
+        pb::NotesHealth {
+            broadcast_queued: queued as u64,
+            broadcast_capacity: self.capacity as u64,
+            degraded: queued.saturating_mul(100) >= self.capacity.saturating_mul(degraded_percent),
+        }
+    }
+
+    fn lock_recent(&self) -> MutexGuard<'_, RecentEvents> {
+        lock(&self.recent)
+    }
+}
+
+impl RecentEvents {
+    fn replay_after(&self, since: u64, owner_id: Option<&str>) -> Option<Vec<pb::NoteEvent>> {
+        let first_needed = since.checked_add(1)?;
+        if first_needed > self.next_seq {
+            return None;
+        }
+        if first_needed < self.next_seq
+            && self
+                .events
+                .front()
+                .is_none_or(|oldest| oldest.event.seq > first_needed)
+        {
+            return None;
+        }
+
+        Some(
+            self.events
+                .iter()
+                .filter(|published| {
+                    published.event.seq >= first_needed && published.is_for(owner_id)
+                })
+                .map(|published| published.event.clone())
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deleted(id: i64) -> pb::note_event::Event {
+        pb::note_event::Event::Deleted(pb::NoteDeleted { id })
+    }
+
+    fn seqs(events: &[pb::NoteEvent]) -> Vec<u64> {
+        events.iter().map(|event| event.seq).collect()
+    }
+
+    fn is_resync(events: &[pb::NoteEvent]) -> bool {
+        matches!(
+            events,
+            [pb::NoteEvent {
+                event: Some(pb::note_event::Event::Resync(_)),
+                ..
+            }]
+        )
+    }
+
+    #[test]
+    fn since_within_buffer_replays_missed_events() {
+        let hub = EventHub::new(4, 512);
+        for id in 1..=3 {
+            hub.publish(None, deleted(id));
+        }
+
+        assert_eq!(seqs(&hub.subscribe(Some(1), None).backlog), vec![2, 3]);
+        assert!(hub.subscribe(Some(3), None).backlog.is_empty());
+    }
+
+    #[test]
+    fn since_evicted_from_buffer_requests_resync() {
+        let hub = EventHub::new(2, 512);
+        for id in 1..=5 {
+            hub.publish(None, deleted(id));
+        }
+
+        assert_eq!(seqs(&hub.subscribe(Some(3), None).backlog), vec![4, 5]);
+        assert!(is_resync(&hub.subscribe(Some(2), None).backlog));
+    }
+
+    #[test]
+    fn since_ahead_of_latest_requests_resync() {
+        let hub = EventHub::new(2, 512);
+        hub.publish(None, deleted(1));
+
+        assert!(is_resync(&hub.subscribe(Some(7), None).backlog));
+    }
+
+    #[test]
+    fn replay_skips_other_owners_events() {
+        let hub = EventHub::new(4, 512);
+        hub.publish(Some("alice"), deleted(1));
+        hub.publish(Some("bob"), deleted(2));
+        hub.publish(None, deleted(3));
+        hub.publish(Some("alice"), deleted(4));
+
+        let replayed = hub.subscribe(Some(0), Some("alice".to_owned())).backlog;
+        assert_eq!(seqs(&replayed), vec![1, 4]);
+        assert_eq!(seqs(&hub.subscribe(Some(0), None).backlog), vec![3]);
+    }
+
+    #[test]
+    fn health_degrades_as_unread_events_pile_up() {
+        let hub = EventHub::new(0, 512);
+        let _idle = hub.subscribe(None, None);
+        assert!(!hub.health(50).degraded);
+
+        for id in 0..256 {
+            hub.publish(None, deleted(id));
+        }
+        let health = hub.health(50);
+        assert_eq!(health.broadcast_queued, 256);
+        assert_eq!(health.broadcast_capacity, 512);
+        assert!(health.degraded);
+        assert!(!hub.health(51).degraded);
+    }
+
+    #[test]
+    fn note_subscriber_slots_are_capped_and_released() {
+        let subscribers = Arc::new(NoteSubscribers::default());
+        let first = subscribers.try_claim(7, 2).expect("first slot");
+        let _second = subscribers.try_claim(7, 2).expect("second slot");
+        assert!(subscribers.try_claim(7, 2).is_none());
+        assert!(subscribers.try_claim(8, 2).is_some());
+
+        drop(first);
+        assert!(subscribers.try_claim(7, 2).is_some());
+    }
+
+    #[test]
+    fn ws_connections_are_listed_until_dropped() {
+        let connections = WsConnections::default();
+        let filter = EventFilter::parse(Some("deleted,created"), [7]).expect("valid filter");
+        let first = connections.register(&filter, 1_000);
+        let second = connections.register(&EventFilter::parse(None, []).expect("valid"), 2_000);
+        first.record_sent();
+        first.record_lag(12);
+
+        let listed = connections.list();
+        assert_eq!(listed.len(), 2);
+        assert_eq!(
+            listed[0],
+            pb::WsConnection {
+                id: first.id,
+                connected_at_unix_ms: 1_000,
+                note_id: Some(7),
+                note_ids: vec![7],
+                kinds: vec![
+                    pb::NoteEventKind::Created.into(),
+                    pb::NoteEventKind::Deleted.into()
+                ],
+                events_sent: 1,
+                lag_count: 1,
+                skipped_events: 12,
+            }
+        );
+
+        drop(first);
+        let listed = connections.list();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].id, second.id);
+    }
+}
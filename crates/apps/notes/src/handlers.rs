@@ -1,244 +1,1885 @@
+use std::{collections::BTreeSet, sync::Arc, time::Duration};
+
 use axum::{
-    Router,
+    Extension, Router,
     extract::{
-        Path, State, WebSocketUpgrade,
-        ws::{Message, WebSocket},
+        Path, Query, RawQuery, State, WebSocketUpgrade,
+        ws::{CloseFrame, Message, WebSocket, close_code},
     },
-    response::IntoResponse,
-    routing::{get, post},
+    http::{StatusCode, header},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
 };
 use bytes::Bytes;
 use prost::Message as ProstMessage;
-use sqlx::PgPool;
-use tokio::sync::broadcast;
+use protobuf_http::{JsonByDefault, MaxBodyBytes, negotiate_json};
+use serde::Deserialize;
+use sqlx::{PgConnection, PgExecutor, PgPool};
+use tokio::{sync::broadcast, time};
 use tracing::warn;
 
 use crate::{
-    NotesError, Protobuf, pb,
-    state::{NoteRow, NotesState, build_state, emit_event, now_unix_millis},
+    NotesConfig, NotesError, Protobuf, ProtobufResponse, SignificantFields, WsConnections,
+    actor::Actor,
+    events::{EventFilter, RegisteredConnection, Subscription, note_event},
+    owner::Owner,
+    pb,
+    reconnect::ReconnectTokens,
+    state::{NoteLimits, NoteRow, NotesState, build_state, now_unix_millis},
 };
 
 pub fn create_handlers(pool: PgPool) -> Router {
-    let state = build_state(pool);
+    create_handlers_with_config(pool, NotesConfig::default())
+}
+
+pub fn create_handlers_with_config(pool: PgPool, config: NotesConfig) -> Router {
+    create_handlers_with_connections(pool, config, WsConnections::default())
+}
+
+/// Like [`create_handlers_with_config`], registering realtime connections in
+/// `ws_connections` so they can be listed by [`create_admin_handlers`].
+pub fn create_handlers_with_connections(
+    pool: PgPool,
+    config: NotesConfig,
+    ws_connections: WsConnections,
+) -> Router {
+    let json_by_default = JsonByDefault(config.json_by_default);
+    let max_body_bytes = MaxBodyBytes(config.max_body_bytes);
+    let max_batch_body_bytes = MaxBodyBytes(config.max_batch_body_bytes);
+    let state = build_state(pool, config, ws_connections);
 
     Router::new()
         .route("/", post(create_note).get(list_notes))
+        .route("/due", get(list_due_notes))
+        .route("/search", get(search_notes))
+        .route("/versions", get(list_note_versions))
+        .route("/trash", get(list_trashed_notes))
+        .route("/tags", get(list_tags))
+        .route("/health", get(notes_health))
+        .route(
+            "/batch",
+            post(batch_create_notes).layer(Extension(max_batch_body_bytes)),
+        )
+        .route("/batch-get", post(batch_get_notes))
+        .route("/sync-push", post(sync_push))
         .route(
             "/{note_id}",
             get(get_note).patch(update_note).delete(delete_note),
         )
+        .route("/{note_id}/children", get(list_child_notes))
+        .route("/{note_id}/revisions", get(list_note_revisions))
+        .route(
+            "/{note_id}/revisions/{version}/restore",
+            post(restore_note_revision),
+        )
+        .route("/{note_id}/restore", post(restore_note))
+        .route("/{note_id}/purge", delete(purge_note))
         .route("/events", get(subscribe_note_events))
+        .layer(Extension(max_body_bytes))
+        .layer(middleware::from_fn_with_state(
+            json_by_default,
+            negotiate_json,
+        ))
         .with_state(state)
 }
 
+/// Operator endpoints: `GET /ws-connections` lists live realtime connections.
+/// Unauthenticated; the embedding server must guard them.
+pub fn create_admin_handlers(ws_connections: WsConnections) -> Router {
+    Router::new()
+        .route("/ws-connections", get(list_ws_connections))
+        .layer(middleware::from_fn_with_state(
+            JsonByDefault(false),
+            negotiate_json,
+        ))
+        .with_state(ws_connections)
+}
+
+/// Answers `503` while realtime subscribers are about to start lagging.
+async fn notes_health(State(state): State<NotesState>) -> impl IntoResponse {
+    let health = state.events.health(state.broadcast_degraded_percent);
+    let status = if health.degraded {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+    (status, Protobuf(health))
+}
+
+async fn list_ws_connections(
+    State(ws_connections): State<WsConnections>,
+) -> Protobuf<pb::ListWsConnectionsResponse> {
+    Protobuf(pb::ListWsConnectionsResponse {
+        connections: ws_connections.list(),
+    })
+}
+
 async fn create_note(
     State(state): State<NotesState>,
+    Owner(owner): Owner,
+    Actor(actor): Actor,
     Protobuf(payload): Protobuf<pb::CreateNoteRequest>,
 ) -> Result<Protobuf<pb::CreateNoteResponse>, NotesError> {
-    let title = payload.title.trim();
-    if title.is_empty() {
-        return Err(NotesError::Validation("title cannot be empty"));
+    let new_note = NewNote::validate(payload, state.note_limits)?;
+
+    let mut tx = state.pool.begin().await?;
+    let row = insert_note(&mut tx, new_note, owner.as_deref(), actor.as_deref()).await?;
+    tx.commit().await?;
+
+    let note = pb::Note::from(row);
+    state.events.publish(
+        owner.as_deref(),
+        pb::note_event::Event::Created(note.clone()),
+    );
+
+    Ok(Protobuf(pb::CreateNoteResponse { note: Some(note) }))
+}
+
+/// Most notes a single batch create may carry.
+const MAX_BATCH_CREATE_NOTES: usize = 500;
+
+/// Creates every note in one transaction, or none when any is invalid.
+async fn batch_create_notes(
+    State(state): State<NotesState>,
+    Owner(owner): Owner,
+    Actor(actor): Actor,
+    Protobuf(payload): Protobuf<pb::BatchCreateNotesRequest>,
+) -> Result<Protobuf<pb::BatchCreateNotesResponse>, NotesError> {
+    if payload.notes.len() > MAX_BATCH_CREATE_NOTES {
+        return Err(NotesError::InvalidField {
+            field: "notes",
+            reason: "a batch create supports at most 500 notes",
+        });
     }
+    let new_notes = payload
+        .notes
+        .into_iter()
+        .enumerate()
+        .map(|(index, note)| {
+            NewNote::validate(note, state.note_limits).map_err(|error| error.in_batch_item(index))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
 
-    let now = now_unix_millis();
-    let row = sqlx::query_as!(
+    let mut tx = state.pool.begin().await?;
+    let mut notes = Vec::with_capacity(new_notes.len());
+    for (index, new_note) in new_notes.into_iter().enumerate() {
+        let row = insert_note(&mut tx, new_note, owner.as_deref(), actor.as_deref())
+            .await
+            .map_err(|error| error.in_batch_item(index))?;
+        notes.push(pb::Note::from(row));
+    }
+    tx.commit().await?;
+
+    for note in &notes {
+        state.events.publish(
+            owner.as_deref(),
+            pb::note_event::Event::Created(note.clone()),
+        );
+    }
+
+    Ok(Protobuf(pb::BatchCreateNotesResponse { notes }))
+}
+
+/// A `CreateNoteRequest` that passed the checks not needing the database.
+struct NewNote {
+    title: String,
+    body: String,
+    due_at: Option<i64>,
+    parent_id: Option<i64>,
+    tags: Vec<String>,
+}
+
+impl NewNote {
+    fn validate(payload: pb::CreateNoteRequest, limits: NoteLimits) -> Result<Self, NotesError> {
+        let title = payload.title.trim();
+        if title.is_empty() {
+            return Err(NotesError::InvalidField {
+                field: "title",
+                reason: "title cannot be empty",
+            });
+        }
+        limits.check_title(title)?;
+        limits.check_body(&payload.body)?;
+        validate_due_at(payload.due_at_unix_ms)?;
+
+        Ok(Self {
+            title: title.to_owned(),
+            body: payload.body,
+            due_at: payload.due_at_unix_ms,
+            parent_id: payload.parent_id,
+            tags: normalize_tags(payload.tags)?,
+        })
+    }
+}
+
+async fn insert_note(
+    conn: &mut PgConnection,
+    note: NewNote,
+    owner: Option<&str>,
+    actor: Option<&str>,
+) -> Result<NoteRow, NotesError> {
+    if let Some(parent_id) = note.parent_id {
+        validate_parent(&mut *conn, owner, None, parent_id).await?;
+    }
+
+    let mut row = sqlx::query_as!(
         NoteRow,
         r#"
-        INSERT INTO notes (title, body, created_at, updated_at, version)
-        VALUES ($1, $2, $3, $3, 1)
-        RETURNING id, title, body, created_at, updated_at, version
+        INSERT INTO notes (title, body, created_at, updated_at, version, due_at, parent_id,
+            updated_by, owner_id)
+        VALUES ($1, $2, $3, $3, 1, $4, $5, $6, $7)
+        RETURNING id, title, body, created_at, updated_at, version, due_at, parent_id, updated_by,
+            '{}'::TEXT[] AS "tags!"
         "#,
-        title,
-        payload.body,
-        now
+        note.title,
+        note.body,
+        now_unix_millis(),
+        note.due_at,
+        note.parent_id,
+        actor,
+        owner
     )
-    .fetch_one(&state.pool)
+    .fetch_one(&mut *conn)
     .await?;
+    save_tags(conn, row.id, &note.tags).await?;
+    row.tags = note.tags;
 
-    let note = pb::Note::from(row);
-    emit_event(
-        &state.events_tx,
-        pb::NoteEvent {
-            event: Some(pb::note_event::Event::Created(note.clone())),
-        },
-    );
+    Ok(row)
+}
 
-    Ok(Protobuf(pb::CreateNoteResponse { note: Some(note) }))
+/// Orders accepted by `GET /notes?sort=`. Each one breaks ties by id, so the
+/// `after_id` cursor resumes right after the cursor note's position. Editing a
+/// note moves it in `updated_desc`, which therefore cannot be paged.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum ListSort {
+    #[default]
+    IdAsc,
+    UpdatedDesc,
+    CreatedAsc,
+    TitleAsc,
+}
+
+impl ListSort {
+    fn parse(sort: Option<&str>) -> Result<Self, NotesError> {
+        match sort.map(str::trim) {
+            None | Some("" | "id_asc") => Ok(Self::IdAsc),
+            Some("updated_desc") => Ok(Self::UpdatedDesc),
+            Some("created_asc") => Ok(Self::CreatedAsc),
+            Some("title_asc") => Ok(Self::TitleAsc),
+            Some(_) => Err(NotesError::InvalidField {
+                field: "sort",
+                reason: "sort must be one of id_asc, updated_desc, created_asc or title_asc",
+            }),
+        }
+    }
+
+    /// The `GET /notes` query in this order, as `list_page` runs it, for
+    /// explaining. `$2` and `$3` are the optional inclusive `min_id` and
+    /// `max_id` bounds, `$4` the owner, `$5` an optional tag every listed note
+    /// carries, `$6` the optional `after_id` cursor and `$7` whether to return
+    /// bodies. Without them, large out-of-line bodies are neither detoasted
+    /// nor sent over the wire.
+    fn sql(self) -> &'static str {
+        match self {
+            Self::IdAsc => include_str!("../queries/list_notes_by_id.sql"),
+            Self::UpdatedDesc => include_str!("../queries/list_notes_by_updated.sql"),
+            Self::CreatedAsc => include_str!("../queries/list_notes_by_created.sql"),
+            Self::TitleAsc => include_str!("../queries/list_notes_by_title.sql"),
+        }
+    }
+}
+
+/// Page size of `GET /notes` without a `limit`.
+const DEFAULT_LIST_PAGE_SIZE: usize = 50;
+/// Largest `limit` honored by `GET /notes`; larger values are capped.
+const MAX_LIST_PAGE_SIZE: usize = 200;
+
+#[derive(Debug, Default, Deserialize)]
+struct ListNotesQuery {
+    #[serde(default)]
+    explain: bool,
+    /// Page size, defaulting to 50 and capped at 200 (and at `max_list_rows`).
+    limit: Option<usize>,
+    /// Returns notes after this id; pass the previous page's `next_cursor`.
+    after_id: Option<i64>,
+    /// Returns every note with an empty `body`, e.g. for a titles-only view.
+    #[serde(default)]
+    omit_body: bool,
+    /// Inclusive id bounds, so export jobs can split the table into disjoint ranges.
+    min_id: Option<i64>,
+    max_id: Option<i64>,
+    /// Only lists notes carrying this tag.
+    tag: Option<String>,
+    /// `id_asc` (the default), `updated_desc`, `created_asc` or `title_asc`.
+    sort: Option<String>,
 }
 
 async fn list_notes(
+    Query(query): Query<ListNotesQuery>,
+    State(state): State<NotesState>,
+    Owner(owner): Owner,
+) -> Result<Response, NotesError> {
+    if let (Some(min_id), Some(max_id)) = (query.min_id, query.max_id)
+        && min_id > max_id
+    {
+        return Err(NotesError::InvalidField {
+            field: "min_id",
+            reason: "min_id must not be greater than max_id",
+        });
+    }
+    if query.limit == Some(0) {
+        return Err(NotesError::InvalidField {
+            field: "limit",
+            reason: "limit must be positive",
+        });
+    }
+    let sort = ListSort::parse(query.sort.as_deref())?;
+    if sort == ListSort::UpdatedDesc && query.after_id.is_some() {
+        return Err(NotesError::InvalidField {
+            field: "after_id",
+            reason: "sort=updated_desc cannot be paged with after_id",
+        });
+    }
+    let page_size = query
+        .limit
+        .unwrap_or(DEFAULT_LIST_PAGE_SIZE)
+        .min(MAX_LIST_PAGE_SIZE)
+        .min(state.max_list_rows);
+    let tag = query.tag.as_deref().map(str::trim);
+
+    let page = ListPage {
+        limit: list_limit(page_size),
+        min_id: query.min_id,
+        max_id: query.max_id,
+        owner: owner.as_deref(),
+        tag,
+        after_id: query.after_id,
+        with_body: !query.omit_body,
+    };
+    if query.explain {
+        ensure_explain_allowed(&state)?;
+        let plan = sqlx::query_scalar(&explain_sql(sort.sql()))
+            .bind(page.limit)
+            .bind(page.min_id)
+            .bind(page.max_id)
+            .bind(page.owner)
+            .bind(page.tag)
+            .bind(page.after_id)
+            .bind(page.with_body)
+            .fetch_all(&state.pool)
+            .await?;
+        return Ok(plan_response(&plan));
+    }
+
+    let mut rows = list_page(&state.pool, sort, &page).await?;
+    let truncated = rows.len() > page_size;
+    rows.truncate(page_size);
+    let next_cursor = match rows.last() {
+        Some(row) if truncated && sort != ListSort::UpdatedDesc => row.id,
+        _ => 0,
+    };
+
+    Ok(Protobuf(pb::ListNotesResponse {
+        notes: rows.into_iter().map(pb::Note::from).collect(),
+        truncated,
+        next_cursor,
+    })
+    .into_response())
+}
+
+/// The parameters of a `GET /notes` query; see [`ListSort::sql`].
+struct ListPage<'a> {
+    limit: i64,
+    min_id: Option<i64>,
+    max_id: Option<i64>,
+    owner: Option<&'a str>,
+    tag: Option<&'a str>,
+    after_id: Option<i64>,
+    with_body: bool,
+}
+
+async fn list_page(
+    pool: &PgPool,
+    sort: ListSort,
+    page: &ListPage<'_>,
+) -> Result<Vec<NoteRow>, NotesError> {
+    // One checked query per order; `ListSort::sql` names the same files.
+    macro_rules! fetch {
+        ($file:literal) => {
+            sqlx::query_file_as!(
+                NoteRow,
+                $file,
+                page.limit,
+                page.min_id,
+                page.max_id,
+                page.owner,
+                page.tag,
+                page.after_id,
+                page.with_body
+            )
+            .fetch_all(pool)
+            .await?
+        };
+    }
+    Ok(match sort {
+        ListSort::IdAsc => fetch!("queries/list_notes_by_id.sql"),
+        ListSort::UpdatedDesc => fetch!("queries/list_notes_by_updated.sql"),
+        ListSort::CreatedAsc => fetch!("queries/list_notes_by_created.sql"),
+        ListSort::TitleAsc => fetch!("queries/list_notes_by_title.sql"),
+    })
+}
+
+/// Asks for one row past the page so a last page can be told apart from a truncated one.
+fn list_limit(page_size: usize) -> i64 {
+    i64::try_from(page_size)
+        .unwrap_or(i64::MAX)
+        .saturating_add(1)
+}
+
+fn ensure_explain_allowed(state: &NotesState) -> Result<(), NotesError> {
+    if state.allow_explain {
+        Ok(())
+    } else {
+        Err(NotesError::Validation(
+            "query plans are disabled on this server",
+        ))
+    }
+}
+
+fn explain_sql(sql: &str) -> String {
+    format!("EXPLAIN (ANALYZE, FORMAT TEXT) {sql}")
+}
+
+fn plan_response(plan: &[String]) -> Response {
+    (
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        plan.join("\n"),
+    )
+        .into_response()
+}
+
+/// Most ids a single batch-get may ask for.
+const MAX_BATCH_GET_IDS: usize = 500;
+
+async fn batch_get_notes(
+    State(state): State<NotesState>,
+    Owner(owner): Owner,
+    Protobuf(payload): Protobuf<pb::BatchGetNotesRequest>,
+) -> Result<Protobuf<pb::BatchGetNotesResponse>, NotesError> {
+    if payload.ids.len() > MAX_BATCH_GET_IDS {
+        return Err(NotesError::InvalidField {
+            field: "ids",
+            reason: "a batch get supports at most 500 ids",
+        });
+    }
+
+    let rows = sqlx::query_as!(
+        NoteRow,
+        r#"
+        SELECT notes.id, title, body, created_at, updated_at, version, due_at, parent_id, updated_by,
+            ARRAY(SELECT tag FROM note_tags WHERE note_id = notes.id ORDER BY tag) AS "tags!"
+        FROM UNNEST($1::BIGINT[]) WITH ORDINALITY AS requested(id, position)
+        JOIN notes ON notes.id = requested.id AND notes.deleted_at IS NULL
+            AND notes.owner_id IS NOT DISTINCT FROM $2
+        ORDER BY requested.position
+        "#,
+        &payload.ids,
+        owner
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    let notes = rows.into_iter().map(pb::Note::from);
+    let response = if payload.as_map {
+        pb::BatchGetNotesResponse {
+            notes_by_id: notes.map(|note| (note.id, note)).collect(),
+            ..Default::default()
+        }
+    } else {
+        pb::BatchGetNotesResponse {
+            notes: notes.collect(),
+            ..Default::default()
+        }
+    };
+
+    Ok(Protobuf(response))
+}
+
+#[derive(Debug, Deserialize)]
+struct DueNotesQuery {
+    before_ms: i64,
+}
+
+async fn list_due_notes(
+    Query(query): Query<DueNotesQuery>,
     State(state): State<NotesState>,
+    Owner(owner): Owner,
 ) -> Result<Protobuf<pb::ListNotesResponse>, NotesError> {
+    validate_due_at(Some(query.before_ms))?;
+
     let rows = sqlx::query_as!(
         NoteRow,
         r#"
-        SELECT id, title, body, created_at, updated_at, version
+        SELECT id, title, body, created_at, updated_at, version, due_at, parent_id, updated_by,
+            ARRAY(SELECT tag FROM note_tags WHERE note_id = notes.id ORDER BY tag) AS "tags!"
         FROM notes
-        ORDER BY id
+        WHERE due_at IS NOT NULL AND due_at < $1 AND deleted_at IS NULL
+            AND owner_id IS NOT DISTINCT FROM $2
+        ORDER BY due_at, id
         "#,
+        query.before_ms,
+        owner
     )
     .fetch_all(&state.pool)
     .await?;
 
     Ok(Protobuf(pb::ListNotesResponse {
         notes: rows.into_iter().map(pb::Note::from).collect(),
+        ..Default::default()
+    }))
+}
+
+async fn list_note_versions(
+    State(state): State<NotesState>,
+    Owner(owner): Owner,
+) -> Result<Protobuf<pb::ListNoteVersionsResponse>, NotesError> {
+    let versions = sqlx::query_as!(
+        pb::NoteVersion,
+        r#"
+        SELECT id, version
+        FROM notes
+        WHERE deleted_at IS NULL AND owner_id IS NOT DISTINCT FROM $1
+        ORDER BY id
+        "#,
+        owner
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    Ok(Protobuf(pb::ListNoteVersionsResponse { versions }))
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchNotesQuery {
+    q: String,
+}
+
+/// Full-text search over title and body, ranked by relevance and capped at
+/// `max_list_rows` results.
+async fn search_notes(
+    Query(query): Query<SearchNotesQuery>,
+    State(state): State<NotesState>,
+    Owner(owner): Owner,
+) -> Result<Protobuf<pb::SearchNotesResponse>, NotesError> {
+    let terms = query.q.trim();
+    if terms.is_empty() {
+        return Err(NotesError::InvalidField {
+            field: "q",
+            reason: "search query cannot be empty",
+        });
+    }
+
+    // The document expression must match `idx_notes_search` for the index to be used.
+    let rows = sqlx::query_as!(
+        NoteRow,
+        r#"
+        SELECT id, title, body, created_at, updated_at, version, due_at, parent_id, updated_by,
+            ARRAY(SELECT tag FROM note_tags WHERE note_id = notes.id ORDER BY tag) AS "tags!"
+        FROM notes, plainto_tsquery('english', $1) AS query
+        WHERE to_tsvector('english', title || ' ' || body) @@ query AND deleted_at IS NULL
+            AND owner_id IS NOT DISTINCT FROM $3
+        ORDER BY ts_rank(to_tsvector('english', title || ' ' || body), query) DESC, id
+        LIMIT $2
+        "#,
+        terms,
+        i64::try_from(state.max_list_rows).unwrap_or(i64::MAX),
+        owner
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    Ok(Protobuf(pb::SearchNotesResponse {
+        notes: rows.into_iter().map(pb::Note::from).collect(),
     }))
 }
 
+/// Answers `304 Not Modified` when `If-None-Match` names the note as last
+/// written, in the negotiated format. Notes of other owners are reported as
+/// missing, so their ids leak nothing.
 async fn get_note(
     Path(note_id): Path<i64>,
     State(state): State<NotesState>,
-) -> Result<Protobuf<pb::GetNoteResponse>, NotesError> {
+    Owner(owner): Owner,
+) -> Result<ProtobufResponse<pb::GetNoteResponse>, NotesError> {
     let row = sqlx::query_as!(
         NoteRow,
         r#"
-        SELECT id, title, body, created_at, updated_at, version
+        SELECT id, title, body, created_at, updated_at, version, due_at, parent_id, updated_by,
+            ARRAY(SELECT tag FROM note_tags WHERE note_id = notes.id ORDER BY tag) AS "tags!"
         FROM notes
-        WHERE id = $1
+        WHERE id = $1 AND deleted_at IS NULL AND owner_id IS NOT DISTINCT FROM $2
         "#,
-        note_id
+        note_id,
+        owner
     )
     .fetch_optional(&state.pool)
-    .await?;
+    .await
+    .map_err(NotesError::reading_note(note_id))?;
 
     let note = row.ok_or(NotesError::NotFound(note_id))?;
+    // Quiet updates keep the version but refresh `updated_at`.
+    let etag = format!("v{}-{}", note.version, note.updated_at);
     Ok(Protobuf(pb::GetNoteResponse {
         note: Some(pb::Note::from(note)),
+    })
+    .with_etag(etag)
+    .with_cache_control("no-cache"))
+}
+
+async fn list_child_notes(
+    Path(note_id): Path<i64>,
+    State(state): State<NotesState>,
+    Owner(owner): Owner,
+) -> Result<Protobuf<pb::ListNotesResponse>, NotesError> {
+    ensure_note_exists(&state.pool, owner.as_deref(), note_id).await?;
+
+    let rows = sqlx::query_as!(
+        NoteRow,
+        r#"
+        SELECT id, title, body, created_at, updated_at, version, due_at, parent_id, updated_by,
+            ARRAY(SELECT tag FROM note_tags WHERE note_id = notes.id ORDER BY tag) AS "tags!"
+        FROM notes
+        WHERE parent_id = $1 AND deleted_at IS NULL AND owner_id IS NOT DISTINCT FROM $2
+        ORDER BY id
+        "#,
+        note_id,
+        owner
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    Ok(Protobuf(pb::ListNotesResponse {
+        notes: rows.into_iter().map(pb::Note::from).collect(),
+        ..Default::default()
+    }))
+}
+
+/// Fails with `NotFound` unless `note_id` is one of `owner`'s notes outside the trash.
+async fn ensure_note_exists(
+    pool: &PgPool,
+    owner: Option<&str>,
+    note_id: i64,
+) -> Result<(), NotesError> {
+    let exists = sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS(
+            SELECT 1 FROM notes
+            WHERE id = $1 AND deleted_at IS NULL AND owner_id IS NOT DISTINCT FROM $2
+        ) AS "exists!"
+        "#,
+        note_id,
+        owner
+    )
+    .fetch_one(pool)
+    .await?;
+    if exists {
+        Ok(())
+    } else {
+        Err(NotesError::NotFound(note_id))
+    }
+}
+
+/// Superseded versions of a note, newest first, capped at `max_list_rows`.
+async fn list_note_revisions(
+    Path(note_id): Path<i64>,
+    State(state): State<NotesState>,
+    Owner(owner): Owner,
+) -> Result<Protobuf<pb::ListNoteRevisionsResponse>, NotesError> {
+    ensure_note_exists(&state.pool, owner.as_deref(), note_id).await?;
+
+    let mut revisions = sqlx::query_as!(
+        pb::NoteRevision,
+        r#"
+        SELECT version, title, body, updated_at AS updated_at_unix_ms, updated_by
+        FROM note_revisions
+        WHERE note_id = $1
+        ORDER BY version DESC
+        LIMIT $2
+        "#,
+        note_id,
+        list_limit(state.max_list_rows)
+    )
+    .fetch_all(&state.pool)
+    .await
+    .map_err(NotesError::reading_note(note_id))?;
+    let truncated = revisions.len() > state.max_list_rows;
+    revisions.truncate(state.max_list_rows);
+
+    Ok(Protobuf(pb::ListNoteRevisionsResponse {
+        revisions,
+        truncated,
+    }))
+}
+
+/// Makes a revision's title and body the note's content again, as a new
+/// version announced like any other update.
+async fn restore_note_revision(
+    Path((note_id, version)): Path<(i64, i64)>,
+    State(state): State<NotesState>,
+    Owner(owner): Owner,
+    Actor(actor): Actor,
+) -> Result<Protobuf<pb::UpdateNoteResponse>, NotesError> {
+    let mut tx = state.pool.begin().await?;
+    let mut row = sqlx::query_as!(
+        NoteRow,
+        r#"
+        SELECT id, title, body, created_at, updated_at, version, due_at, parent_id, updated_by,
+            ARRAY(SELECT tag FROM note_tags WHERE note_id = notes.id ORDER BY tag) AS "tags!"
+        FROM notes
+        WHERE id = $1 AND deleted_at IS NULL AND owner_id IS NOT DISTINCT FROM $2
+        FOR UPDATE
+        "#,
+        note_id,
+        owner
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(NotesError::reading_note(note_id))?
+    .ok_or(NotesError::NotFound(note_id))?;
+    let revision = sqlx::query!(
+        "SELECT title, body FROM note_revisions WHERE note_id = $1 AND version = $2",
+        note_id,
+        version
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(NotesError::reading_note(note_id))?
+    .ok_or(NotesError::RevisionNotFound { note_id, version })?;
+
+    let update = pb::UpdateNoteRequest {
+        title: Some(revision.title),
+        body: Some(revision.body),
+        ..Default::default()
+    };
+    let outcome = apply_update(
+        &mut row,
+        update,
+        actor,
+        state.significant_fields,
+        state.note_limits,
+    )?;
+    // The row is locked `FOR UPDATE`, so saving needs no version check.
+    if !matches!(outcome, UpdateOutcome::Unchanged) {
+        save_note(&mut tx, &row, None).await?;
+    }
+    tx.commit().await?;
+    if let UpdateOutcome::Versioned(delta) = outcome {
+        state
+            .events
+            .publish(owner.as_deref(), pb::note_event::Event::Updated(delta));
+    }
+
+    Ok(Protobuf(pb::UpdateNoteResponse {
+        note: Some(pb::Note::from(row)),
     }))
 }
 
 async fn update_note(
     Path(note_id): Path<i64>,
     State(state): State<NotesState>,
+    Owner(owner): Owner,
+    Actor(actor): Actor,
     Protobuf(payload): Protobuf<pb::UpdateNoteRequest>,
 ) -> Result<Protobuf<pb::UpdateNoteResponse>, NotesError> {
-    if payload.title.is_none() && payload.body.is_none() {
-        return Err(NotesError::Validation(
-            "at least one field must be provided",
-        ));
-    }
+    validate_update_request(&payload)?;
 
     let mut row = sqlx::query_as!(
         NoteRow,
         r#"
-        SELECT id, title, body, created_at, updated_at, version
+        SELECT id, title, body, created_at, updated_at, version, due_at, parent_id, updated_by,
+            ARRAY(SELECT tag FROM note_tags WHERE note_id = notes.id ORDER BY tag) AS "tags!"
         FROM notes
-        WHERE id = $1
+        WHERE id = $1 AND deleted_at IS NULL AND owner_id IS NOT DISTINCT FROM $2
         "#,
-        note_id
+        note_id,
+        owner
     )
     .fetch_optional(&state.pool)
-    .await?
+    .await
+    .map_err(NotesError::reading_note(note_id))?
     .ok_or(NotesError::NotFound(note_id))?;
 
+    if let Some(parent_id) = payload.parent_id
+        && row.parent_id != Some(parent_id)
+    {
+        validate_parent(&state.pool, owner.as_deref(), Some(note_id), parent_id).await?;
+    }
+
+    let expected_version = payload.expected_version;
+    if let Some(expected_version) = expected_version
+        && row.version != expected_version
+    {
+        return Err(NotesError::VersionConflict {
+            note_id,
+            current_version: row.version,
+        });
+    }
+
+    let outcome = apply_update(
+        &mut row,
+        payload,
+        actor,
+        state.significant_fields,
+        state.note_limits,
+    )?;
+    if !matches!(outcome, UpdateOutcome::Unchanged) {
+        let mut tx = state.pool.begin().await?;
+        if !save_note(&mut tx, &row, expected_version).await? {
+            drop(tx);
+            return Err(concurrent_update_error(&state.pool, note_id).await);
+        }
+        tx.commit().await?;
+    }
+    if let UpdateOutcome::Versioned(delta) = outcome {
+        state
+            .events
+            .publish(owner.as_deref(), pb::note_event::Event::Updated(delta));
+    }
+
+    Ok(Protobuf(pb::UpdateNoteResponse {
+        note: Some(pb::Note::from(row)),
+    }))
+}
+
+/// Saves `row` along with its tags, and when `expected_version` is set only if
+/// the stored note is still at that version. Returns whether the note was updated.
+///
+/// A version bump first records the stored content as a revision, so `conn`
+/// must be in a transaction that is rolled back when this returns `false`.
+async fn save_note(
+    conn: &mut PgConnection,
+    row: &NoteRow,
+    expected_version: Option<i64>,
+) -> Result<bool, NotesError> {
+    sqlx::query!(
+        r#"
+        INSERT INTO note_revisions (note_id, version, title, body, updated_at, updated_by)
+        SELECT id, version, title, body, updated_at, updated_by
+        FROM notes
+        WHERE id = $1 AND deleted_at IS NULL AND version < $2
+            AND ($3::BIGINT IS NULL OR version = $3)
+        FOR UPDATE
+        ON CONFLICT DO NOTHING
+        "#,
+        row.id,
+        row.version,
+        expected_version
+    )
+    .execute(&mut *conn)
+    .await?;
+    let updated = sqlx::query!(
+        r#"
+        UPDATE notes
+        SET title = $1, body = $2, updated_at = $3, version = $4, due_at = $5,
+            parent_id = $6, updated_by = $7
+        WHERE id = $8 AND deleted_at IS NULL AND ($9::BIGINT IS NULL OR version = $9)
+        "#,
+        &row.title,
+        &row.body,
+        row.updated_at,
+        row.version,
+        row.due_at,
+        row.parent_id,
+        row.updated_by,
+        row.id,
+        expected_version
+    )
+    .execute(&mut *conn)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    save_tags(conn, row.id, &row.tags).await?;
+    Ok(true)
+}
+
+/// Makes `tags` the note's exact set of tags.
+async fn save_tags(
+    conn: &mut PgConnection,
+    note_id: i64,
+    tags: &[String],
+) -> Result<(), NotesError> {
+    sqlx::query!(
+        "DELETE FROM note_tags WHERE note_id = $1 AND tag <> ALL($2)",
+        note_id,
+        tags
+    )
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO note_tags (note_id, tag)
+        SELECT $1, tag FROM UNNEST($2::TEXT[]) AS tag
+        ON CONFLICT DO NOTHING
+        "#,
+        note_id,
+        tags
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Most tags a single note may carry.
+const MAX_TAGS_PER_NOTE: usize = 32;
+/// Longest tag accepted, in characters.
+const MAX_TAG_CHARS: usize = 64;
+
+/// Trims, deduplicates and sorts `tags`.
+fn normalize_tags(tags: Vec<String>) -> Result<Vec<String>, NotesError> {
+    let tags: BTreeSet<String> = tags
+        .into_iter()
+        .map(|tag| {
+            let tag = tag.trim();
+            if tag.is_empty() {
+                return Err(NotesError::InvalidField {
+                    field: "tags",
+                    reason: "tags cannot be empty",
+                });
+            }
+            if tag.chars().count() > MAX_TAG_CHARS {
+                return Err(NotesError::InvalidField {
+                    field: "tags",
+                    reason: "tags cannot exceed 64 characters",
+                });
+            }
+            Ok(tag.to_owned())
+        })
+        .collect::<Result<_, _>>()?;
+    if tags.len() > MAX_TAGS_PER_NOTE {
+        return Err(NotesError::InvalidField {
+            field: "tags",
+            reason: "a note cannot have more than 32 tags",
+        });
+    }
+
+    Ok(tags.into_iter().collect())
+}
+
+/// Tags on notes outside the trash, with how many notes carry each.
+async fn list_tags(
+    State(state): State<NotesState>,
+    Owner(owner): Owner,
+) -> Result<Protobuf<pb::ListTagsResponse>, NotesError> {
+    let tags = sqlx::query_as!(
+        pb::TagCount,
+        r#"
+        SELECT note_tags.tag, COUNT(*) AS "note_count!"
+        FROM note_tags
+        JOIN notes ON notes.id = note_tags.note_id
+        WHERE notes.deleted_at IS NULL AND notes.owner_id IS NOT DISTINCT FROM $1
+        GROUP BY note_tags.tag
+        ORDER BY note_tags.tag
+        "#,
+        owner
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    Ok(Protobuf(pb::ListTagsResponse { tags }))
+}
+
+/// Explains why a save matched no row: the note changed version or was deleted
+/// since it was read.
+async fn concurrent_update_error(pool: &PgPool, note_id: i64) -> NotesError {
+    let current_version = sqlx::query_scalar!(
+        r#"
+        SELECT version
+        FROM notes
+        WHERE id = $1 AND deleted_at IS NULL
+        "#,
+        note_id
+    )
+    .fetch_optional(pool)
+    .await;
+
+    match current_version {
+        Ok(Some(current_version)) => NotesError::VersionConflict {
+            note_id,
+            current_version,
+        },
+        Ok(None) => NotesError::NotFound(note_id),
+        Err(error) => error.into(),
+    }
+}
+
+/// Most edits a single sync push may carry.
+const MAX_SYNC_PUSH_ITEMS: usize = 500;
+
+/// Applies offline edits whose `expected_version` still matches and reports
+/// conflicts for the rest, all in one transaction.
+async fn sync_push(
+    State(state): State<NotesState>,
+    Owner(owner): Owner,
+    Actor(actor): Actor,
+    Protobuf(payload): Protobuf<pb::SyncPushRequest>,
+) -> Result<Protobuf<pb::SyncPushResponse>, NotesError> {
+    if payload.items.len() > MAX_SYNC_PUSH_ITEMS {
+        return Err(NotesError::InvalidField {
+            field: "items",
+            reason: "a sync push supports at most 500 items",
+        });
+    }
+    if payload
+        .items
+        .iter()
+        .any(|item| item.title.is_none() && item.body.is_none())
+    {
+        return Err(NotesError::InvalidField {
+            field: "items",
+            reason: "every sync push item must change title or body",
+        });
+    }
+
+    let mut tx = state.pool.begin().await?;
+    let mut results = Vec::with_capacity(payload.items.len());
+    let mut deltas = Vec::new();
+    for item in payload.items {
+        let current = sqlx::query_as!(
+            NoteRow,
+            r#"
+            SELECT id, title, body, created_at, updated_at, version, due_at, parent_id, updated_by,
+                ARRAY(SELECT tag FROM note_tags WHERE note_id = notes.id ORDER BY tag) AS "tags!"
+            FROM notes
+            WHERE id = $1 AND deleted_at IS NULL AND owner_id IS NOT DISTINCT FROM $2
+            FOR UPDATE
+            "#,
+            item.id,
+            owner
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(NotesError::reading_note(item.id))?;
+
+        let outcome = match current {
+            None => pb::sync_push_result::Outcome::NotFound(true),
+            Some(row) if row.version != item.expected_version => {
+                pb::sync_push_result::Outcome::Conflict(pb::SyncPushConflict {
+                    current_version: row.version,
+                })
+            }
+            Some(mut row) => {
+                let update = pb::UpdateNoteRequest {
+                    title: item.title,
+                    body: item.body,
+                    ..Default::default()
+                };
+                // The row is locked `FOR UPDATE`, so saving needs no version check.
+                match apply_update(
+                    &mut row,
+                    update,
+                    actor.clone(),
+                    state.significant_fields,
+                    state.note_limits,
+                )? {
+                    UpdateOutcome::Unchanged => {}
+                    UpdateOutcome::Quiet => {
+                        save_note(&mut tx, &row, None).await?;
+                    }
+                    UpdateOutcome::Versioned(delta) => {
+                        save_note(&mut tx, &row, None).await?;
+                        deltas.push(delta);
+                    }
+                }
+                pb::sync_push_result::Outcome::Applied(pb::Note::from(row))
+            }
+        };
+        results.push(pb::SyncPushResult {
+            id: item.id,
+            outcome: Some(outcome),
+        });
+    }
+    tx.commit().await?;
+
+    if payload.bulk || deltas.len() > state.bulk_event_threshold {
+        if !deltas.is_empty() {
+            let ids = deltas.iter().map(|delta| delta.id).collect();
+            state.events.publish(
+                owner.as_deref(),
+                pb::note_event::Event::BulkChange(pb::BulkChange { ids }),
+            );
+        }
+    } else {
+        for delta in deltas {
+            state
+                .events
+                .publish(owner.as_deref(), pb::note_event::Event::Updated(delta));
+        }
+    }
+
+    Ok(Protobuf(pb::SyncPushResponse { results }))
+}
+
+fn validate_update_request(payload: &pb::UpdateNoteRequest) -> Result<(), NotesError> {
+    if payload.title.is_none()
+        && payload.body.is_none()
+        && payload.due_at_unix_ms.is_none()
+        && !payload.clear_due_at
+        && payload.parent_id.is_none()
+        && !payload.clear_parent
+        && payload.tags.is_empty()
+        && !payload.clear_tags
+    {
+        return Err(NotesError::Validation(
+            "at least one field must be provided",
+        ));
+    }
+    if payload.due_at_unix_ms.is_some() && payload.clear_due_at {
+        return Err(NotesError::InvalidField {
+            field: "due_at_unix_ms",
+            reason: "due_at_unix_ms cannot be set and cleared at once",
+        });
+    }
+    if payload.parent_id.is_some() && payload.clear_parent {
+        return Err(NotesError::InvalidField {
+            field: "parent_id",
+            reason: "parent_id cannot be set and cleared at once",
+        });
+    }
+    if !payload.tags.is_empty() && payload.clear_tags {
+        return Err(NotesError::InvalidField {
+            field: "tags",
+            reason: "tags cannot be set and cleared at once",
+        });
+    }
+    validate_due_at(payload.due_at_unix_ms)
+}
+
+enum UpdateOutcome {
+    Unchanged,
+    /// Only insignificant fields changed; the row is saved without a new version.
+    Quiet,
+    /// A significant field changed; carries the delta to broadcast.
+    Versioned(pb::NoteDelta),
+}
+
+/// Applies `payload` to `row`, bumping its version when a significant field changed.
+fn apply_update(
+    row: &mut NoteRow,
+    payload: pb::UpdateNoteRequest,
+    actor: Option<String>,
+    significant_fields: SignificantFields,
+    limits: NoteLimits,
+) -> Result<UpdateOutcome, NotesError> {
     let mut delta = pb::NoteDelta {
-        id: note_id,
+        id: row.id,
         title: None,
         body: None,
         updated_at_unix_ms: row.updated_at,
         version: row.version,
+        due_at_unix_ms: None,
+        due_at_cleared: false,
+        parent_id: None,
+        parent_cleared: false,
+        updated_by: None,
+        tags: Vec::new(),
+        tags_cleared: false,
     };
     let mut changed = false;
+    let mut significant = false;
 
     if let Some(title) = payload.title {
         let title = title.trim().to_owned();
         if title.is_empty() {
-            return Err(NotesError::Validation("title cannot be empty"));
+            return Err(NotesError::InvalidField {
+                field: "title",
+                reason: "title cannot be empty",
+            });
         }
+        limits.check_title(&title)?;
         if title != row.title {
             row.title.clone_from(&title);
             delta.title = Some(title);
             changed = true;
+            significant |= significant_fields.title;
         }
     }
 
     if let Some(body) = payload.body
         && body != row.body
     {
+        limits.check_body(&body)?;
         row.body.clone_from(&body);
         delta.body = Some(body);
         changed = true;
+        significant |= significant_fields.body;
+    }
+
+    if let Some(due_at) = payload.due_at_unix_ms
+        && row.due_at != Some(due_at)
+    {
+        row.due_at = Some(due_at);
+        delta.due_at_unix_ms = Some(due_at);
+        changed = true;
+        significant |= significant_fields.due_at;
     }
 
-    if changed {
-        row.version += 1;
-        row.updated_at = now_unix_millis();
-        delta.version = row.version;
-        delta.updated_at_unix_ms = row.updated_at;
+    if payload.clear_due_at && row.due_at.is_some() {
+        row.due_at = None;
+        delta.due_at_cleared = true;
+        changed = true;
+        significant |= significant_fields.due_at;
+    }
 
-        sqlx::query!(
-            r#"
-            UPDATE notes
-            SET title = $1, body = $2, updated_at = $3, version = $4
-            WHERE id = $5
-            "#,
-            &row.title,
-            &row.body,
-            row.updated_at,
-            row.version,
-            note_id
-        )
-        .execute(&state.pool)
-        .await?;
+    if let Some(parent_id) = payload.parent_id
+        && row.parent_id != Some(parent_id)
+    {
+        row.parent_id = Some(parent_id);
+        delta.parent_id = Some(parent_id);
+        changed = true;
+        significant |= significant_fields.parent;
+    }
 
-        emit_event(
-            &state.events_tx,
-            pb::NoteEvent {
-                event: Some(pb::note_event::Event::Updated(delta)),
-            },
-        );
+    if payload.clear_parent && row.parent_id.is_some() {
+        row.parent_id = None;
+        delta.parent_cleared = true;
+        changed = true;
+        significant |= significant_fields.parent;
     }
 
-    Ok(Protobuf(pb::UpdateNoteResponse {
-        note: Some(pb::Note::from(row)),
-    }))
+    if !payload.tags.is_empty() {
+        let tags = normalize_tags(payload.tags)?;
+        if tags != row.tags {
+            row.tags.clone_from(&tags);
+            delta.tags = tags;
+            changed = true;
+            significant |= significant_fields.tags;
+        }
+    }
+
+    if payload.clear_tags && !row.tags.is_empty() {
+        row.tags.clear();
+        delta.tags_cleared = true;
+        changed = true;
+        significant |= significant_fields.tags;
+    }
+
+    if !changed {
+        return Ok(UpdateOutcome::Unchanged);
+    }
+
+    row.updated_at = now_unix_millis();
+    row.updated_by.clone_from(&actor);
+    if !significant {
+        return Ok(UpdateOutcome::Quiet);
+    }
+
+    row.version += 1;
+    delta.version = row.version;
+    delta.updated_at_unix_ms = row.updated_at;
+    delta.updated_by = actor;
+    Ok(UpdateOutcome::Versioned(delta))
 }
 
+/// Moves a note to the trash; `POST /{note_id}/restore` takes it back out.
 async fn delete_note(
     Path(note_id): Path<i64>,
     State(state): State<NotesState>,
+    Owner(owner): Owner,
 ) -> Result<Protobuf<pb::DeleteNoteResponse>, NotesError> {
-    let result = sqlx::query!("DELETE FROM notes WHERE id = $1", note_id)
-        .execute(&state.pool)
-        .await?;
+    let result = sqlx::query!(
+        r#"
+        UPDATE notes
+        SET deleted_at = $2
+        WHERE id = $1 AND deleted_at IS NULL AND owner_id IS NOT DISTINCT FROM $3
+        "#,
+        note_id,
+        now_unix_millis(),
+        owner
+    )
+    .execute(&state.pool)
+    .await?;
 
     if result.rows_affected() == 0 {
         return Err(NotesError::NotFound(note_id));
     }
 
-    emit_event(
-        &state.events_tx,
-        pb::NoteEvent {
-            event: Some(pb::note_event::Event::Deleted(pb::NoteDeleted {
-                id: note_id,
-            })),
-        },
+    state.events.publish(
+        owner.as_deref(),
+        pb::note_event::Event::Deleted(pb::NoteDeleted { id: note_id }),
+    );
+
+    Ok(Protobuf(pb::DeleteNoteResponse { id: note_id }))
+}
+
+async fn restore_note(
+    Path(note_id): Path<i64>,
+    State(state): State<NotesState>,
+    Owner(owner): Owner,
+) -> Result<Protobuf<pb::RestoreNoteResponse>, NotesError> {
+    let row = sqlx::query_as!(
+        NoteRow,
+        r#"
+        UPDATE notes
+        SET deleted_at = NULL
+        WHERE id = $1 AND deleted_at IS NOT NULL AND owner_id IS NOT DISTINCT FROM $2
+        RETURNING id, title, body, created_at, updated_at, version, due_at, parent_id, updated_by,
+            ARRAY(SELECT tag FROM note_tags WHERE note_id = notes.id ORDER BY tag) AS "tags!"
+        "#,
+        note_id,
+        owner
+    )
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(NotesError::reading_note(note_id))?
+    .ok_or(NotesError::NotFound(note_id))?;
+
+    let note = pb::Note::from(row);
+    state.events.publish(
+        owner.as_deref(),
+        pb::note_event::Event::Restored(note.clone()),
+    );
+
+    Ok(Protobuf(pb::RestoreNoteResponse { note: Some(note) }))
+}
+
+/// Deleted notes, most recently deleted first, capped at `max_list_rows`.
+async fn list_trashed_notes(
+    State(state): State<NotesState>,
+    Owner(owner): Owner,
+) -> Result<Protobuf<pb::ListNotesResponse>, NotesError> {
+    let mut rows = sqlx::query_as!(
+        NoteRow,
+        r#"
+        SELECT id, title, body, created_at, updated_at, version, due_at, parent_id, updated_by,
+            ARRAY(SELECT tag FROM note_tags WHERE note_id = notes.id ORDER BY tag) AS "tags!"
+        FROM notes
+        WHERE deleted_at IS NOT NULL AND owner_id IS NOT DISTINCT FROM $2
+        ORDER BY deleted_at DESC, id
+        LIMIT $1
+        "#,
+        list_limit(state.max_list_rows),
+        owner
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    let truncated = rows.len() > state.max_list_rows;
+    rows.truncate(state.max_list_rows);
+
+    Ok(Protobuf(pb::ListNotesResponse {
+        notes: rows.into_iter().map(pb::Note::from).collect(),
+        truncated,
+        ..Default::default()
+    }))
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct PurgeNoteQuery {
+    #[serde(default)]
+    confirm: bool,
+}
+
+/// Irreversibly erases a note, e.g. for GDPR erasure requests.
+async fn purge_note(
+    Path(note_id): Path<i64>,
+    Query(query): Query<PurgeNoteQuery>,
+    State(state): State<NotesState>,
+    Owner(owner): Owner,
+) -> Result<Protobuf<pb::DeleteNoteResponse>, NotesError> {
+    if !state.allow_purge {
+        return Err(NotesError::Forbidden(
+            "purging notes is disabled on this server",
+        ));
+    }
+    if !query.confirm {
+        return Err(NotesError::InvalidField {
+            field: "confirm",
+            reason: "purging a note is irreversible and requires confirm=true",
+        });
+    }
+
+    let mut tx = state.pool.begin().await?;
+    let result = sqlx::query!(
+        "DELETE FROM notes WHERE id = $1 AND owner_id IS NOT DISTINCT FROM $2",
+        note_id,
+        owner
+    )
+    .execute(&mut *tx)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(NotesError::NotFound(note_id));
+    }
+    tx.commit().await?;
+
+    state.events.publish(
+        owner.as_deref(),
+        pb::note_event::Event::Deleted(pb::NoteDeleted { id: note_id }),
     );
 
     Ok(Protobuf(pb::DeleteNoteResponse { id: note_id }))
 }
 
+fn validate_due_at(due_at: Option<i64>) -> Result<(), NotesError> {
+    if due_at.is_some_and(|value| value < 0) {
+        return Err(NotesError::InvalidField {
+            field: "due_at_unix_ms",
+            reason: "due timestamp cannot be negative",
+        });
+    }
+    Ok(())
+}
+
+/// Ensures `parent_id` exists among `owner`'s notes and, when re-parenting
+/// `note_id`, that the note would not become its own ancestor.
+async fn validate_parent(
+    executor: impl PgExecutor<'_>,
+    owner: Option<&str>,
+    note_id: Option<i64>,
+    parent_id: i64,
+) -> Result<(), NotesError> {
+    if note_id == Some(parent_id) {
+        return Err(NotesError::InvalidField {
+            field: "parent_id",
+            reason: "a note cannot be its own parent",
+        });
+    }
+
+    let ancestors = sqlx::query_scalar!(
+        r#"
+        WITH RECURSIVE ancestors (id, parent_id) AS (
+            SELECT id, parent_id FROM notes
+            WHERE id = $1 AND deleted_at IS NULL AND owner_id IS NOT DISTINCT FROM $2
+            UNION
+            SELECT notes.id, notes.parent_id
+            FROM notes
+            JOIN ancestors ON notes.id = ancestors.parent_id
+        )
+        SELECT id AS "id!" FROM ancestors
+        "#,
+        parent_id,
+        owner
+    )
+    .fetch_all(executor)
+    .await?;
+
+    if ancestors.is_empty() {
+        return Err(NotesError::InvalidField {
+            field: "parent_id",
+            reason: "parent note does not exist",
+        });
+    }
+    if note_id.is_some_and(|note_id| ancestors.contains(&note_id)) {
+        return Err(NotesError::InvalidField {
+            field: "parent_id",
+            reason: "a note cannot be its own ancestor",
+        });
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct NoteEventsQuery {
+    kinds: Option<String>,
+    /// Last `seq` the subscriber saw; newer buffered events are replayed first.
+    /// Also accepted as `after_seq`.
+    #[serde(alias = "after_seq")]
+    since: Option<u64>,
+    /// A `reconnect_token` from an earlier connection, resuming after its `seq`.
+    resume: Option<String>,
+    #[serde(default)]
+    format: EventFormat,
+}
+
+/// Most notes a single subscription may name.
+const MAX_SUBSCRIBED_NOTES: usize = 100;
+
+/// Every `note_id` in `query`; it may repeat, which `Query` cannot express.
+fn note_id_params(query: Option<&str>) -> Result<Vec<i64>, NotesError> {
+    let note_ids = form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .filter(|(key, _)| key == "note_id")
+        .map(|(_, note_id)| {
+            note_id.parse().map_err(|_| NotesError::InvalidField {
+                field: "note_id",
+                reason: "note_id must be an integer",
+            })
+        })
+        .collect::<Result<Vec<i64>, _>>()?;
+    if note_ids.len() > MAX_SUBSCRIBED_NOTES {
+        return Err(NotesError::InvalidField {
+            field: "note_id",
+            reason: "a subscription supports at most 100 notes",
+        });
+    }
+    Ok(note_ids)
+}
+
+/// Pings subscribers so proxies keep idle connections open, and drops
+/// connections that stop answering.
+#[derive(Debug, Clone, Copy)]
+struct Heartbeat {
+    interval: Duration,
+    timeout: Duration,
+}
+
+/// Encoding of websocket events.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum EventFormat {
+    /// Binary frames holding an encoded `NoteEvent`.
+    #[default]
+    Protobuf,
+    /// Text frames holding the `NoteEvent` as JSON, for inspecting in browser devtools.
+    Json,
+}
+
+/// Streams events about the owner's notes only, or only about the notes named
+/// by one or more `note_id` parameters, each of which must be one of theirs.
 async fn subscribe_note_events(
     websocket: WebSocketUpgrade,
+    Query(query): Query<NoteEventsQuery>,
+    RawQuery(raw_query): RawQuery,
     State(state): State<NotesState>,
-) -> impl IntoResponse {
-    let events_rx = state.events_tx.subscribe();
-    websocket.on_upgrade(move |socket| websocket_loop(socket, events_rx))
+    Owner(owner): Owner,
+) -> Result<impl IntoResponse, NotesError> {
+    let filter = EventFilter::parse(
+        query.kinds.as_deref(),
+        note_id_params(raw_query.as_deref())?,
+    )?;
+    let since = match (query.since, query.resume.as_deref()) {
+        (Some(_), Some(_)) => {
+            return Err(NotesError::Validation(
+                "since and resume cannot be combined",
+            ));
+        }
+        (since, None) => since,
+        (None, Some(token)) => Some(state.reconnect_tokens.verify(token, now_unix_millis())?),
+    };
+    for note_id in filter.note_ids() {
+        ensure_note_exists(&state.pool, owner.as_deref(), note_id).await?;
+    }
+    let mut slots = Vec::new();
+    for note_id in filter.note_ids() {
+        let Some(slot) = state
+            .note_subscribers
+            .try_claim(note_id, state.max_subscribers_per_note)
+        else {
+            return Ok(websocket.on_upgrade(move |socket| {
+                close_socket(
+                    socket,
+                    close_code::AGAIN,
+                    format!("note {note_id} has too many subscribers"),
+                )
+            }));
+        };
+        slots.push(slot);
+    }
+    let mut subscription = state.events.subscribe(since, owner);
+    if since.is_none() {
+        let snapshot = snapshot_event(
+            &state,
+            subscription.owner_id.as_deref(),
+            &filter.note_ids(),
+            subscription.latest_seq,
+        )
+        .await?;
+        subscription.backlog.push(snapshot);
+    }
+    let format = query.format;
+    let heartbeat = Heartbeat {
+        interval: state.heartbeat_interval,
+        timeout: state.heartbeat_timeout,
+    };
+    Ok(websocket.on_upgrade(move |socket| async move {
+        let connection = state.ws_connections.register(&filter, now_unix_millis());
+        websocket_loop(
+            socket,
+            subscription,
+            filter,
+            format,
+            heartbeat,
+            &connection,
+            state.reconnect_tokens,
+        )
+        .await;
+        drop(slots);
+    }))
+}
+
+/// The current notes for a fresh subscriber, or only `note_ids` when given.
+/// Taken after subscribing, so every change it misses still arrives as a live
+/// event.
+async fn snapshot_event(
+    state: &NotesState,
+    owner: Option<&str>,
+    note_ids: &[i64],
+    latest_seq: u64,
+) -> Result<pb::NoteEvent, NotesError> {
+    let mut rows = sqlx::query_as!(
+        NoteRow,
+        r#"
+        SELECT id, title, body, created_at, updated_at, version, due_at, parent_id, updated_by,
+            ARRAY(SELECT tag FROM note_tags WHERE note_id = notes.id ORDER BY tag) AS "tags!"
+        FROM notes
+        WHERE deleted_at IS NULL AND (cardinality($1::BIGINT[]) = 0 OR id = ANY($1))
+            AND owner_id IS NOT DISTINCT FROM $3
+        ORDER BY id
+        LIMIT $2
+        "#,
+        note_ids,
+        list_limit(state.max_list_rows),
+        owner
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    let truncated = rows.len() > state.max_list_rows;
+    rows.truncate(state.max_list_rows);
+
+    Ok(note_event(
+        pb::note_event::Event::Snapshot(pb::Snapshot {
+            notes: rows.into_iter().map(pb::Note::from).collect(),
+            latest_seq,
+            truncated,
+        }),
+        0,
+    ))
+}
+
+async fn close_socket(mut socket: WebSocket, code: u16, reason: String) {
+    let frame = CloseFrame {
+        code,
+        reason: reason.into(),
+    };
+    if socket.send(Message::Close(Some(frame))).await.is_err() {
+        // The client is already gone.
+    }
 }
 
-async fn websocket_loop(mut socket: WebSocket, mut events_rx: broadcast::Receiver<pb::NoteEvent>) {
+async fn websocket_loop(
+    mut socket: WebSocket,
+    subscription: Subscription,
+    filter: EventFilter,
+    format: EventFormat,
+    heartbeat: Heartbeat,
+    connection: &RegisteredConnection,
+    reconnect_tokens: Arc<ReconnectTokens>,
+) {
+    let Subscription {
+        rx: mut events_rx,
+        backlog,
+        mut latest_seq,
+        owner_id,
+    } = subscription;
+    for event in backlog {
+        if !filter.matches(&event) {
+            continue;
+        }
+        if send_event(&mut socket, &event, format).await.is_err() {
+            return;
+        }
+        connection.record_sent();
+    }
+
+    let period = reconnect_tokens.interval;
+    let mut reconnect_interval = time::interval_at(time::Instant::now() + period, period);
+    let mut ping_interval = time::interval_at(
+        time::Instant::now() + heartbeat.interval,
+        heartbeat.interval,
+    );
+    // Set while a ping is unanswered; any frame from the client clears it.
+    let mut reply_deadline = None;
     loop {
-        match events_rx.recv().await {
-            Ok(event) => {
-                let payload = Bytes::from(event.encode_to_vec());
-                if socket.send(Message::Binary(payload)).await.is_err() {
+        tokio::select! {
+            received = events_rx.recv() => match received {
+                Ok(published) => {
+                    // Filtered events count as seen, so resuming skips them too.
+                    latest_seq = latest_seq.max(published.event.seq);
+                    if !published.is_for(owner_id.as_deref()) || !filter.matches(&published.event) {
+                        continue;
+                    }
+                    let event = published.event;
+                    if send_event(&mut socket, &event, format).await.is_err() {
+                        break;
+                    }
+                    connection.record_sent();
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped_count)) => {
+                    warn!("websocket receiver lagged by {skipped_count} events");
+                    connection.record_lag(skipped_count);
+                    // The skipped events directly follow the last one received.
+                    latest_seq = latest_seq.saturating_add(skipped_count);
+                    let resync = note_event(
+                        pb::note_event::Event::Resync(pb::Resync { latest_seq }),
+                        0,
+                    );
+                    if send_event(&mut socket, &resync, format).await.is_err() {
+                        break;
+                    }
+                    connection.record_sent();
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = reconnect_interval.tick() => {
+                let token = reconnect_tokens.issue(latest_seq, now_unix_millis());
+                let event = note_event(
+                    pb::note_event::Event::Reconnect(pb::ReconnectToken {
+                        token,
+                        seq: latest_seq,
+                    }),
+                    0,
+                );
+                if send_event(&mut socket, &event, format).await.is_err() {
+                    break;
+                }
+                connection.record_sent();
+            }
+            _ = ping_interval.tick() => {
+                if socket.send(Message::Ping(Bytes::new())).await.is_err() {
                     break;
                 }
+                reply_deadline.get_or_insert(time::Instant::now() + heartbeat.timeout);
             }
-            Err(broadcast::error::RecvError::Lagged(skipped_count)) => {
-                warn!("websocket receiver lagged by {skipped_count} events");
+            () = sleep_until_deadline(reply_deadline) => {
+                warn!("dropping websocket subscriber that stopped answering pings");
+                break;
             }
-            Err(broadcast::error::RecvError::Closed) => break,
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                Some(Ok(_)) => reply_deadline = None,
+            },
+            () = connection.closing() => {
+                close_socket(socket, close_code::AWAY, "server is shutting down".to_owned()).await;
+                return;
+            }
+        }
+    }
+}
+
+async fn sleep_until_deadline(deadline: Option<time::Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn send_event(
+    socket: &mut WebSocket,
+    event: &pb::NoteEvent,
+    format: EventFormat,
+) -> Result<(), axum::Error> {
+    let message = match format {
+        EventFormat::Protobuf => Message::Binary(Bytes::from(event.encode_to_vec())),
+        EventFormat::Json => Message::Text(
+            serde_json::to_string(event)
+                .map_err(axum::Error::new)?
+                .into(),
+        ),
+    };
+    socket.send(message).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: NoteLimits = NoteLimits {
+        title_chars: 255,
+        body_bytes: 1024,
+    };
+
+    fn note_row() -> NoteRow {
+        NoteRow {
+            id: 1,
+            title: "title".to_owned(),
+            body: "body".to_owned(),
+            created_at: 1,
+            updated_at: 1,
+            version: 3,
+            due_at: None,
+            parent_id: None,
+            updated_by: None,
+            tags: Vec::new(),
         }
     }
+
+    #[test]
+    fn insignificant_change_keeps_version() {
+        let mut row = note_row();
+        let payload = pb::UpdateNoteRequest {
+            due_at_unix_ms: Some(10),
+            ..Default::default()
+        };
+
+        let outcome = apply_update(
+            &mut row,
+            payload,
+            None,
+            SignificantFields::default(),
+            LIMITS,
+        )
+        .expect("update should apply");
+
+        assert!(matches!(outcome, UpdateOutcome::Quiet));
+        assert_eq!(row.version, 3);
+        assert_eq!(row.due_at, Some(10));
+    }
+
+    #[test]
+    fn significant_change_bumps_version() {
+        let mut row = note_row();
+        let payload = pb::UpdateNoteRequest {
+            title: Some("renamed".to_owned()),
+            due_at_unix_ms: Some(10),
+            ..Default::default()
+        };
+
+        let outcome = apply_update(
+            &mut row,
+            payload,
+            None,
+            SignificantFields::default(),
+            LIMITS,
+        )
+        .expect("update should apply");
+
+        let UpdateOutcome::Versioned(delta) = outcome else {
+            panic!("expected a versioned update");
+        };
+        assert_eq!(delta.version, 4);
+        assert_eq!(delta.due_at_unix_ms, Some(10));
+    }
+
+    #[test]
+    fn note_ids_may_repeat_in_the_query() {
+        assert_eq!(
+            note_id_params(Some("kinds=updated&note_id=4&note_id=2&since=1")).ok(),
+            Some(vec![4, 2])
+        );
+        assert_eq!(note_id_params(None).ok(), Some(Vec::new()));
+        assert!(matches!(
+            note_id_params(Some("note_id=four")),
+            Err(NotesError::InvalidField {
+                field: "note_id",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn note_ids_are_percent_decoded_and_capped() {
+        assert_eq!(
+            note_id_params(Some("note%5Fid=%37&note_id=8")).ok(),
+            Some(vec![7, 8])
+        );
+        let too_many = vec!["note_id=1"; MAX_SUBSCRIBED_NOTES + 1].join("&");
+        assert!(matches!(
+            note_id_params(Some(&too_many)),
+            Err(NotesError::InvalidField {
+                field: "note_id",
+                ..
+            })
+        ));
+    }
 }
@@ -1,20 +1,61 @@
 use sqlx::PgPool;
 
+mod actor;
+mod config;
 mod errors;
+mod events;
 mod handlers;
-mod protobuf;
+mod owner;
+mod reconnect;
+mod relay;
 mod state;
 
+#[allow(clippy::doc_markdown)]
 pub mod pb {
     include!(concat!(env!("OUT_DIR"), "/notes.v1.rs"));
 }
 
+pub use config::{NotesConfig, SignificantFields};
 pub use errors::NotesError;
-pub use handlers::create_handlers;
-pub use protobuf::Protobuf;
+pub use events::WsConnections;
+pub use handlers::{
+    create_admin_handlers, create_handlers, create_handlers_with_config,
+    create_handlers_with_connections,
+};
+pub use owner::NoteOwner;
+pub use protobuf_http::{Protobuf, ProtobufResponse};
 
-static MIGRATOR: sqlx::migrate::Migrator = sqlx::migrate!("./migrations");
+/// Session-level advisory lock held while running notes migrations, so that
+/// only one of several concurrently booting instances applies them.
+const MIGRATION_LOCK_KEY: i64 = i64::from_be_bytes(*b"notes-mg");
 
 pub async fn run_migrations(pool: &PgPool) -> Result<(), sqlx::migrate::MigrateError> {
-    MIGRATOR.run(pool).await
+    let mut migrator = sqlx::migrate!("./migrations");
+    // Apps share one `_sqlx_migrations` table, so other apps' versions are expected.
+    migrator.set_ignore_missing(true);
+
+    let mut conn = pool.acquire().await?;
+    sqlx::query("SELECT pg_advisory_lock($1)")
+        .bind(MIGRATION_LOCK_KEY)
+        .execute(&mut *conn)
+        .await?;
+
+    let result = migrator.run_direct(&mut *conn).await;
+
+    let unlocked = sqlx::query("SELECT pg_advisory_unlock($1)")
+        .bind(MIGRATION_LOCK_KEY)
+        .execute(&mut *conn)
+        .await;
+    if unlocked.is_err() {
+        // Closing the session is the only other way to release the lock.
+        conn.close_on_drop();
+    }
+
+    result
+}
+
+/// Whether every notes migration is recorded as successfully applied, for
+/// readiness probes.
+pub async fn migrations_applied(pool: &PgPool) -> Result<bool, sqlx::Error> {
+    protobuf_http::migrations_applied(pool, &sqlx::migrate!("./migrations")).await
 }
@@ -0,0 +1,28 @@
+use std::convert::Infallible;
+
+use axum::{extract::FromRequestParts, http::request::Parts};
+
+/// The authenticated user a request acts for. The embedding server inserts it
+/// into request extensions; notes are then scoped to that user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoteOwner(pub String);
+
+/// Whose notes a request reads and writes, from [`NoteOwner`]. `None` when the
+/// server runs unauthenticated, where every note is unowned and shared.
+pub(crate) struct Owner(pub(crate) Option<String>);
+
+impl<S> FromRequestParts<S> for Owner
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self(
+            parts
+                .extensions
+                .get::<NoteOwner>()
+                .map(|owner| owner.0.clone()),
+        ))
+    }
+}
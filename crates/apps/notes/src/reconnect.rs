@@ -0,0 +1,113 @@
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::NotesError;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Issues and verifies the `reconnect_token`s sent over the realtime stream.
+///
+/// A token is `<seq>.<expires_at_ms>.<hex hmac>`. The key is random per
+/// process because event sequence numbers are too, so tokens from another
+/// instance or an earlier run are rejected rather than resumed incorrectly.
+pub(crate) struct ReconnectTokens {
+    key: [u8; 32],
+    ttl: Duration,
+    pub(crate) interval: Duration,
+}
+
+impl ReconnectTokens {
+    pub(crate) fn new(ttl: Duration, interval: Duration) -> Self {
+        Self {
+            key: rand::random(),
+            ttl,
+            interval,
+        }
+    }
+
+    pub(crate) fn issue(&self, seq: u64, now_ms: i64) -> String {
+        let ttl_ms = i64::try_from(self.ttl.as_millis()).unwrap_or(i64::MAX);
+        let claims = format!("{seq}.{}", now_ms.saturating_add(ttl_ms));
+        let signature = hex::encode(self.mac(&claims).finalize().into_bytes());
+        format!("{claims}.{signature}")
+    }
+
+    /// Returns the sequence number embedded in a valid, unexpired token.
+    pub(crate) fn verify(&self, token: &str, now_ms: i64) -> Result<u64, NotesError> {
+        const INVALID: NotesError = NotesError::InvalidField {
+            field: "resume",
+            reason: "reconnect token is invalid",
+        };
+
+        let (claims, signature) = token.rsplit_once('.').ok_or(INVALID)?;
+        let signature = hex::decode(signature).map_err(|_| INVALID)?;
+        self.mac(claims)
+            .verify_slice(&signature)
+            .map_err(|_| INVALID)?;
+
+        let (seq, expires_at_ms) = claims.split_once('.').ok_or(INVALID)?;
+        let seq = seq.parse().map_err(|_| INVALID)?;
+        let expires_at_ms: i64 = expires_at_ms.parse().map_err(|_| INVALID)?;
+        if expires_at_ms <= now_ms {
+            return Err(NotesError::InvalidField {
+                field: "resume",
+                reason: "reconnect token has expired",
+            });
+        }
+
+        Ok(seq)
+    }
+
+    fn mac(&self, claims: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any size");
+        mac.update(claims.as_bytes());
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens() -> ReconnectTokens {
+        ReconnectTokens::new(Duration::from_mins(1), Duration::from_secs(30))
+    }
+
+    #[test]
+    fn issued_token_round_trips_its_seq() {
+        let tokens = tokens();
+        let token = tokens.issue(42, 1_000);
+        assert_eq!(
+            tokens.verify(&token, 2_000).expect("token should verify"),
+            42
+        );
+    }
+
+    #[test]
+    fn tampered_token_is_rejected() {
+        let tokens = tokens();
+        let token = tokens.issue(42, 1_000).replacen("42", "43", 1);
+        let error = tokens
+            .verify(&token, 2_000)
+            .expect_err("token should be rejected");
+        assert_eq!(error.to_string(), "reconnect token is invalid");
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let tokens = tokens();
+        let token = tokens.issue(42, 1_000);
+        let error = tokens
+            .verify(&token, 61_000)
+            .expect_err("token should be rejected");
+        assert_eq!(error.to_string(), "reconnect token has expired");
+    }
+
+    #[test]
+    fn token_from_another_process_is_rejected() {
+        let token = tokens().issue(42, 1_000);
+        assert!(tokens().verify(&token, 2_000).is_err());
+    }
+}
@@ -0,0 +1,246 @@
+use std::{
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use prost::Message;
+use sqlx::{PgPool, postgres::PgListener};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::{
+    events::{EventHub, note_event},
+    pb,
+};
+
+/// Postgres channel the instances sharing a database relay note events on.
+const CHANNEL: &str = "notes_events";
+/// Postgres rejects `NOTIFY` payloads of 8000 bytes or more.
+const MAX_PAYLOAD_BYTES: usize = 7999;
+/// Events waiting to be notified; more are dropped while the database lags.
+const QUEUE_CAPACITY: usize = 1024;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Stands for an unowned event in the payload's owner field.
+const NO_OWNER: &str = "-";
+
+/// Hands an instance's events to Postgres `NOTIFY`, so that the other
+/// instances sharing its database re-publish them to their own subscribers.
+pub(crate) struct Relay {
+    origin: u64,
+    tx: mpsc::Sender<String>,
+}
+
+impl Relay {
+    /// Queues `event` for the other instances, or returns `false` when it has
+    /// to be dropped.
+    pub(crate) fn send(&self, owner_id: Option<&str>, event: &pb::note_event::Event) -> bool {
+        let Some(payload) = encode(self.origin, owner_id, event) else {
+            warn!("note event too large to relay to other instances");
+            return false;
+        };
+        if self.tx.try_send(payload).is_err() {
+            warn!("note event relay queue is full; other instances will miss an event");
+            return false;
+        }
+        true
+    }
+}
+
+/// Creates an [`EventHub`] whose events are relayed to, and which receives
+/// the events of, every instance relaying through the same database.
+pub(crate) fn relayed_hub(pool: &PgPool, buffer_size: usize, capacity: usize) -> Arc<EventHub> {
+    let origin = rand::random();
+    let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+    let hub = Arc::new(EventHub::with_relay(
+        buffer_size,
+        capacity,
+        Some(Relay { origin, tx }),
+    ));
+    tokio::spawn(notify(pool.clone(), rx));
+    tokio::spawn(listen(pool.clone(), origin, Arc::downgrade(&hub)));
+    hub
+}
+
+/// Runs until the hub, and with it the sending half, is dropped.
+async fn notify(pool: PgPool, mut rx: mpsc::Receiver<String>) {
+    while let Some(payload) = rx.recv().await {
+        let notified = sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(CHANNEL)
+            .bind(&payload)
+            .execute(&pool)
+            .await;
+        if let Err(error) = notified {
+            warn!(%error, "failed to relay a note event to other instances");
+        }
+    }
+}
+
+async fn listen(pool: PgPool, origin: u64, hub: Weak<EventHub>) {
+    let mut reconnecting = false;
+    loop {
+        let Some(mut listener) = connect_listener(&pool, &hub).await else {
+            return;
+        };
+        if reconnecting {
+            // Events relayed while disconnected are gone; subscribers refetch
+            // once new ones are certain to arrive again.
+            let Some(hub) = hub.upgrade() else {
+                return;
+            };
+            hub.publish_resync();
+        }
+        if !receive(&mut listener, origin, &hub).await {
+            return;
+        }
+        warn!("note event relay connection lost; events relayed meanwhile are missed");
+        reconnecting = true;
+    }
+}
+
+/// Listens on `CHANNEL` until it succeeds, or the hub is dropped.
+async fn connect_listener(pool: &PgPool, hub: &Weak<EventHub>) -> Option<PgListener> {
+    loop {
+        let connected = async {
+            let mut listener = PgListener::connect_with(pool).await?;
+            listener.listen(CHANNEL).await?;
+            Ok::<_, sqlx::Error>(listener)
+        };
+        match connected.await {
+            Ok(listener) => return Some(listener),
+            Err(error) => {
+                warn!(%error, "failed to listen for other instances' note events");
+                if hub.strong_count() == 0 {
+                    return None;
+                }
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Publishes other instances' events until the connection is lost, then
+/// returns `true`, or until the hub is dropped.
+async fn receive(listener: &mut PgListener, origin: u64, hub: &Weak<EventHub>) -> bool {
+    loop {
+        let notification = match listener.try_recv().await {
+            Ok(Some(notification)) => notification,
+            Ok(None) => return true,
+            Err(error) => {
+                warn!(%error, "failed to receive other instances' note events");
+                return true;
+            }
+        };
+        let Some(hub) = hub.upgrade() else {
+            return false;
+        };
+        match decode(notification.payload()) {
+            // An instance's own events were already published when relayed.
+            Some((from, _, _)) if from == origin => {}
+            Some((_, owner_id, event)) => hub.publish_relayed(owner_id.as_deref(), event),
+            None => warn!("ignoring a malformed relayed note event"),
+        }
+    }
+}
+
+/// `<origin hex>.<base64 owner or ->.<base64 NoteEvent>`. Events too large
+/// for a notification are relayed as a `BulkChange` of their note, which
+/// subscribers refetch.
+fn encode(origin: u64, owner_id: Option<&str>, event: &pb::note_event::Event) -> Option<String> {
+    let owner = owner_id.map_or_else(|| NO_OWNER.to_owned(), |owner| STANDARD.encode(owner));
+    let payload = |event: pb::note_event::Event| {
+        let event = STANDARD.encode(note_event(event, 0).encode_to_vec());
+        format!("{origin:016x}.{owner}.{event}")
+    };
+
+    let full = payload(event.clone());
+    if full.len() <= MAX_PAYLOAD_BYTES {
+        return Some(full);
+    }
+    let id = match event {
+        pb::note_event::Event::Created(note) | pb::note_event::Event::Restored(note) => note.id,
+        pb::note_event::Event::Updated(delta) => delta.id,
+        _ => return None,
+    };
+    let bulk = payload(pb::note_event::Event::BulkChange(pb::BulkChange {
+        ids: vec![id],
+    }));
+    (bulk.len() <= MAX_PAYLOAD_BYTES).then_some(bulk)
+}
+
+fn decode(payload: &str) -> Option<(u64, Option<String>, pb::note_event::Event)> {
+    let mut parts = payload.splitn(3, '.');
+    let origin = u64::from_str_radix(parts.next()?, 16).ok()?;
+    let owner_id = match parts.next()? {
+        NO_OWNER => None,
+        owner => Some(String::from_utf8(STANDARD.decode(owner).ok()?).ok()?),
+    };
+    let event = pb::NoteEvent::decode(STANDARD.decode(parts.next()?).ok()?.as_slice()).ok()?;
+    Some((origin, owner_id, event.event?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn created(body: String) -> pb::note_event::Event {
+        pb::note_event::Event::Created(pb::Note {
+            id: 7,
+            title: "relayed".to_owned(),
+            body,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn payloads_round_trip() {
+        let event = created("hello".to_owned());
+
+        let owned = encode(42, Some("alice"), &event).expect("event fits a payload");
+        assert_eq!(
+            decode(&owned),
+            Some((42, Some("alice".to_owned()), event.clone()))
+        );
+
+        let unowned = encode(42, None, &event).expect("event fits a payload");
+        assert_eq!(decode(&unowned), Some((42, None, event)));
+        assert_eq!(decode("not a payload"), None);
+    }
+
+    #[test]
+    fn dropped_events_resync_local_subscribers() {
+        let (tx, _rx) = mpsc::channel(1);
+        let hub = EventHub::with_relay(4, 16, Some(Relay { origin: 42, tx }));
+        let mut subscription = hub.subscribe(None, Some("alice".to_owned()));
+
+        hub.publish(Some("alice"), created("queued".to_owned()));
+        hub.publish(Some("bob"), created("dropped".to_owned()));
+
+        let mut kinds = Vec::new();
+        while let Ok(published) = subscription.rx.try_recv() {
+            if published.is_for(subscription.owner_id.as_deref()) {
+                kinds.push(published.event.kind());
+            }
+        }
+        assert_eq!(
+            kinds,
+            [pb::NoteEventKind::Created, pb::NoteEventKind::Resync]
+        );
+    }
+
+    #[test]
+    fn oversized_events_are_relayed_as_bulk_changes() {
+        let payload = encode(42, None, &created("x".repeat(MAX_PAYLOAD_BYTES)))
+            .expect("bulk change fits a payload");
+
+        assert!(payload.len() <= MAX_PAYLOAD_BYTES);
+        assert_eq!(
+            decode(&payload),
+            Some((
+                42,
+                None,
+                pb::note_event::Event::BulkChange(pb::BulkChange { ids: vec![7] })
+            ))
+        );
+    }
+}
@@ -1,14 +1,89 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use axum::http::Extensions;
+use protobuf_http::ProtobufState;
+use serde::Serialize;
 use sqlx::PgPool;
-use tokio::sync::broadcast;
+use tracing::{Level, trace};
 
-use crate::pb;
+use crate::{
+    NotesConfig, NotesError, SignificantFields,
+    config::DEFAULT_MAX_BODY_BYTES,
+    events::{EventHub, NoteSubscribers, WsConnections},
+    pb,
+    reconnect::ReconnectTokens,
+    relay::relayed_hub,
+};
 
 #[derive(Clone)]
 pub(crate) struct NotesState {
     pub(crate) pool: PgPool,
-    pub(crate) events_tx: broadcast::Sender<pb::NoteEvent>,
+    pub(crate) events: Arc<EventHub>,
+    pub(crate) reconnect_tokens: Arc<ReconnectTokens>,
+    pub(crate) allow_explain: bool,
+    pub(crate) allow_purge: bool,
+    pub(crate) significant_fields: SignificantFields,
+    pub(crate) note_subscribers: Arc<NoteSubscribers>,
+    pub(crate) ws_connections: WsConnections,
+    pub(crate) max_subscribers_per_note: usize,
+    pub(crate) max_list_rows: usize,
+    pub(crate) note_limits: NoteLimits,
+    pub(crate) bulk_event_threshold: usize,
+    pub(crate) heartbeat_interval: Duration,
+    pub(crate) heartbeat_timeout: Duration,
+    pub(crate) broadcast_degraded_percent: usize,
+}
+
+/// Enable with `RUST_LOG=notes::payload=trace` to log decoded requests as JSON.
+const PAYLOAD_LOG_TARGET: &str = "notes::payload";
+
+impl ProtobufState for NotesState {
+    type Rejection = NotesError;
+    const MAX_BODY_BYTES: Option<usize> = Some(DEFAULT_MAX_BODY_BYTES);
+    const STRICT_CONTENT_TYPE: bool = true;
+
+    fn decoded<T: Serialize>(_extensions: &Extensions, message: &T, _body: &[u8]) {
+        if !tracing::enabled!(target: PAYLOAD_LOG_TARGET, Level::TRACE) {
+            return;
+        }
+        match serde_json::to_string(message) {
+            Ok(json) => trace!(target: PAYLOAD_LOG_TARGET, payload = %json, "decoded request"),
+            Err(error) => trace!(target: PAYLOAD_LOG_TARGET, %error, "failed to serialize request"),
+        }
+    }
+}
+
+/// Longest title and body a note may be saved with.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct NoteLimits {
+    pub(crate) title_chars: usize,
+    pub(crate) body_bytes: usize,
+}
+
+impl NoteLimits {
+    /// Counts characters rather than bytes, so non-Latin titles get the same room.
+    pub(crate) fn check_title(self, title: &str) -> Result<(), NotesError> {
+        if title.chars().count() > self.title_chars {
+            return Err(NotesError::InvalidField {
+                field: "title",
+                reason: "title exceeds the maximum length",
+            });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_body(self, body: &str) -> Result<(), NotesError> {
+        if body.len() > self.body_bytes {
+            return Err(NotesError::InvalidField {
+                field: "body",
+                reason: "body exceeds the maximum length",
+            });
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, sqlx::FromRow)]
@@ -19,6 +94,10 @@ pub(crate) struct NoteRow {
     pub(crate) created_at: i64,
     pub(crate) updated_at: i64,
     pub(crate) version: i64,
+    pub(crate) due_at: Option<i64>,
+    pub(crate) parent_id: Option<i64>,
+    pub(crate) updated_by: Option<String>,
+    pub(crate) tags: Vec<String>,
 }
 
 impl From<NoteRow> for pb::Note {
@@ -30,18 +109,49 @@ impl From<NoteRow> for pb::Note {
             created_at_unix_ms: value.created_at,
             updated_at_unix_ms: value.updated_at,
             version: value.version,
+            due_at_unix_ms: value.due_at,
+            parent_id: value.parent_id,
+            updated_by: value.updated_by,
+            tags: value.tags,
         }
     }
 }
 
-pub(crate) fn build_state(pool: PgPool) -> NotesState {
-    let (events_tx, _) = broadcast::channel(512);
-    NotesState { pool, events_tx }
-}
-
-pub(crate) fn emit_event(events_tx: &broadcast::Sender<pb::NoteEvent>, event: pb::NoteEvent) {
-    if events_tx.send(event).is_err() {
-        // No active realtime subscribers is expected and not a server error.
+pub(crate) fn build_state(
+    pool: PgPool,
+    config: NotesConfig,
+    ws_connections: WsConnections,
+) -> NotesState {
+    let events = if config.relay_events {
+        relayed_hub(&pool, config.event_buffer_size, config.broadcast_capacity)
+    } else {
+        Arc::new(EventHub::new(
+            config.event_buffer_size,
+            config.broadcast_capacity,
+        ))
+    };
+    NotesState {
+        pool,
+        events,
+        reconnect_tokens: Arc::new(ReconnectTokens::new(
+            config.reconnect_token_ttl,
+            config.reconnect_token_interval,
+        )),
+        allow_explain: config.allow_explain,
+        allow_purge: config.allow_purge,
+        significant_fields: config.significant_fields,
+        note_subscribers: Arc::default(),
+        ws_connections,
+        max_subscribers_per_note: config.max_subscribers_per_note,
+        max_list_rows: config.max_list_rows,
+        note_limits: NoteLimits {
+            title_chars: config.max_title_chars,
+            body_bytes: config.max_note_body_bytes,
+        },
+        bulk_event_threshold: config.bulk_event_threshold,
+        heartbeat_interval: config.heartbeat_interval,
+        heartbeat_timeout: config.heartbeat_timeout,
+        broadcast_degraded_percent: config.broadcast_degraded_percent,
     }
 }
 
@@ -0,0 +1,556 @@
+//! Protocol buffers request and response bodies for the apps' axum routers,
+//! with JSON as an alternative encoding of the same messages.
+
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{FromRequest, Request, State},
+    http::{
+        Extensions, HeaderMap, HeaderValue, StatusCode,
+        header::{
+            ACCEPT, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, ETAG, HeaderName, IF_NONE_MATCH,
+            VARY,
+        },
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use prost::Message as ProstMessage;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use thiserror::Error;
+use tracing::error;
+
+#[cfg(feature = "sqlx")]
+mod migrations;
+
+#[cfg(feature = "sqlx")]
+pub use migrations::migrations_applied;
+
+pub const PROTOBUF_CONTENT_TYPE: &str = "application/x-protobuf";
+const PROTOBUF_CONTENT_TYPE_HEADER: HeaderName = CONTENT_TYPE;
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// A protobuf message body. Requests sent as `application/json` and responses
+/// to clients that `Accept` it use the same message serialized as JSON instead.
+/// How other bodies are treated is up to the router's [`ProtobufState`].
+pub struct Protobuf<T>(pub T);
+
+/// Body of every error response, encoded like the `ApiError` of each app's
+/// schema, for errors answered before a request reaches an app.
+#[derive(Clone, PartialEq, Eq, ProstMessage, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ApiError {
+    /// Stable machine-readable identifier, e.g. `unauthenticated`.
+    #[prost(string, tag = "1")]
+    pub code: String,
+    #[prost(string, tag = "2")]
+    pub message: String,
+    #[prost(map = "string, string", tag = "3")]
+    pub details: HashMap<String, String>,
+}
+
+impl ApiError {
+    pub fn new(code: &str, message: impl Into<String>) -> Self {
+        Self {
+            code: code.to_owned(),
+            message: message.into(),
+            details: HashMap::new(),
+        }
+    }
+}
+
+/// Why a request body could not be decoded into a [`Protobuf`].
+#[derive(Debug, Error)]
+pub enum Rejection {
+    #[error("request body must be protocol buffers bytes")]
+    InvalidBody,
+    #[error("invalid protocol buffers payload: {0}")]
+    InvalidProtobuf(prost::DecodeError),
+    #[error("invalid JSON payload: {0}")]
+    InvalidJson(serde_json::Error),
+    #[error("request body exceeds {limit} bytes")]
+    BodyTooLarge { limit: usize },
+    #[error("request body must be application/x-protobuf or application/json")]
+    UnsupportedMediaType,
+}
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response {
+        let status = match self {
+            Self::InvalidBody | Self::InvalidProtobuf(_) | Self::InvalidJson(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            Self::BodyTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+/// Implemented by router states, so that [`Protobuf`] rejects undecodable
+/// bodies with the app's own error type and limits.
+pub trait ProtobufState {
+    type Rejection: From<Rejection> + IntoResponse;
+
+    /// Largest body buffered when the router sets no [`MaxBodyBytes`]; larger
+    /// ones are rejected with `413`. Without one, only axum's body limit applies.
+    const MAX_BODY_BYTES: Option<usize> = None;
+
+    /// Rejects bodies declaring a `Content-Type` other than JSON or protobuf
+    /// with `415`, instead of decoding them as protobuf.
+    const STRICT_CONTENT_TYPE: bool = false;
+
+    /// Observes every decoded request, e.g. to trace its payload.
+    fn decoded<T: Serialize>(_extensions: &Extensions, _message: &T, _body: &[u8]) {}
+}
+
+impl ProtobufState for () {
+    type Rejection = Rejection;
+}
+
+/// Caps the request bodies [`Protobuf`] buffers; larger ones are rejected with `413`.
+#[derive(Debug, Clone, Copy)]
+pub struct MaxBodyBytes(pub usize);
+
+/// Whether bodies of requests without `Content-Type`, and responses to requests
+/// without a JSON or protobuf `Accept`, are JSON rather than protobuf.
+#[derive(Debug, Clone, Copy)]
+pub struct JsonByDefault(pub bool);
+
+impl<S, T> FromRequest<S> for Protobuf<T>
+where
+    S: ProtobufState + Send + Sync,
+    T: ProstMessage + Default + Serialize + DeserializeOwned,
+{
+    type Rejection = S::Rejection;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let json = wants_json_body(req.headers(), req.extensions(), S::STRICT_CONTENT_TYPE)?;
+        let limit = req
+            .extensions()
+            .get::<MaxBodyBytes>()
+            .map(|max| max.0)
+            .or(S::MAX_BODY_BYTES);
+        let (extensions, body) = if let Some(limit) = limit {
+            let (parts, body) = req.into_parts();
+            let body = read_limited_body(&parts.headers, body, limit).await?;
+            (parts.extensions, body)
+        } else {
+            let extensions = req.extensions().clone();
+            let body = Bytes::from_request(req, state)
+                .await
+                .map_err(|_| Rejection::InvalidBody)?;
+            (extensions, body)
+        };
+        let message = if json {
+            serde_json::from_slice(&body).map_err(Rejection::InvalidJson)?
+        } else {
+            T::decode(body.clone()).map_err(Rejection::InvalidProtobuf)?
+        };
+        S::decoded(&extensions, &message, &body);
+        Ok(Self(message))
+    }
+}
+
+/// Whether the request body is JSON rather than protobuf, judging by its
+/// `Content-Type` or, without one, the router's [`JsonByDefault`].
+fn wants_json_body(
+    headers: &HeaderMap,
+    extensions: &Extensions,
+    strict: bool,
+) -> Result<bool, Rejection> {
+    match headers.get(CONTENT_TYPE) {
+        Some(content_type) => match content_type.to_str() {
+            Ok(media_type) if is_json(media_type) => Ok(true),
+            Ok(media_type) if has_essence(media_type, PROTOBUF_CONTENT_TYPE) => Ok(false),
+            _ if strict => Err(Rejection::UnsupportedMediaType),
+            _ => Ok(false),
+        },
+        None => Ok(extensions
+            .get::<JsonByDefault>()
+            .is_some_and(|json_by_default| json_by_default.0)),
+    }
+}
+
+async fn read_limited_body(
+    headers: &HeaderMap,
+    body: Body,
+    limit: usize,
+) -> Result<Bytes, Rejection> {
+    // Refuse declared oversized bodies before reading any of them.
+    let declared_length = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if declared_length.is_some_and(|length| length > limit as u64) {
+        return Err(Rejection::BodyTooLarge { limit });
+    }
+
+    // Chunked bodies declare no length, so the cap is enforced while reading too.
+    Ok(Limited::new(body, limit)
+        .collect()
+        .await
+        .map_err(|error| {
+            if error.is::<LengthLimitError>() {
+                Rejection::BodyTooLarge { limit }
+            } else {
+                Rejection::InvalidBody
+            }
+        })?
+        .to_bytes())
+}
+
+/// Whether a media type such as `application/json; charset=utf-8` is JSON.
+fn is_json(media_type: &str) -> bool {
+    has_essence(media_type, JSON_CONTENT_TYPE)
+}
+
+fn has_essence(media_type: &str, essence: &str) -> bool {
+    media_type
+        .split(';')
+        .next()
+        .is_some_and(|given| given.trim().eq_ignore_ascii_case(essence))
+}
+
+/// Renders the message of a protobuf response as JSON, in case the client asks for it.
+#[derive(Clone)]
+struct JsonBody(Arc<dyn Fn() -> serde_json::Result<Vec<u8>> + Send + Sync>);
+
+impl<T> IntoResponse for Protobuf<T>
+where
+    T: ProstMessage + Serialize + Send + Sync + 'static,
+{
+    fn into_response(self) -> Response {
+        let mut response = self.0.encode_to_vec().into_response();
+        response.headers_mut().insert(
+            PROTOBUF_CONTENT_TYPE_HEADER,
+            HeaderValue::from_static(PROTOBUF_CONTENT_TYPE),
+        );
+        let message = self.0;
+        response
+            .extensions_mut()
+            .insert(JsonBody(Arc::new(move || serde_json::to_vec(&message))));
+        response
+    }
+}
+
+/// Swaps protobuf response bodies for JSON when the request `Accept`s
+/// `application/json`, or names neither format and JSON is the default.
+///
+/// Negotiated responses `Vary` by `Accept`. Those given an entity tag with
+/// [`ProtobufResponse::with_etag`] get an `ETag` naming the chosen format too,
+/// and are answered `304 Not Modified` when `If-None-Match` names it.
+pub async fn negotiate_json(
+    State(json_by_default): State<JsonByDefault>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let wants_json = accepts_json(request.headers(), json_by_default);
+    request.extensions_mut().insert(json_by_default);
+    let if_none_match = request.headers().clone();
+    let mut response = next.run(request).await;
+    let Some(JsonBody(render)) = response.extensions_mut().remove::<JsonBody>() else {
+        return response;
+    };
+    response
+        .headers_mut()
+        .insert(VARY, HeaderValue::from_static("accept"));
+    if let Some(EntityTag(tag)) = response.extensions_mut().remove::<EntityTag>() {
+        let etag = format_etag(&tag, wants_json);
+        if if_none_match_names(&if_none_match, &etag) {
+            let (mut parts, _) = response.into_parts();
+            parts.status = StatusCode::NOT_MODIFIED;
+            parts.headers.remove(CONTENT_TYPE);
+            parts.headers.remove(CONTENT_LENGTH);
+            parts.headers.insert(ETAG, etag_header(&etag));
+            return Response::from_parts(parts, Body::empty());
+        }
+        response.headers_mut().insert(ETAG, etag_header(&etag));
+    }
+    if !wants_json {
+        return response;
+    }
+
+    match render() {
+        Ok(json) => {
+            let (mut parts, _) = response.into_parts();
+            parts
+                .headers
+                .insert(CONTENT_TYPE, HeaderValue::from_static(JSON_CONTENT_TYPE));
+            parts.headers.remove(CONTENT_LENGTH);
+            Response::from_parts(parts, Body::from(json))
+        }
+        Err(error) => {
+            error!(%error, "failed to serialize response as JSON");
+            response
+        }
+    }
+}
+
+/// Whether [`negotiate_json`] answers a request with these headers in JSON:
+/// the first of JSON and protobuf its `Accept` names, or the default.
+pub fn accepts_json(headers: &HeaderMap, json_by_default: JsonByDefault) -> bool {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|media_type| {
+            if is_json(media_type) {
+                Some(true)
+            } else if has_essence(media_type, PROTOBUF_CONTENT_TYPE) {
+                Some(false)
+            } else {
+                None
+            }
+        })
+        .unwrap_or(json_by_default.0)
+}
+
+impl<T> Protobuf<T> {
+    /// Attaches a strong `ETag`; see [`ProtobufResponse::with_etag`].
+    pub fn with_etag(self, tag: impl Into<String>) -> ProtobufResponse<T> {
+        ProtobufResponse::from(self).with_etag(tag)
+    }
+
+    pub fn with_cache_control(self, value: &'static str) -> ProtobufResponse<T> {
+        ProtobufResponse::from(self).with_cache_control(value)
+    }
+}
+
+/// A protobuf response carrying extra caching headers.
+#[must_use]
+pub struct ProtobufResponse<T> {
+    message: Protobuf<T>,
+    headers: HeaderMap,
+    etag: Option<String>,
+}
+
+/// The format-independent part of a response's `ETag`, qualified with the
+/// negotiated format by [`negotiate_json`].
+#[derive(Clone)]
+struct EntityTag(String);
+
+impl<T> From<Protobuf<T>> for ProtobufResponse<T> {
+    fn from(message: Protobuf<T>) -> Self {
+        Self {
+            message,
+            headers: HeaderMap::new(),
+            etag: None,
+        }
+    }
+}
+
+impl<T> ProtobufResponse<T> {
+    /// Attaches a strong `ETag` built from `tag`, which must change with every
+    /// write of the resource, e.g. `v3-1718000000000`. Only visible ASCII
+    /// other than `"` may be used. [`negotiate_json`] answers matching
+    /// conditional requests with `304 Not Modified`.
+    pub fn with_etag(mut self, tag: impl Into<String>) -> Self {
+        self.etag = Some(tag.into());
+        self
+    }
+
+    pub fn with_cache_control(mut self, value: &'static str) -> Self {
+        self.headers
+            .insert(CACHE_CONTROL, HeaderValue::from_static(value));
+        self
+    }
+}
+
+/// Whether `If-None-Match` lists `etag` or is `*`. The comparison is weak, as
+/// RFC 9110 requires for this header, so `W/"v3"` matches `"v3"`.
+fn if_none_match_names(request_headers: &HeaderMap, etag: &str) -> bool {
+    request_headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|candidate| {
+            candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+        })
+}
+
+impl<T> IntoResponse for ProtobufResponse<T>
+where
+    T: ProstMessage + Serialize + Send + Sync + 'static,
+{
+    fn into_response(self) -> Response {
+        let mut response = self.message.into_response();
+        response.headers_mut().extend(self.headers);
+        if let Some(tag) = self.etag {
+            // Routers without `negotiate_json` only ever answer protobuf.
+            response
+                .headers_mut()
+                .insert(ETAG, etag_header(&format_etag(&tag, false)));
+            response.extensions_mut().insert(EntityTag(tag));
+        }
+        response
+    }
+}
+
+/// JSON and protobuf renderings of one resource are different representations,
+/// so they get different tags, e.g. `"v3"` and `"v3-json"`.
+fn format_etag(tag: &str, json: bool) -> String {
+    if json {
+        format!("\"{tag}-json\"")
+    } else {
+        format!("\"{tag}\"")
+    }
+}
+
+fn etag_header(etag: &str) -> HeaderValue {
+    HeaderValue::try_from(etag).expect("entity tags are restricted to header-safe characters")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, PartialEq, ProstMessage, Serialize, Deserialize)]
+    struct Greeting {
+        #[prost(string, tag = "1")]
+        text: String,
+    }
+
+    /// A router state with the strict settings of the notes app.
+    struct Strict;
+
+    impl ProtobufState for Strict {
+        type Rejection = Rejection;
+        const MAX_BODY_BYTES: Option<usize> = Some(1024);
+        const STRICT_CONTENT_TYPE: bool = true;
+    }
+
+    async fn extract<S: ProtobufState<Rejection = Rejection> + Send + Sync>(
+        request: Request,
+        state: &S,
+    ) -> Result<Greeting, Rejection> {
+        <Protobuf<Greeting> as FromRequest<S>>::from_request(request, state)
+            .await
+            .map(|Protobuf(greeting)| greeting)
+    }
+
+    fn request(content_type: &str, body: impl Into<Body>) -> Request {
+        Request::post("/")
+            .header(CONTENT_TYPE, content_type)
+            .body(body.into())
+            .expect("request is valid")
+    }
+
+    #[tokio::test]
+    async fn chunked_bodies_are_capped_while_streaming() {
+        let chunks = || (0..4).map(|_| Ok::<_, std::io::Error>(vec![0_u8; 512]));
+        let streamed = || Body::from_stream(futures_util::stream::iter(chunks()));
+
+        let rejection = extract(Request::new(streamed()), &Strict)
+            .await
+            .expect_err("a 2 KiB body exceeds a 1 KiB cap");
+        assert_eq!(
+            rejection.into_response().status(),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+
+        let mut request = Request::new(streamed());
+        request.extensions_mut().insert(MaxBodyBytes(1024));
+        assert!(matches!(
+            extract(request, &()).await,
+            Err(Rejection::BodyTooLarge { limit: 1024 })
+        ));
+        // Without a cap of its own the body is read in full, and only fails to decode.
+        assert!(matches!(
+            extract(Request::new(streamed()), &()).await,
+            Err(Rejection::InvalidProtobuf(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn bodies_are_decoded_by_content_type() {
+        let greeting = Greeting {
+            text: "hello".to_owned(),
+        };
+
+        let protobuf = request(PROTOBUF_CONTENT_TYPE, greeting.encode_to_vec());
+        assert_eq!(
+            extract(protobuf, &Strict).await.ok(),
+            Some(greeting.clone())
+        );
+        let json = request(
+            "application/json; charset=utf-8",
+            serde_json::to_vec(&greeting).expect("greeting serializes"),
+        );
+        assert_eq!(extract(json, &Strict).await.ok(), Some(greeting.clone()));
+
+        let text = request("text/plain", b"hello".to_vec());
+        assert!(matches!(
+            extract(text, &Strict).await,
+            Err(Rejection::UnsupportedMediaType)
+        ));
+        let mislabeled = request("text/plain", greeting.encode_to_vec());
+        assert_eq!(extract(mislabeled, &()).await.ok(), Some(greeting));
+    }
+
+    #[tokio::test]
+    async fn etags_name_the_negotiated_format() {
+        use axum::{Router, middleware, routing::get};
+        use tower::ServiceExt;
+
+        let app = Router::new()
+            .route(
+                "/",
+                get(|| async {
+                    Protobuf(Greeting {
+                        text: "hello".to_owned(),
+                    })
+                    .with_etag("v3")
+                }),
+            )
+            .layer(middleware::from_fn_with_state(
+                JsonByDefault(false),
+                negotiate_json,
+            ));
+        let get = |accept: &'static str, if_none_match: &'static str| {
+            let request = Request::get("/")
+                .header(ACCEPT, accept)
+                .header(IF_NONE_MATCH, if_none_match)
+                .body(Body::empty())
+                .expect("request is valid");
+            app.clone().oneshot(request)
+        };
+
+        let protobuf = get(PROTOBUF_CONTENT_TYPE, "\"v2\"")
+            .await
+            .expect("infallible");
+        assert_eq!(protobuf.status(), StatusCode::OK);
+        assert_eq!(protobuf.headers()[ETAG], "\"v3\"");
+        assert_eq!(protobuf.headers()[VARY], "accept");
+
+        let json = get(JSON_CONTENT_TYPE, "\"v3\"").await.expect("infallible");
+        assert_eq!(json.status(), StatusCode::OK);
+        assert_eq!(json.headers()[ETAG], "\"v3-json\"");
+
+        let cached = get(JSON_CONTENT_TYPE, "\"v3-json\"")
+            .await
+            .expect("infallible");
+        assert_eq!(cached.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(cached.headers()[ETAG], "\"v3-json\"");
+        assert!(cached.headers().get(CONTENT_TYPE).is_none());
+    }
+
+    #[test]
+    fn if_none_match_compares_etags_weakly() {
+        let headers = |value: &'static str| {
+            HeaderMap::from_iter([(IF_NONE_MATCH, HeaderValue::from_static(value))])
+        };
+
+        assert!(if_none_match_names(&headers("\"v3\""), "\"v3\""));
+        assert!(if_none_match_names(&headers("W/\"v3\""), "\"v3\""));
+        assert!(if_none_match_names(&headers("\"v1\", \"v3\""), "\"v3\""));
+        assert!(if_none_match_names(&headers("*"), "\"v3\""));
+        assert!(!if_none_match_names(&headers("\"v2\""), "\"v3\""));
+        assert!(!if_none_match_names(&HeaderMap::new(), "\"v3\""));
+    }
+}
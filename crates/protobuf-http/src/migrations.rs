@@ -0,0 +1,14 @@
+use sqlx::{PgPool, migrate::Migrator};
+
+/// Whether every migration of `migrator` is recorded as successfully applied,
+/// for readiness probes.
+pub async fn migrations_applied(pool: &PgPool, migrator: &Migrator) -> Result<bool, sqlx::Error> {
+    let versions: Vec<i64> = migrator.iter().map(|migration| migration.version).collect();
+    let applied: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM _sqlx_migrations WHERE success AND version = ANY($1)",
+    )
+    .bind(&versions)
+    .fetch_one(pool)
+    .await?;
+    Ok(usize::try_from(applied).is_ok_and(|applied| applied == versions.len()))
+}
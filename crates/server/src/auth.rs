@@ -0,0 +1,323 @@
+use std::{
+    borrow::Cow,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{
+        HeaderValue, StatusCode,
+        header::{AUTHORIZATION, UPGRADE, WWW_AUTHENTICATE},
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use protobuf_http::{ApiError, Protobuf};
+use serde::Deserialize;
+use sha2::Sha256;
+use tracing::debug;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Routes open to unauthenticated probes, matched like any other route.
+const UNAUTHENTICATED_PATHS: &[&str] = &["/api/notes/health"];
+/// Query parameter carrying the token of websocket upgrades, whose headers
+/// browsers cannot set.
+const ACCESS_TOKEN_PARAM: &str = "access_token";
+
+/// Subject (`sub` claim) of the token a request was authenticated with,
+/// available to handlers as `Extension<AuthenticatedUser>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthenticatedUser {
+    pub subject: String,
+}
+
+/// Verifies HS256 JSON Web Tokens signed with a shared secret.
+#[derive(Clone)]
+pub(crate) struct JwtVerifier {
+    secret: Arc<[u8]>,
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+}
+
+#[derive(Deserialize)]
+struct JwtClaims {
+    sub: String,
+    exp: u64,
+    nbf: Option<u64>,
+}
+
+impl JwtVerifier {
+    pub(crate) fn new(secret: &[u8]) -> Self {
+        Self {
+            secret: secret.into(),
+        }
+    }
+
+    /// Returns the subject of `token` if its signature is valid and it is
+    /// current at `now`, in seconds since the Unix epoch, or why it is not.
+    fn verify(&self, token: &str, now: u64) -> Result<String, &'static str> {
+        let Some((signing_input, signature)) = token.rsplit_once('.') else {
+            return Err("token is not a JWT");
+        };
+        let Some((header, claims)) = signing_input
+            .split_once('.')
+            .filter(|(_, claims)| !claims.contains('.'))
+        else {
+            return Err("token is not a JWT");
+        };
+
+        let header: JwtHeader = decode_part(header)?;
+        // Anything else, `none` in particular, would let the token pick how it is checked.
+        if header.alg != "HS256" {
+            return Err("unsupported signing algorithm");
+        }
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| "signature is not base64url")?;
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts any key size");
+        mac.update(signing_input.as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| "signature mismatch")?;
+
+        let claims: JwtClaims = decode_part(claims)?;
+        if claims.exp <= now {
+            return Err("token expired");
+        }
+        if claims.nbf.is_some_and(|not_before| not_before > now) {
+            return Err("token not yet valid");
+        }
+        Ok(claims.sub)
+    }
+}
+
+fn decode_part<T: serde::de::DeserializeOwned>(part: &str) -> Result<T, &'static str> {
+    let bytes = URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|_| "segment is not base64url")?;
+    serde_json::from_slice(&bytes).map_err(|_| "segment is not the expected JSON")
+}
+
+/// Rejects requests without a valid `Authorization: Bearer <JWT>` with 401
+/// and passes the rest on with their [`AuthenticatedUser`], which also scopes
+/// notes to that user. Websocket upgrades may carry the token as
+/// `?access_token=` instead.
+pub(crate) async fn require_jwt(
+    State(verifier): State<JwtVerifier>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if is_unauthenticated(&request) {
+        return next.run(request).await;
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let outcome = request_token(&request)
+        .ok_or("missing bearer token")
+        .and_then(|token| verifier.verify(&token, now));
+
+    match outcome {
+        Ok(subject) => {
+            #[cfg(feature = "notes")]
+            request
+                .extensions_mut()
+                .insert(notes::NoteOwner(subject.clone()));
+            request
+                .extensions_mut()
+                .insert(AuthenticatedUser { subject });
+            next.run(request).await
+        }
+        Err(reason) => {
+            debug!(reason, "rejected unauthenticated request");
+            (
+                StatusCode::UNAUTHORIZED,
+                [(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"))],
+                Protobuf(ApiError::new("unauthenticated", reason)),
+            )
+                .into_response()
+        }
+    }
+}
+
+fn is_unauthenticated(request: &Request) -> bool {
+    request
+        .extensions()
+        .get::<MatchedPath>()
+        .is_some_and(|path| UNAUTHENTICATED_PATHS.contains(&path.as_str()))
+}
+
+fn request_token(request: &Request) -> Option<Cow<'_, str>> {
+    let headers = request.headers();
+    if let Some(token) = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    {
+        return Some(Cow::Borrowed(token.trim()));
+    }
+
+    let is_websocket_upgrade = headers
+        .get(UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"));
+    if !is_websocket_upgrade {
+        return None;
+    }
+    form_urlencoded::parse(request.uri().query()?.as_bytes())
+        .find(|(name, _)| name == ACCESS_TOKEN_PARAM)
+        .map(|(_, token)| token)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        Extension, Router,
+        body::{Body, Bytes, to_bytes},
+        middleware::from_fn_with_state,
+        routing::get,
+    };
+    use prost::Message;
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    const SECRET: &[u8] = b"test-secret";
+
+    fn sign(header: &serde_json::Value, claims: &serde_json::Value, secret: &[u8]) -> String {
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts any key size");
+        mac.update(signing_input.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        format!("{signing_input}.{signature}")
+    }
+
+    fn token(exp: u64) -> String {
+        sign(
+            &json!({ "alg": "HS256", "typ": "JWT" }),
+            &json!({ "sub": "user-1", "exp": exp }),
+            SECRET,
+        )
+    }
+
+    fn in_an_hour() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("clock is after the epoch")
+            .as_secs()
+            + 3600
+    }
+
+    /// Mirrors how the server nests the apps under `/api`.
+    async fn send(request: axum::http::request::Builder) -> (StatusCode, Bytes) {
+        let api = Router::new()
+            .route(
+                "/whoami",
+                get(|Extension(user): Extension<AuthenticatedUser>| async move { user.subject }),
+            )
+            .route("/notes/health", get(|| async { "healthy" }))
+            .layer(from_fn_with_state(JwtVerifier::new(SECRET), require_jwt));
+        let app = Router::new().nest("/api", api);
+        let response = app
+            .oneshot(request.body(Body::empty()).expect("request is valid"))
+            .await
+            .expect("router is infallible");
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("body is readable");
+        (status, body)
+    }
+
+    async fn request(authorization: Option<&str>) -> (StatusCode, String) {
+        let mut request = Request::get("/api/whoami");
+        if let Some(authorization) = authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        let (status, body) = send(request).await;
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    #[tokio::test]
+    async fn valid_tokens_expose_their_subject() {
+        let authorization = format!("Bearer {}", token(in_an_hour()));
+
+        let (status, body) = request(Some(&authorization)).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "user-1");
+    }
+
+    #[tokio::test]
+    async fn expired_tokens_are_rejected() {
+        let authorization = format!("Bearer {}", token(1));
+
+        let (status, body) =
+            send(Request::get("/api/whoami").header(AUTHORIZATION, authorization)).await;
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let error = ApiError::decode(body).expect("error body should be an ApiError");
+        assert_eq!(error, ApiError::new("unauthenticated", "token expired"));
+    }
+
+    #[tokio::test]
+    async fn websocket_upgrades_may_pass_the_token_as_a_query_parameter() {
+        let uri = format!("/api/whoami?access_token={}", token(in_an_hour()));
+
+        let (status, body) = send(Request::get(&uri).header(UPGRADE, "websocket")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "user-1");
+
+        let (status, _) = send(Request::get(&uri)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn health_checks_need_no_token() {
+        let (status, body) = send(Request::get("/api/notes/health")).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "healthy");
+    }
+
+    #[tokio::test]
+    async fn missing_and_malformed_tokens_are_rejected() {
+        let exp = in_an_hour();
+        let wrong_secret = sign(
+            &json!({ "alg": "HS256" }),
+            &json!({ "sub": "user-1", "exp": exp }),
+            b"other-secret",
+        );
+        let unsigned = sign(
+            &json!({ "alg": "none" }),
+            &json!({ "sub": "user-1", "exp": exp }),
+            SECRET,
+        );
+        let valid = token(exp);
+
+        for authorization in [
+            None,
+            Some("Basic dXNlcjpwYXNz".to_owned()),
+            Some("Bearer not-a-jwt".to_owned()),
+            Some("Bearer a.b.c".to_owned()),
+            Some(format!("Bearer {valid}.extra")),
+            Some(format!("Bearer {wrong_secret}")),
+            Some(format!("Bearer {unsigned}")),
+        ] {
+            let (status, _) = request(authorization.as_deref()).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{authorization:?}");
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use axum::http::{
+    HeaderName, HeaderValue, Method,
+    header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE, ETAG, IF_NONE_MATCH, RETRY_AFTER},
+};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::APP_VERSION_HEADER;
+
+/// Names who made a change; recorded by the notes app as `updated_by`.
+const ACTOR_HEADER: HeaderName = HeaderName::from_static("x-actor");
+/// Correlates a request with server logs; reported back by `GET /api/whoami`.
+const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Answers browser preflights for the protobuf API. `application/x-protobuf`
+/// is not a CORS-safelisted content type, so every write triggers one.
+/// `allowed_origins` of `None` allows any origin.
+pub(crate) fn cors_layer(allowed_origins: Option<Vec<HeaderValue>>) -> CorsLayer {
+    let allow_origin = match allowed_origins {
+        Some(origins) => AllowOrigin::list(origins),
+        None => AllowOrigin::any(),
+    };
+    CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
+        ])
+        .allow_headers([
+            ACCEPT,
+            AUTHORIZATION,
+            CONTENT_TYPE,
+            IF_NONE_MATCH,
+            ACTOR_HEADER,
+            REQUEST_ID_HEADER,
+        ])
+        .expose_headers([ETAG, RETRY_AFTER, APP_VERSION_HEADER])
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        Router,
+        body::Body,
+        http::{
+            HeaderName, HeaderValue, Request, StatusCode,
+            header::{
+                ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+                ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_REQUEST_HEADERS,
+                ACCESS_CONTROL_REQUEST_METHOD, ORIGIN,
+            },
+        },
+        routing::post,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn header<'a>(
+        response: &'a axum::response::Response,
+        name: &HeaderName,
+    ) -> Option<&'a HeaderValue> {
+        response.headers().get(name)
+    }
+
+    async fn preflight(
+        allowed_origins: Option<Vec<HeaderValue>>,
+        origin: &str,
+        request_headers: &str,
+    ) -> axum::response::Response {
+        let app = Router::new()
+            .route("/api/notes", post(|| async { StatusCode::OK }))
+            .layer(cors_layer(allowed_origins));
+
+        let request = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/api/notes")
+            .header(ORIGIN, origin)
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "PATCH")
+            .header(ACCESS_CONTROL_REQUEST_HEADERS, request_headers)
+            .body(Body::empty())
+            .expect("failed to build preflight request");
+        app.oneshot(request).await.expect("preflight failed")
+    }
+
+    #[tokio::test]
+    async fn preflight_allows_protobuf_writes() {
+        let response = preflight(None, "https://app.example.com", "content-type").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            header(&response, &ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some(&HeaderValue::from_static("*"))
+        );
+        let allowed_methods = header(&response, &ACCESS_CONTROL_ALLOW_METHODS)
+            .and_then(|value| value.to_str().ok())
+            .expect("missing allowed methods");
+        for method in ["GET", "POST", "PATCH", "DELETE"] {
+            assert!(allowed_methods.contains(method), "{method} not allowed");
+        }
+        let allowed_headers = header(&response, &ACCESS_CONTROL_ALLOW_HEADERS)
+            .and_then(|value| value.to_str().ok())
+            .expect("missing allowed headers");
+        assert!(allowed_headers.contains("content-type"));
+    }
+
+    #[tokio::test]
+    async fn preflight_only_admits_listed_origins() {
+        let allowed = || Some(vec![HeaderValue::from_static("https://app.example.com")]);
+
+        let response = preflight(allowed(), "https://app.example.com", "content-type").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            header(&response, &ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some(&HeaderValue::from_static("https://app.example.com"))
+        );
+
+        let response = preflight(allowed(), "https://evil.example.com", "content-type").await;
+        assert_eq!(header(&response, &ACCESS_CONTROL_ALLOW_ORIGIN), None);
+    }
+
+    #[tokio::test]
+    async fn preflight_allows_bearer_tokens_and_request_ids() {
+        let response = preflight(
+            None,
+            "https://app.example.com",
+            "authorization, x-request-id",
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let allowed_headers = header(&response, &ACCESS_CONTROL_ALLOW_HEADERS)
+            .and_then(|value| value.to_str().ok())
+            .expect("missing allowed headers");
+        assert!(allowed_headers.contains("authorization"));
+        assert!(allowed_headers.contains("x-request-id"));
+    }
+}
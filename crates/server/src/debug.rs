@@ -0,0 +1,74 @@
+use axum::{
+    Router,
+    extract::Path,
+    http::{StatusCode, header::CONTENT_TYPE},
+    response::{IntoResponse, Response},
+    routing::post,
+};
+use bytes::Bytes;
+
+/// Development-only tools for protobuf client authors; mounted only when
+/// `ALLOW_DEBUG_ECHO` is set.
+pub(crate) fn debug_router() -> Router {
+    Router::new().route("/echo/{message_type}", post(echo_message))
+}
+
+/// Decodes the body as the fully-qualified `message_type` and answers with
+/// our own encoding of it, so clients can diff the bytes against theirs.
+async fn echo_message(Path(message_type): Path<String>, body: Bytes) -> Response {
+    match re_encode(&message_type, &body) {
+        Some(Ok(bytes)) => ([(CONTENT_TYPE, "application/x-protobuf")], bytes).into_response(),
+        Some(Err(error)) => (
+            StatusCode::BAD_REQUEST,
+            format!("invalid protocol buffers payload: {error}"),
+        )
+            .into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            format!("unknown message type `{message_type}`"),
+        )
+            .into_response(),
+    }
+}
+
+#[cfg_attr(
+    not(any(feature = "notes", feature = "ai-chat")),
+    allow(unused_variables)
+)]
+fn re_encode(message_type: &str, body: &[u8]) -> Option<Result<Vec<u8>, prost::DecodeError>> {
+    match message_type {
+        #[cfg(feature = "notes")]
+        "notes.v1.Note" => Some(round_trip::<notes::pb::Note>(body)),
+        #[cfg(feature = "notes")]
+        "notes.v1.CreateNoteRequest" => Some(round_trip::<notes::pb::CreateNoteRequest>(body)),
+        #[cfg(feature = "notes")]
+        "notes.v1.UpdateNoteRequest" => Some(round_trip::<notes::pb::UpdateNoteRequest>(body)),
+        #[cfg(feature = "notes")]
+        "notes.v1.SyncPushRequest" => Some(round_trip::<notes::pb::SyncPushRequest>(body)),
+        #[cfg(feature = "notes")]
+        "notes.v1.NoteEvent" => Some(round_trip::<notes::pb::NoteEvent>(body)),
+        #[cfg(feature = "ai-chat")]
+        "ai_chat.v1.Chat" => Some(round_trip::<ai_chat::pb::Chat>(body)),
+        #[cfg(feature = "ai-chat")]
+        "ai_chat.v1.ChatMessage" => Some(round_trip::<ai_chat::pb::ChatMessage>(body)),
+        #[cfg(feature = "ai-chat")]
+        "ai_chat.v1.CreateChatRequest" => Some(round_trip::<ai_chat::pb::CreateChatRequest>(body)),
+        #[cfg(feature = "ai-chat")]
+        "ai_chat.v1.InteractChatRequest" => {
+            Some(round_trip::<ai_chat::pb::InteractChatRequest>(body))
+        }
+        #[cfg(feature = "ai-chat")]
+        "ai_chat.v1.InteractChatResponse" => {
+            Some(round_trip::<ai_chat::pb::InteractChatResponse>(body))
+        }
+        _ => None,
+    }
+}
+
+#[cfg_attr(not(any(feature = "notes", feature = "ai-chat")), allow(dead_code))]
+fn round_trip<T>(body: &[u8]) -> Result<Vec<u8>, prost::DecodeError>
+where
+    T: prost::Message + Default,
+{
+    Ok(T::decode(body)?.encode_to_vec())
+}
@@ -0,0 +1,87 @@
+use axum::{Extension, http::StatusCode};
+use sqlx::PgPool;
+
+/// Liveness: the process is up and serving, whatever the state of postgres.
+pub(crate) async fn livez() -> StatusCode {
+    StatusCode::OK
+}
+
+/// Readiness: postgres answers and every enabled app's migrations are applied.
+/// Also served as the older `/healthcheck`.
+pub(crate) async fn readyz(Extension(pool): Extension<PgPool>) -> StatusCode {
+    match ready(&pool).await {
+        Ok(true) => StatusCode::OK,
+        Ok(false) => {
+            tracing::warn!("readiness check found unapplied migrations");
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+        Err(error) => {
+            tracing::warn!(%error, "readiness check failed");
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+    }
+}
+
+async fn ready(pool: &PgPool) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!("SELECT 1").fetch_one(pool).await?;
+
+    #[cfg(feature = "notes")]
+    if !notes::migrations_applied(pool).await? {
+        return Ok(false);
+    }
+    #[cfg(feature = "ai-chat")]
+    if !ai_chat::migrations_applied(pool).await? {
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{Router, body::Body, http::Request, routing::get};
+    use sqlx::postgres::PgPoolOptions;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn closed_pool() -> PgPool {
+        let pool = PgPoolOptions::new()
+            .connect_lazy("postgres://postgres@127.0.0.1/unused")
+            .expect("url is valid");
+        pool.close().await;
+        pool
+    }
+
+    async fn status(pool: PgPool, uri: &str) -> StatusCode {
+        let app = Router::new()
+            .route("/livez", get(livez))
+            .route("/readyz", get(readyz))
+            .route("/healthcheck", get(readyz))
+            .layer(Extension(pool));
+        let request = Request::get(uri)
+            .body(Body::empty())
+            .expect("request is valid");
+        app.oneshot(request)
+            .await
+            .expect("router is infallible")
+            .status()
+    }
+
+    #[tokio::test]
+    async fn liveness_ignores_the_database() {
+        assert_eq!(status(closed_pool().await, "/livez").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn readiness_fails_once_the_pool_is_closed() {
+        assert_eq!(
+            status(closed_pool().await, "/readyz").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            status(closed_pool().await, "/healthcheck").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+}
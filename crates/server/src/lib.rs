@@ -1,23 +1,123 @@
+use std::str::FromStr;
+
 use anyhow::Context;
-use axum::{Extension, Router, http::StatusCode, routing::get};
-use sqlx::{PgPool, postgres::PgPoolOptions};
-use tower_http::trace::TraceLayer;
+use axum::{
+    Extension, Router,
+    http::{HeaderName, HeaderValue},
+    middleware::from_fn_with_state,
+    routing::get,
+};
+use log::LevelFilter;
+use protobuf_http::{JsonByDefault, negotiate_json};
+use sqlx::{
+    ConnectOptions, PgPool,
+    postgres::{PgConnectOptions, PgPoolOptions},
+};
+use tower_http::{set_header::SetResponseHeaderLayer, trace::TraceLayer};
 use tracing_subscriber::EnvFilter;
 
-pub async fn build_app(database_url: &str) -> anyhow::Result<Router> {
+#[cfg(feature = "auth")]
+mod auth;
+mod config;
+mod cors;
+mod debug;
+mod health;
+mod maintenance;
+mod rate_limit;
+mod shutdown;
+mod timeout;
+mod whoami;
+
+#[cfg(feature = "auth")]
+pub use auth::AuthenticatedUser;
+pub use config::database_url;
+use config::{DevFlags, PoolConfig};
+use cors::cors_layer;
+use debug::debug_router;
+use health::{livez, readyz};
+use maintenance::{Maintenance, admin_router, reject_writes};
+use rate_limit::{ClientRateLimits, limit_clients};
+pub use shutdown::{Shutdown, serve};
+use timeout::{RequestTimeout, time_out_requests};
+use whoami::whoami;
+
+/// Crate version and git sha of this build, e.g. `0.1.0+1a2b3c4`.
+pub const APP_VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "+", env!("GIT_SHA"));
+
+const APP_VERSION_HEADER: HeaderName = HeaderName::from_static("x-app-version");
+
+/// Builds the whole application; triggering `shutdown` also closes its
+/// realtime connections so that [`serve`] can finish.
+pub async fn build_app(database_url: &str, shutdown: &Shutdown) -> anyhow::Result<Router> {
+    let pool_config = PoolConfig::from_env()?;
+    let dev_flags = DevFlags::from_env()?;
+    let mut connect_options =
+        PgConnectOptions::from_str(database_url).context("DATABASE_URL is not a valid url")?;
+    if let Some(threshold) = pool_config.slow_statement_threshold {
+        connect_options = connect_options
+            .log_statements(LevelFilter::Off)
+            .log_slow_statements(LevelFilter::Warn, threshold);
+    }
     let pool = PgPoolOptions::new()
-        .max_connections(10)
-        .connect(database_url)
+        .max_connections(pool_config.max_connections)
+        .min_connections(pool_config.min_connections)
+        .acquire_timeout(pool_config.acquire_timeout)
+        .idle_timeout(pool_config.idle_timeout)
+        .connect_with(connect_options)
         .await
         .context("failed to connect to postgres")?;
 
-    let api_router = api_router(pool.clone()).await?;
+    let maintenance = Maintenance::default();
+    let (api_router, apps_admin_router) = api_router(pool.clone(), &dev_flags, shutdown).await?;
+    let api_router = api_router.layer(from_fn_with_state(maintenance.clone(), reject_writes));
+    // Only `/api` is authenticated and rate limited: the health checks stay
+    // open to probes and `/admin` has its own token. Authentication runs
+    // first so that clients are limited by subject rather than address.
+    let api_router = match config::rate_limit()? {
+        Some(limit) => api_router.layer(from_fn_with_state(
+            ClientRateLimits::new(limit),
+            limit_clients,
+        )),
+        None => api_router,
+    };
+    #[cfg(feature = "auth")]
+    let api_router = api_router.layer(from_fn_with_state(
+        auth::JwtVerifier::new(config::jwt_secret()?.as_bytes()),
+        auth::require_jwt,
+    ));
+    // Encodes the errors of the middleware above as the client asked; the
+    // apps negotiate their own responses.
+    let api_router = api_router.layer(from_fn_with_state(
+        JsonByDefault(config::json_by_default()?),
+        negotiate_json,
+    ));
 
     let app = Router::new()
-        .route("/healthcheck", get(healthcheck))
-        .nest("/api", api_router)
+        .route("/livez", get(livez))
+        .route("/readyz", get(readyz))
+        // Kept for probes configured before `/readyz` existed.
+        .route("/healthcheck", get(readyz))
+        .nest("/api", api_router);
+    // Operator endpoints exist only when a token to guard them is configured.
+    let app = match config::admin_token() {
+        Some(token) => app.nest(
+            "/admin",
+            admin_router(maintenance, &token, apps_admin_router),
+        ),
+        None => app,
+    };
+    let app = app
+        .layer(from_fn_with_state(
+            RequestTimeout::new(config::request_timeout()?),
+            time_out_requests,
+        ))
         .layer(TraceLayer::new_for_http())
-        .layer(Extension(pool));
+        .layer(Extension(pool))
+        .layer(cors_layer(config::allowed_origins()?))
+        .layer(SetResponseHeaderLayer::overriding(
+            APP_VERSION_HEADER,
+            HeaderValue::from_static(APP_VERSION),
+        ));
 
     Ok(app)
 }
@@ -32,26 +132,46 @@ pub fn init_tracing() {
         .try_init();
 }
 
-async fn healthcheck(Extension(pool): Extension<PgPool>) -> StatusCode {
-    match sqlx::query_scalar!("SELECT 1").fetch_one(&pool).await {
-        Ok(_) => StatusCode::OK,
-        Err(_) => StatusCode::SERVICE_UNAVAILABLE,
-    }
-}
-
+#[cfg_attr(not(feature = "notes"), allow(unused_variables))]
 #[cfg_attr(
     not(any(feature = "notes", feature = "ai-chat")),
-    allow(unused_variables)
+    allow(clippy::unused_async)
 )]
-async fn api_router(pool: PgPool) -> anyhow::Result<Router> {
-    let api_router = Router::new();
+/// Builds the `/api` routes, along with the apps' operator routes for `/admin`.
+async fn api_router(
+    pool: PgPool,
+    dev_flags: &DevFlags,
+    shutdown: &Shutdown,
+) -> anyhow::Result<(Router, Router)> {
+    let api_router = Router::new().route("/whoami", get(whoami));
+    let admin_router = Router::new();
 
     #[cfg(feature = "notes")]
-    let api_router = {
+    let (api_router, admin_router) = {
         notes::run_migrations(&pool)
             .await
             .context("failed to run notes migrations")?;
-        api_router.nest("/notes", notes::create_handlers(pool.clone()))
+        let config = notes_config(dev_flags)?;
+        let ws_connections = notes::WsConnections::default();
+        tokio::spawn({
+            let shutdown = shutdown.clone();
+            let ws_connections = ws_connections.clone();
+            async move {
+                shutdown.triggered().await;
+                ws_connections.close_all();
+            }
+        });
+        (
+            api_router.nest(
+                "/notes",
+                notes::create_handlers_with_connections(
+                    pool.clone(),
+                    config,
+                    ws_connections.clone(),
+                ),
+            ),
+            admin_router.merge(notes::create_admin_handlers(ws_connections)),
+        )
     };
 
     #[cfg(feature = "ai-chat")]
@@ -59,8 +179,85 @@ async fn api_router(pool: PgPool) -> anyhow::Result<Router> {
         ai_chat::run_migrations(&pool)
             .await
             .context("failed to run ai-chat migrations")?;
-        api_router.nest("/ai-chat", ai_chat::create_handlers(pool.clone()))
+        let config = ai_chat_config(dev_flags)?;
+        api_router.nest(
+            "/ai-chat",
+            ai_chat::create_handlers_with_config(pool.clone(), config),
+        )
+    };
+
+    let api_router = if dev_flags.allow_debug_echo {
+        api_router.nest("/debug", debug_router())
+    } else {
+        api_router
     };
 
-    Ok(api_router)
+    Ok((api_router, admin_router))
+}
+
+/// The notes app's settings, from the `NOTES_*` and shared variables.
+#[cfg(feature = "notes")]
+fn notes_config(dev_flags: &DevFlags) -> anyhow::Result<notes::NotesConfig> {
+    let defaults = notes::NotesConfig::default();
+    let (heartbeat_interval, heartbeat_timeout) =
+        config::heartbeat((defaults.heartbeat_interval, defaults.heartbeat_timeout))?;
+    Ok(notes::NotesConfig {
+        allow_explain: dev_flags.allow_explain,
+        event_buffer_size: config::env_or("NOTES_EVENT_BUFFER_SIZE", defaults.event_buffer_size)?,
+        allow_purge: config::env_or("NOTES_ALLOW_PURGE", defaults.allow_purge)?,
+        significant_fields: config::significant_fields()?,
+        max_subscribers_per_note: config::env_or(
+            "NOTES_MAX_SUBSCRIBERS_PER_NOTE",
+            defaults.max_subscribers_per_note,
+        )?,
+        max_list_rows: config::env_or("NOTES_MAX_LIST_ROWS", defaults.max_list_rows)?,
+        bulk_event_threshold: config::env_or(
+            "NOTES_BULK_EVENT_THRESHOLD",
+            defaults.bulk_event_threshold,
+        )?,
+        heartbeat_interval,
+        heartbeat_timeout,
+        json_by_default: config::json_by_default()?,
+        max_body_bytes: config::env_or("NOTES_MAX_BODY_BYTES", defaults.max_body_bytes)?,
+        max_batch_body_bytes: config::env_or(
+            "NOTES_MAX_BATCH_BODY_BYTES",
+            defaults.max_batch_body_bytes,
+        )?,
+        max_title_chars: config::env_or("NOTES_MAX_TITLE_CHARS", defaults.max_title_chars)?,
+        max_note_body_bytes: config::env_or(
+            "NOTES_MAX_NOTE_BODY_BYTES",
+            defaults.max_note_body_bytes,
+        )?,
+        broadcast_capacity: config::broadcast_capacity(defaults.broadcast_capacity)?,
+        broadcast_degraded_percent: config::env_or(
+            "NOTES_BROADCAST_DEGRADED_PERCENT",
+            defaults.broadcast_degraded_percent,
+        )?,
+        relay_events: config::env_or("NOTES_RELAY_EVENTS", defaults.relay_events)?,
+        ..defaults
+    })
+}
+
+/// The ai-chat app's settings, from the `AI_CHAT_*` and shared variables.
+#[cfg(feature = "ai-chat")]
+fn ai_chat_config(dev_flags: &DevFlags) -> anyhow::Result<ai_chat::AiChatConfig> {
+    let defaults = ai_chat::AiChatConfig::default();
+    Ok(ai_chat::AiChatConfig {
+        allow_explain: dev_flags.allow_explain,
+        disabled_integrations: config::disabled_integrations()?,
+        // Raw prompts are only logged by debug builds unless configured otherwise.
+        redact_logged_content: config::env_or("AI_CHAT_REDACT_LOGS", !cfg!(debug_assertions))?,
+        max_list_rows: config::env_or("AI_CHAT_MAX_LIST_ROWS", defaults.max_list_rows)?,
+        busy_chat_timeout: config::env_opt("AI_CHAT_BUSY_TIMEOUT_MS")?
+            .map_or(defaults.busy_chat_timeout, std::time::Duration::from_millis),
+        requests_per_minute: config::integration_rate_limits()?,
+        json_by_default: config::json_by_default()?,
+        openai: config::openai()?,
+        max_retries: config::env_or("AI_CHAT_MAX_RETRIES", defaults.max_retries)?,
+        interaction_timeout: config::request_timeout()?,
+        fake_template: config::env_opt("AI_CHAT_FAKE_TEMPLATE")?,
+        history_turns: config::env_or("AI_CHAT_HISTORY_TURNS", defaults.history_turns)?,
+        history_max_bytes: config::env_or("AI_CHAT_HISTORY_MAX_BYTES", defaults.history_max_bytes)?,
+        ..defaults
+    })
 }
@@ -1,4 +1,3 @@
-use anyhow::Context;
 use tokio::net::TcpListener;
 use tracing::info;
 
@@ -6,15 +5,17 @@ use tracing::info;
 async fn main() -> anyhow::Result<()> {
     server::init_tracing();
 
-    let database_url =
-        std::env::var("DATABASE_URL").context("DATABASE_URL must be set for the server")?;
+    let database_url = server::database_url()?;
     let listen_addr = std::env::var("LISTEN_ADDR").unwrap_or_else(|_| "0.0.0.0:3000".to_owned());
 
-    let app = server::build_app(&database_url).await?;
+    let shutdown = server::Shutdown::default();
+    let app = server::build_app(&database_url, &shutdown).await?;
     let listener = TcpListener::bind(&listen_addr).await?;
 
+    shutdown.trigger_on_signals();
     info!("server listening on {listen_addr}");
-    axum::serve(listener, app).await?;
+    server::serve(listener, app, shutdown).await?;
+    info!("server stopped");
 
     Ok(())
 }
@@ -0,0 +1,237 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+use axum::{
+    Router,
+    extract::{MatchedPath, Request, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header::AUTHORIZATION, header::RETRY_AFTER},
+    middleware::{Next, from_fn_with_state},
+    response::{IntoResponse, Response},
+    routing::put,
+};
+use protobuf_http::{ApiError, Protobuf};
+use tracing::warn;
+
+/// Seconds clients are asked to wait before retrying a write during maintenance.
+const MAINTENANCE_RETRY_AFTER_SECS: HeaderValue = HeaderValue::from_static("30");
+/// Routes that only read despite their unsafe method, because their request
+/// is too large for a query string.
+const READ_ONLY_ROUTES: &[&str] = &["/api/notes/batch-get"];
+
+/// Runtime switch that pauses writes while keeping reads available.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Maintenance(Arc<AtomicBool>);
+
+impl Maintenance {
+    fn set(&self, enabled: bool) {
+        if self.0.swap(enabled, Ordering::SeqCst) != enabled {
+            if enabled {
+                warn!("entered maintenance mode; writes are rejected");
+            } else {
+                warn!("left maintenance mode; writes are accepted");
+            }
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Rejects requests with unsafe methods (`POST`, `PUT`, `PATCH`, `DELETE`)
+/// with `503` while maintenance mode is on, except for [`READ_ONLY_ROUTES`].
+pub(crate) async fn reject_writes(
+    State(maintenance): State<Maintenance>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if maintenance.is_enabled() && is_write(&request) {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(RETRY_AFTER, MAINTENANCE_RETRY_AFTER_SECS)],
+            Protobuf(ApiError::new(
+                "maintenance",
+                "server is in maintenance mode, retry later",
+            )),
+        )
+            .into_response();
+    }
+
+    next.run(request).await
+}
+
+fn is_write(request: &Request) -> bool {
+    !request.method().is_safe()
+        && !request
+            .extensions()
+            .get::<MatchedPath>()
+            .is_some_and(|path| READ_ONLY_ROUTES.contains(&path.as_str()))
+}
+
+/// Operator endpoints guarded by `Authorization: Bearer <ADMIN_TOKEN>`:
+/// `PUT /maintenance` enables maintenance mode and `DELETE` disables it.
+/// `apps` adds the apps' own operator endpoints, guarded the same way.
+pub(crate) fn admin_router(maintenance: Maintenance, token: &str, apps: Router) -> Router {
+    Router::new()
+        .route(
+            "/maintenance",
+            put(enable_maintenance)
+                .delete(disable_maintenance)
+                .get(maintenance_status),
+        )
+        .with_state(maintenance)
+        .merge(apps)
+        .layer(from_fn_with_state(Arc::<str>::from(token), require_token))
+}
+
+async fn require_token(State(token): State<Arc<str>>, request: Request, next: Next) -> Response {
+    if !is_authorized(request.headers(), &token) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    next.run(request).await
+}
+
+async fn enable_maintenance(State(maintenance): State<Maintenance>) -> StatusCode {
+    maintenance.set(true);
+    StatusCode::NO_CONTENT
+}
+
+async fn disable_maintenance(State(maintenance): State<Maintenance>) -> StatusCode {
+    maintenance.set(false);
+    StatusCode::NO_CONTENT
+}
+
+async fn maintenance_status(State(maintenance): State<Maintenance>) -> &'static str {
+    if maintenance.is_enabled() {
+        "enabled"
+    } else {
+        "disabled"
+    }
+}
+
+fn is_authorized(headers: &HeaderMap, token: &str) -> bool {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes()))
+}
+
+fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+    left.len() == right.len()
+        && left
+            .iter()
+            .zip(right)
+            .fold(0, |difference, (left, right)| difference | (left ^ right))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{Body, to_bytes},
+        http::{Method, Request},
+        routing::{get, post},
+    };
+    use prost::Message;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    const TOKEN: &str = "s3cret";
+
+    fn app(maintenance: &Maintenance) -> Router {
+        let api = Router::new()
+            .route(
+                "/notes",
+                get(|| async { "listed" }).post(|| async { "created" }),
+            )
+            .route("/notes/batch-get", post(|| async { "fetched" }))
+            .layer(from_fn_with_state(maintenance.clone(), reject_writes));
+        Router::new().nest("/api", api).nest(
+            "/admin",
+            admin_router(
+                maintenance.clone(),
+                TOKEN,
+                Router::new().route("/apps", get(|| async { "app internals" })),
+            ),
+        )
+    }
+
+    async fn send(app: Router, method: Method, uri: &str, token: Option<&str>) -> Response {
+        let mut request = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            request = request.header(AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let request = request
+            .body(Body::empty())
+            .expect("failed to build request");
+        app.oneshot(request).await.expect("request failed")
+    }
+
+    #[tokio::test]
+    async fn maintenance_rejects_writes_but_serves_reads() {
+        let maintenance = Maintenance::default();
+        let enabled = send(
+            app(&maintenance),
+            Method::PUT,
+            "/admin/maintenance",
+            Some(TOKEN),
+        )
+        .await;
+        assert_eq!(enabled.status(), StatusCode::NO_CONTENT);
+
+        let read = send(app(&maintenance), Method::GET, "/api/notes", None).await;
+        assert_eq!(read.status(), StatusCode::OK);
+
+        let batch_read = send(
+            app(&maintenance),
+            Method::POST,
+            "/api/notes/batch-get",
+            None,
+        )
+        .await;
+        assert_eq!(batch_read.status(), StatusCode::OK);
+
+        let write = send(app(&maintenance), Method::POST, "/api/notes", None).await;
+        assert_eq!(write.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            write.headers().get(RETRY_AFTER),
+            Some(&MAINTENANCE_RETRY_AFTER_SECS)
+        );
+        let body = to_bytes(write.into_body(), usize::MAX)
+            .await
+            .expect("body is readable");
+        let error = ApiError::decode(body).expect("error body should be an ApiError");
+        assert_eq!(error.code, "maintenance");
+
+        let disabled = send(
+            app(&maintenance),
+            Method::DELETE,
+            "/admin/maintenance",
+            Some(TOKEN),
+        )
+        .await;
+        assert_eq!(disabled.status(), StatusCode::NO_CONTENT);
+        let write = send(app(&maintenance), Method::POST, "/api/notes", None).await;
+        assert_eq!(write.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn toggling_requires_the_admin_token() {
+        let maintenance = Maintenance::default();
+        for token in [None, Some("wrong")] {
+            let response = send(app(&maintenance), Method::PUT, "/admin/maintenance", token).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+        assert!(!maintenance.is_enabled());
+
+        let response = send(app(&maintenance), Method::GET, "/admin/apps", None).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = send(app(&maintenance), Method::GET, "/admin/apps", Some(TOKEN)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,216 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex, PoisonError},
+    time::Instant,
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderValue, StatusCode, header::RETRY_AFTER},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use protobuf_http::{ApiError, Protobuf};
+
+/// Buckets beyond this many trigger dropping the ones that have refilled,
+/// so clients that went away do not accumulate.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Sustained rate and burst size allowed per client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RateLimit {
+    pub(crate) requests_per_sec: u32,
+    pub(crate) burst: u32,
+}
+
+/// One token bucket per client: the authenticated subject when there is one,
+/// otherwise the peer IP address.
+#[derive(Debug, Clone)]
+pub(crate) struct ClientRateLimits {
+    limit: RateLimit,
+    buckets: Arc<Mutex<HashMap<String, TokenBucket>>>,
+}
+
+impl ClientRateLimits {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: Arc::default(),
+        }
+    }
+
+    /// Takes one request from `client`'s bucket, or returns how many whole
+    /// seconds to wait until one is available.
+    fn try_acquire(&self, client: &str, now: Instant) -> Result<(), u64> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        if buckets.len() >= PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| !bucket.is_full(self.limit, now));
+        }
+        buckets
+            .entry(client.to_owned())
+            .or_insert_with(|| TokenBucket::new(self.limit, now))
+            .try_take(self.limit, now)
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(limit.burst),
+            refilled_at: now,
+        }
+    }
+
+    fn refill(&mut self, limit: RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.tokens = elapsed
+            .as_secs_f64()
+            .mul_add(f64::from(limit.requests_per_sec), self.tokens)
+            .min(f64::from(limit.burst));
+        self.refilled_at = now;
+    }
+
+    fn is_full(&mut self, limit: RateLimit, now: Instant) -> bool {
+        self.refill(limit, now);
+        self.tokens >= f64::from(limit.burst)
+    }
+
+    fn try_take(&mut self, limit: RateLimit, now: Instant) -> Result<(), u64> {
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+
+        let wait_secs = (1.0 - self.tokens) / f64::from(limit.requests_per_sec);
+        // Bounded by one token's refill time, which is at most a second.
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        Err(wait_secs.ceil().max(1.0) as u64)
+    }
+}
+
+/// Answers `429 Too Many Requests` with `Retry-After` once a client has used
+/// up its burst, until its bucket refills.
+pub(crate) async fn limit_clients(
+    State(limits): State<ClientRateLimits>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let client = client_key(&request);
+    if let Err(retry_after_secs) = limits.try_acquire(&client, Instant::now()) {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(RETRY_AFTER, HeaderValue::from(retry_after_secs))],
+            Protobuf(ApiError::new(
+                "rate_limited",
+                "too many requests, retry later",
+            )),
+        )
+            .into_response();
+    }
+
+    next.run(request).await
+}
+
+pub(crate) fn client_key(request: &Request) -> String {
+    #[cfg(feature = "auth")]
+    if let Some(user) = request.extensions().get::<crate::AuthenticatedUser>() {
+        return format!("sub:{}", user.subject);
+    }
+
+    // Absent only when the app is served without connect info, e.g. in tests;
+    // such requests share one bucket.
+    request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map_or_else(
+            || "ip:unknown".to_owned(),
+            |info| format!("ip:{}", info.0.ip()),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{
+        Router,
+        body::{Body, to_bytes},
+        middleware::from_fn_with_state,
+        routing::get,
+    };
+    use prost::Message;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    const LIMIT: RateLimit = RateLimit {
+        requests_per_sec: 1,
+        burst: 3,
+    };
+
+    async fn request_from(app: &Router, ip: [u8; 4]) -> Response {
+        let mut request = Request::get("/notes")
+            .body(Body::empty())
+            .expect("request is valid");
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from((ip, 4000))));
+        app.clone()
+            .oneshot(request)
+            .await
+            .expect("router is infallible")
+    }
+
+    #[tokio::test]
+    async fn bursting_past_the_limit_is_rejected_with_retry_after() {
+        let app = Router::new()
+            .route("/notes", get(|| async { "ok" }))
+            .layer(from_fn_with_state(
+                ClientRateLimits::new(LIMIT),
+                limit_clients,
+            ));
+
+        for _ in 0..LIMIT.burst {
+            assert_eq!(
+                request_from(&app, [10, 0, 0, 1]).await.status(),
+                StatusCode::OK
+            );
+        }
+        let rejected = request_from(&app, [10, 0, 0, 1]).await;
+        assert_eq!(rejected.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(rejected.headers()[RETRY_AFTER], "1");
+        let body = to_bytes(rejected.into_body(), usize::MAX)
+            .await
+            .expect("body is readable");
+        let error = ApiError::decode(body).expect("error body should be an ApiError");
+        assert_eq!(error.code, "rate_limited");
+
+        // Other clients have buckets of their own.
+        assert_eq!(
+            request_from(&app, [10, 0, 0, 2]).await.status(),
+            StatusCode::OK
+        );
+    }
+
+    #[test]
+    fn buckets_refill_at_the_sustained_rate() {
+        let limits = ClientRateLimits::new(LIMIT);
+        let start = Instant::now();
+        for _ in 0..LIMIT.burst {
+            assert_eq!(limits.try_acquire("ip:10.0.0.1", start), Ok(()));
+        }
+        assert_eq!(limits.try_acquire("ip:10.0.0.1", start), Err(1));
+
+        let later = start + Duration::from_secs(1);
+        assert_eq!(limits.try_acquire("ip:10.0.0.1", later), Ok(()));
+        assert_eq!(limits.try_acquire("ip:10.0.0.1", later), Err(1));
+    }
+}
@@ -0,0 +1,124 @@
+use std::net::SocketAddr;
+
+use axum::Router;
+use tokio::{net::TcpListener, sync::watch};
+use tracing::info;
+
+/// Stops a server started with [`serve`]: new connections are refused while
+/// in-flight requests finish and realtime connections are asked to close.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown(watch::Sender<bool>);
+
+impl Shutdown {
+    pub fn trigger(&self) {
+        self.0.send_replace(true);
+    }
+
+    /// Resolves once [`Shutdown::trigger`] was called, immediately if it already was.
+    pub async fn triggered(&self) {
+        let mut triggered = self.0.subscribe();
+        // `self` keeps the sender alive, so this only returns once triggered.
+        let _ignored = triggered.wait_for(|triggered| *triggered).await;
+    }
+
+    /// Triggers on ctrl-c, or on `SIGTERM` where there are Unix signals.
+    pub fn trigger_on_signals(&self) {
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            termination_signal().await;
+            info!("shutdown signal received, draining connections");
+            shutdown.trigger();
+        });
+    }
+}
+
+async fn termination_signal() {
+    let ctrl_c = async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            // Without a handler the process can only be killed outright.
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
+}
+
+/// Serves `app` until `shutdown` is triggered and every open connection is done.
+/// Requests carry the peer's `ConnectInfo<SocketAddr>`.
+pub async fn serve(listener: TcpListener, app: Router, shutdown: Shutdown) -> std::io::Result<()> {
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(async move { shutdown.triggered().await })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use axum::routing::get;
+    use tokio::{sync::Notify, time::timeout};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn in_flight_requests_finish_before_the_server_exits() {
+        let started = Arc::new(Notify::new());
+        let handler_started = Arc::clone(&started);
+        let app = Router::new().route(
+            "/slow",
+            get(move || async move {
+                handler_started.notify_one();
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                "done"
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind test listener");
+        let url = format!(
+            "http://{}/slow",
+            listener.local_addr().expect("listener has an address")
+        );
+        let shutdown = Shutdown::default();
+        let server = tokio::spawn(serve(listener, app, shutdown.clone()));
+
+        let request = tokio::spawn(async move {
+            let response = reqwest::get(&url).await.expect("request failed");
+            (
+                response.status(),
+                response.text().await.expect("body failed"),
+            )
+        });
+        started.notified().await;
+        shutdown.trigger();
+
+        let (status, body) = request.await.expect("request task panicked");
+        assert_eq!(status, reqwest::StatusCode::OK);
+        assert_eq!(body, "done");
+        timeout(Duration::from_secs(5), server)
+            .await
+            .expect("server did not exit after shutdown")
+            .expect("server task panicked")
+            .expect("server exited with an error");
+    }
+}
@@ -0,0 +1,134 @@
+use std::time::Duration;
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tower::{Layer, ServiceExt};
+use tower_http::timeout::TimeoutLayer;
+
+/// Bounds how long a request may take to produce its response, answering
+/// `408 Request Timeout` past that.
+#[derive(Debug, Clone)]
+pub(crate) struct RequestTimeout(TimeoutLayer);
+
+impl RequestTimeout {
+    pub(crate) fn new(timeout: Duration) -> Self {
+        Self(TimeoutLayer::with_status_code(
+            StatusCode::REQUEST_TIMEOUT,
+            timeout,
+        ))
+    }
+}
+
+/// The notes event websocket, open for as long as the client listens.
+const NOTE_EVENTS_PATH: &str = "/api/notes/events";
+/// Chat interactions, which with `transfer=chunked` stream for as long as
+/// the model keeps answering.
+const CHAT_INTERACT_PATH: &str = "/api/ai-chat/{chat_id}/interact";
+
+/// Applies the [`RequestTimeout`] to every request but long-lived ones,
+/// recognised by the route they matched rather than by anything the client
+/// can add to another request.
+pub(crate) async fn time_out_requests(
+    State(RequestTimeout(timeout)): State<RequestTimeout>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if is_long_lived(&request) {
+        return next.run(request).await;
+    }
+
+    match timeout.layer(next).oneshot(request).await {
+        Ok(response) => response.into_response(),
+        Err(infallible) => match infallible {},
+    }
+}
+
+fn is_long_lived(request: &Request) -> bool {
+    let Some(path) = request.extensions().get::<MatchedPath>() else {
+        return false;
+    };
+    match path.as_str() {
+        NOTE_EVENTS_PATH => true,
+        CHAT_INTERACT_PATH => request
+            .uri()
+            .query()
+            .is_some_and(|query| query.split('&').any(|pair| pair == "transfer=chunked")),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{Router, body::Body, middleware::from_fn_with_state, routing::get};
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    /// Mirrors how the server nests the apps under `/api`.
+    fn slow_app() -> Router {
+        let slow = get(|| async {
+            tokio::time::sleep(TIMEOUT * 4).await;
+            "done"
+        });
+        let apps = Router::new()
+            .route("/notes", slow.clone())
+            .route("/notes/events", slow.clone())
+            .route("/ai-chat/{chat_id}/interact", slow);
+        Router::new().nest("/api", apps).layer(from_fn_with_state(
+            RequestTimeout::new(TIMEOUT),
+            time_out_requests,
+        ))
+    }
+
+    async fn send(request: Request) -> StatusCode {
+        slow_app()
+            .oneshot(request)
+            .await
+            .expect("router is infallible")
+            .status()
+    }
+
+    fn get_request(uri: &str) -> Request {
+        Request::get(uri)
+            .body(Body::empty())
+            .expect("request is valid")
+    }
+
+    #[tokio::test]
+    async fn slow_requests_time_out() {
+        assert_eq!(
+            send(get_request("/api/notes")).await,
+            StatusCode::REQUEST_TIMEOUT
+        );
+        assert_eq!(
+            send(get_request("/api/ai-chat/1/interact")).await,
+            StatusCode::REQUEST_TIMEOUT
+        );
+    }
+
+    #[tokio::test]
+    async fn long_lived_routes_are_exempt() {
+        assert_eq!(send(get_request("/api/notes/events")).await, StatusCode::OK);
+        assert_eq!(
+            send(get_request("/api/ai-chat/1/interact?transfer=chunked")).await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn other_routes_cannot_opt_out() {
+        let chunked = get_request("/api/notes?transfer=chunked");
+        let upgrade = Request::get("/api/notes")
+            .header(axum::http::header::UPGRADE, "websocket")
+            .body(Body::empty())
+            .expect("request is valid");
+
+        assert_eq!(send(chunked).await, StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(send(upgrade).await, StatusCode::REQUEST_TIMEOUT);
+    }
+}
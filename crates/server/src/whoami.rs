@@ -0,0 +1,139 @@
+use axum::{
+    extract::Request,
+    http::{HeaderMap, HeaderName},
+};
+use protobuf_http::{
+    JSON_CONTENT_TYPE, JsonByDefault, PROTOBUF_CONTENT_TYPE, Protobuf, accepts_json,
+};
+use serde::Serialize;
+
+use crate::rate_limit::client_key;
+
+const ACTOR_HEADER: HeaderName = HeaderName::from_static("x-actor");
+const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+/// Longest `X-Actor` the apps accept.
+const MAX_ACTOR_LEN: usize = 256;
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// What the server extracted from a request, for debugging header propagation
+/// through proxies and the auth middleware. Values the server would not accept
+/// are left out rather than echoed.
+#[derive(Clone, PartialEq, prost::Message, Serialize)]
+pub(crate) struct WhoAmI {
+    /// Subject of the request's token, when it was authenticated.
+    #[prost(string, optional, tag = "1")]
+    subject: Option<String>,
+    /// Whom the request is rate limited as, e.g. `sub:alice` or `ip:10.0.0.1`.
+    #[prost(string, tag = "2")]
+    client: String,
+    #[prost(string, optional, tag = "3")]
+    actor: Option<String>,
+    #[prost(string, optional, tag = "4")]
+    request_id: Option<String>,
+    /// Media type the response body was negotiated to.
+    #[prost(string, tag = "5")]
+    response_content_type: String,
+}
+
+pub(crate) async fn whoami(request: Request) -> Protobuf<WhoAmI> {
+    let headers = request.headers();
+    let json_by_default = request
+        .extensions()
+        .get::<JsonByDefault>()
+        .copied()
+        .unwrap_or(JsonByDefault(false));
+    let response_content_type = if accepts_json(headers, json_by_default) {
+        JSON_CONTENT_TYPE
+    } else {
+        PROTOBUF_CONTENT_TYPE
+    };
+
+    #[cfg(feature = "auth")]
+    let subject = request
+        .extensions()
+        .get::<crate::AuthenticatedUser>()
+        .map(|user| user.subject.clone());
+    #[cfg(not(feature = "auth"))]
+    let subject = None;
+
+    Protobuf(WhoAmI {
+        subject,
+        client: client_key(&request),
+        actor: header(headers, &ACTOR_HEADER, MAX_ACTOR_LEN),
+        request_id: header(headers, &REQUEST_ID_HEADER, MAX_REQUEST_ID_LEN),
+        response_content_type: response_content_type.to_owned(),
+    })
+}
+
+/// The trimmed value of `name`, unless it is absent, empty, longer than
+/// `max_len` or not visible ASCII.
+fn header(headers: &HeaderMap, name: &HeaderName, max_len: usize) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty() && value.len() <= max_len)
+        .map(str::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        Router,
+        body::{Body, to_bytes},
+        http::{StatusCode, header::ACCEPT},
+        middleware::from_fn_with_state,
+        routing::get,
+    };
+    use prost::Message;
+    use protobuf_http::negotiate_json;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn app() -> Router {
+        Router::new()
+            .route("/whoami", get(whoami))
+            .layer(from_fn_with_state(JsonByDefault(false), negotiate_json))
+    }
+
+    #[tokio::test]
+    async fn reports_the_parsed_request_context() {
+        let request = Request::builder()
+            .uri("/whoami")
+            .header("x-actor", " alice ")
+            .header("x-request-id", "x".repeat(MAX_REQUEST_ID_LEN + 1))
+            .body(Body::empty())
+            .expect("failed to build request");
+        let response = app().oneshot(request).await.expect("whoami failed");
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("failed to read body");
+        let whoami = WhoAmI::decode(body).expect("body is not a WhoAmI");
+        assert_eq!(whoami.actor.as_deref(), Some("alice"));
+        assert_eq!(whoami.request_id, None);
+        assert_eq!(whoami.client, "ip:unknown");
+        assert_eq!(whoami.response_content_type, PROTOBUF_CONTENT_TYPE);
+    }
+
+    #[tokio::test]
+    async fn is_negotiated_like_other_endpoints() {
+        let request = Request::builder()
+            .uri("/whoami")
+            .header(ACCEPT, JSON_CONTENT_TYPE)
+            .header("x-request-id", "req-42")
+            .body(Body::empty())
+            .expect("failed to build request");
+        let response = app().oneshot(request).await.expect("whoami failed");
+
+        let body = to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("failed to read body");
+        let body: serde_json::Value = serde_json::from_slice(&body).expect("body is not JSON");
+        assert_eq!(body["request_id"], "req-42");
+        assert_eq!(body["response_content_type"], JSON_CONTENT_TYPE);
+        assert!(body["actor"].is_null());
+    }
+}
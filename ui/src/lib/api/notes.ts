@@ -1,5 +1,6 @@
 import {createMutation, createQuery, type QueryClient, useQueryClient} from '@tanstack/svelte-query';
 import {
+    ApiErrorSchema,
     CreateNoteRequestSchema,
     CreateNoteResponseSchema,
     DeleteNoteResponseSchema,
@@ -14,6 +15,9 @@ import {
 import {create, fromBinary, type MessageInitShape, toBinary} from '@bufbuild/protobuf';
 
 const PROTOBUF_CONTENT_TYPE = 'application/x-protobuf';
+const JSON_CONTENT_TYPE = 'application/json';
+// Largest page the backend returns; lists are fetched page by page up to the end.
+const LIST_PAGE_SIZE = 200;
 
 type WebSocketFactory = (url: string) => WebSocket;
 
@@ -62,18 +66,20 @@ function createNotesTransport(options: NotesApiOptions = {}) {
     }): Promise<TResponse> => {
         const response = await fetchImpl(`${baseUrl}${path}`, {
             method,
+            // Named explicitly, since deployments may default to JSON.
             headers:
                 body === undefined
-                    ? undefined
+                    ? {accept: PROTOBUF_CONTENT_TYPE}
                     : {
+                        accept: PROTOBUF_CONTENT_TYPE,
                         'content-type': PROTOBUF_CONTENT_TYPE
                     },
             body: body === undefined ? undefined : toRequestBody(body)
         });
 
         if (!response.ok) {
-            const maybeText = await response.text().catch(() => '');
-            const suffix = maybeText ? `: ${maybeText}` : '';
+            const reason = await describeError(response).catch(() => '');
+            const suffix = reason ? `: ${reason}` : '';
             throw new Error(`${method} ${path} failed with ${response.status}${suffix}`);
         }
 
@@ -82,12 +88,22 @@ function createNotesTransport(options: NotesApiOptions = {}) {
 
     return {
         async listNotes(): Promise<Note[]> {
-            const response = await request({
-                method: 'GET',
-                path: '/api/notes',
-                decode: (payload) => fromBinary(ListNotesResponseSchema, payload)
-            });
-            return sortNotes(response.notes);
+            const notes: Note[] = [];
+            let cursor = 0n;
+            do {
+                const query = new URLSearchParams({limit: String(LIST_PAGE_SIZE)});
+                if (cursor !== 0n) {
+                    query.set('after_id', cursor.toString());
+                }
+                const response = await request({
+                    method: 'GET',
+                    path: `/api/notes?${query}`,
+                    decode: (payload) => fromBinary(ListNotesResponseSchema, payload)
+                });
+                notes.push(...response.notes);
+                cursor = response.nextCursor;
+            } while (cursor !== 0n);
+            return sortNotes(notes);
         },
         async createNote(requestBody: CreateNoteInput): Promise<Note> {
             const response = await request({
@@ -301,6 +317,20 @@ function buildWebSocketUrl(path: string, baseUrl?: string): string {
     throw new Error('cannot build websocket URL without an absolute baseUrl outside the browser');
 }
 
+// Error responses carry an `ApiError`, as protobuf or, for clients asking for it, JSON.
+async function describeError(response: Response): Promise<string> {
+    const contentType = response.headers.get('content-type') ?? '';
+    if (contentType.startsWith(PROTOBUF_CONTENT_TYPE)) {
+        const error = fromBinary(ApiErrorSchema, new Uint8Array(await response.arrayBuffer()));
+        return `${error.code}: ${error.message}`;
+    }
+    if (contentType.startsWith(JSON_CONTENT_TYPE)) {
+        const error = (await response.json()) as { code?: string; message?: string };
+        return `${error.code}: ${error.message}`;
+    }
+    return response.text();
+}
+
 function toRequestBody(payload: Uint8Array): ArrayBuffer {
     const copy = new Uint8Array(payload.byteLength);
     copy.set(payload);
@@ -10,7 +10,7 @@ import type {Message} from "@bufbuild/protobuf";
  * Describes the file notes.proto.
  */
 export const file_notes: GenFile = /*@__PURE__*/
-    fileDesc("Cgtub3Rlcy5wcm90bxIIbm90ZXMudjEieAoETm90ZRIKCgJpZBgBIAEoAxINCgV0aXRsZRgCIAEoCRIMCgRib2R5GAMgASgJEhoKEmNyZWF0ZWRfYXRfdW5peF9tcxgEIAEoAxIaChJ1cGRhdGVkX2F0X3VuaXhfbXMYBSABKAMSDwoHdmVyc2lvbhgGIAEoAyIwChFDcmVhdGVOb3RlUmVxdWVzdBINCgV0aXRsZRgBIAEoCRIMCgRib2R5GAIgASgJIjIKEkNyZWF0ZU5vdGVSZXNwb25zZRIcCgRub3RlGAEgASgLMg4ubm90ZXMudjEuTm90ZSIvCg9HZXROb3RlUmVzcG9uc2USHAoEbm90ZRgBIAEoCzIOLm5vdGVzLnYxLk5vdGUiMgoRTGlzdE5vdGVzUmVzcG9uc2USHQoFbm90ZXMYASADKAsyDi5ub3Rlcy52MS5Ob3RlIk0KEVVwZGF0ZU5vdGVSZXF1ZXN0EhIKBXRpdGxlGAEgASgJSACIAQESEQoEYm9keRgCIAEoCUgBiAEBQggKBl90aXRsZUIHCgVfYm9keSIyChJVcGRhdGVOb3RlUmVzcG9uc2USHAoEbm90ZRgBIAEoCzIOLm5vdGVzLnYxLk5vdGUiIAoSRGVsZXRlTm90ZVJlc3BvbnNlEgoKAmlkGAEgASgDIn4KCU5vdGVEZWx0YRIKCgJpZBgBIAEoAxISCgV0aXRsZRgCIAEoCUgAiAEBEhEKBGJvZHkYAyABKAlIAYgBARIaChJ1cGRhdGVkX2F0X3VuaXhfbXMYBCABKAMSDwoHdmVyc2lvbhgFIAEoA0IICgZfdGl0bGVCBwoFX2JvZHkiGQoLTm90ZURlbGV0ZWQSCgoCaWQYASABKAMiiQEKCU5vdGVFdmVudBIhCgdjcmVhdGVkGAEgASgLMg4ubm90ZXMudjEuTm90ZUgAEiYKB3VwZGF0ZWQYAiABKAsyEy5ub3Rlcy52MS5Ob3RlRGVsdGFIABIoCgdkZWxldGVkGAMgASgLMhUubm90ZXMudjEuTm90ZURlbGV0ZWRIAEIHCgVldmVudGIGcHJvdG8z");
+    fileDesc("Cgtub3Rlcy5wcm90bxIIbm90ZXMudjEieAoETm90ZRIKCgJpZBgBIAEoAxINCgV0aXRsZRgCIAEoCRIMCgRib2R5GAMgASgJEhoKEmNyZWF0ZWRfYXRfdW5peF9tcxgEIAEoAxIaChJ1cGRhdGVkX2F0X3VuaXhfbXMYBSABKAMSDwoHdmVyc2lvbhgGIAEoAyIwChFDcmVhdGVOb3RlUmVxdWVzdBINCgV0aXRsZRgBIAEoCRIMCgRib2R5GAIgASgJIjIKEkNyZWF0ZU5vdGVSZXNwb25zZRIcCgRub3RlGAEgASgLMg4ubm90ZXMudjEuTm90ZSIvCg9HZXROb3RlUmVzcG9uc2USHAoEbm90ZRgBIAEoCzIOLm5vdGVzLnYxLk5vdGUiWgoRTGlzdE5vdGVzUmVzcG9uc2USHQoFbm90ZXMYASADKAsyDi5ub3Rlcy52MS5Ob3RlEhEKCXRydW5jYXRlZBgCIAEoCBITCgtuZXh0X2N1cnNvchgDIAEoAyJNChFVcGRhdGVOb3RlUmVxdWVzdBISCgV0aXRsZRgBIAEoCUgAiAEBEhEKBGJvZHkYAiABKAlIAYgBAUIICgZfdGl0bGVCBwoFX2JvZHkiMgoSVXBkYXRlTm90ZVJlc3BvbnNlEhwKBG5vdGUYASABKAsyDi5ub3Rlcy52MS5Ob3RlIiAKEkRlbGV0ZU5vdGVSZXNwb25zZRIKCgJpZBgBIAEoAyJ+CglOb3RlRGVsdGESCgoCaWQYASABKAMSEgoFdGl0bGUYAiABKAlIAIgBARIRCgRib2R5GAMgASgJSAGIAQESGgoSdXBkYXRlZF9hdF91bml4X21zGAQgASgDEg8KB3ZlcnNpb24YBSABKANCCAoGX3RpdGxlQgcKBV9ib2R5IhkKC05vdGVEZWxldGVkEgoKAmlkGAEgASgDIokBCglOb3RlRXZlbnQSIQoHY3JlYXRlZBgBIAEoCzIOLm5vdGVzLnYxLk5vdGVIABImCgd1cGRhdGVkGAIgASgLMhMubm90ZXMudjEuTm90ZURlbHRhSAASKAoHZGVsZXRlZBgDIAEoCzIVLm5vdGVzLnYxLk5vdGVEZWxldGVkSABCBwoFZXZlbnQiiwEKCEFwaUVycm9yEgwKBGNvZGUYASABKAkSDwoHbWVzc2FnZRgCIAEoCRIwCgdkZXRhaWxzGAMgAygLMh8ubm90ZXMudjEuQXBpRXJyb3IuRGV0YWlsc0VudHJ5Gi4KDERldGFpbHNFbnRyeRILCgNrZXkYASABKAkSDQoFdmFsdWUYAiABKAk6AjgBYgZwcm90bzM=");
 
 /**
  * @generated from message notes.v1.Note
@@ -118,6 +118,22 @@ export type ListNotesResponse = Message<"notes.v1.ListNotesResponse"> & {
      * @generated from field: repeated notes.v1.Note notes = 1;
      */
     notes: Note[];
+
+    /**
+     * Set when more notes remain after this page.
+     *
+     * @generated from field: bool truncated = 2;
+     */
+    truncated: boolean;
+
+    /**
+     * Id of the last returned note when more remain, otherwise 0. Pass it as
+     * `after_id`, with the same `sort`, to fetch the next page. Always 0 with
+     * `sort=updated_desc`, which cannot be paged.
+     *
+     * @generated from field: int64 next_cursor = 3;
+     */
+    nextCursor: bigint;
 };
 
 /**
@@ -272,3 +288,33 @@ export type NoteEvent = Message<"notes.v1.NoteEvent"> & {
 export const NoteEventSchema: GenMessage<NoteEvent> = /*@__PURE__*/
     messageDesc(file_notes, 10);
 
+/**
+ * Body of every error response; `code` is a stable machine-readable identifier.
+ *
+ * @generated from message notes.v1.ApiError
+ */
+export type ApiError = Message<"notes.v1.ApiError"> & {
+    /**
+     * @generated from field: string code = 1;
+     */
+    code: string;
+
+    /**
+     * @generated from field: string message = 2;
+     */
+    message: string;
+
+    /**
+     * Context such as the offending `field` of a `validation` error.
+     *
+     * @generated from field: map<string, string> details = 3;
+     */
+    details: { [key: string]: string };
+};
+
+/**
+ * Describes the message notes.v1.ApiError.
+ * Use `create(ApiErrorSchema)` to create a new message.
+ */
+export const ApiErrorSchema: GenMessage<ApiError> = /*@__PURE__*/
+    messageDesc(file_notes, 11);
@@ -138,7 +138,10 @@ async function requestProtobuf<TResponse>(
 ): Promise<TResponse> {
     const response = await fetch(`${baseUrl}${path}`, {
         method,
-        headers: body === undefined ? undefined : {'content-type': PROTOBUF_CONTENT_TYPE},
+        headers:
+            body === undefined
+                ? {accept: PROTOBUF_CONTENT_TYPE}
+                : {accept: PROTOBUF_CONTENT_TYPE, 'content-type': PROTOBUF_CONTENT_TYPE},
         body: body === undefined ? undefined : toRequestBody(body)
     });
 